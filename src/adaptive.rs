@@ -0,0 +1,166 @@
+use nalgebra::Vector3;
+
+// Luminance below which the error is measured against this floor rather than the pixel itself,
+// otherwise nearly black pixels would never be considered converged
+const LUMINANCE_FLOOR: f64 = 0.01;
+
+// ADAPTIVE SAMPLING -----------------------------------------------------------------
+/// Stops sampling pixels once their mean is known well enough
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AdaptiveSampling {
+    pub enabled: bool,
+    /// Largest half width of the 95% confidence interval of a pixel, relative to its luminance
+    pub threshold: f32,
+    /// Samples a pixel takes before it may be considered converged
+    pub min_samples: u32,
+}
+
+impl AdaptiveSampling {
+    pub fn default() -> AdaptiveSampling {
+        AdaptiveSampling {
+            enabled: false,
+            threshold: 0.05,
+            min_samples: 8,
+        }
+    }
+
+    /// If a pixel has enough samples and a small enough error to stop sampling it
+    pub fn converged(&self, stats: &PixelStats) -> bool {
+        self.enabled
+            && stats.samples >= self.min_samples.max(2)
+            && stats.relative_error() <= self.threshold as f64
+    }
+}
+
+// PIXEL STATS -----------------------------------------------------------------
+/// Running sums over the luminance of every sample traced through a pixel
+#[derive(Clone, Copy, Default, Debug)]
+pub struct PixelStats {
+    pub samples: u32,
+    pub sum: f64,
+    pub sum_squares: f64,
+}
+
+impl PixelStats {
+    /// Add one sample
+    pub fn add(&mut self, colour: Vector3<f32>) {
+        let y = luminance(colour) as f64;
+        self.samples += 1;
+        self.sum += y;
+        self.sum_squares += y * y;
+    }
+
+    /// Add every sample of other
+    pub fn merge(&mut self, other: &PixelStats) {
+        self.samples += other.samples;
+        self.sum += other.sum;
+        self.sum_squares += other.sum_squares;
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self.samples {
+            0 => 0.0,
+            n => self.sum / n as f64,
+        }
+    }
+
+    /// Unbiased variance of the samples
+    pub fn variance(&self) -> f64 {
+        if self.samples < 2 {
+            return f64::INFINITY;
+        }
+        let n = self.samples as f64;
+        ((self.sum_squares - self.sum * self.sum / n) / (n - 1.0)).max(0.0)
+    }
+
+    /// Half width of the 95% confidence interval of the mean, relative to the mean
+    pub fn relative_error(&self) -> f64 {
+        let standard_error = (self.variance() / self.samples as f64).sqrt();
+        1.96 * standard_error / self.mean().max(LUMINANCE_FLOOR)
+    }
+}
+
+/// Rec. 709 luminance of a linear colour
+pub fn luminance(colour: Vector3<f32>) -> f32 {
+    0.2126 * colour.x + 0.7152 * colour.y + 0.0722 * colour.z
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stats of n samples alternating between two grey levels
+    fn alternating(n: u32, low: f32, high: f32) -> PixelStats {
+        let mut stats = PixelStats::default();
+        for i in 0..n {
+            let y = match i % 2 {
+                0 => low,
+                _ => high,
+            };
+            stats.add(Vector3::repeat(y));
+        }
+        stats
+    }
+
+    fn enabled() -> AdaptiveSampling {
+        AdaptiveSampling {
+            enabled: true,
+            ..AdaptiveSampling::default()
+        }
+    }
+
+    #[test]
+    fn error_is_the_confidence_interval_over_the_mean() {
+        for n in [10, 50, 100] {
+            let stats = alternating(n, 0.4, 0.6);
+            assert!((stats.mean() - 0.5).abs() < 1e-6);
+            let variance = 0.01 * n as f64 / (n - 1) as f64;
+            assert!((stats.variance() - variance).abs() < 1e-6);
+            let error = 1.96 * (variance / n as f64).sqrt() / 0.5;
+            assert!((stats.relative_error() - error).abs() < 1e-6);
+        }
+        // The interval narrows with the square root of the samples, crossing 5% between these
+        let adaptive = enabled();
+        assert!(!adaptive.converged(&alternating(50, 0.4, 0.6)));
+        assert!(adaptive.converged(&alternating(100, 0.4, 0.6)));
+    }
+
+    #[test]
+    fn dark_pixels_are_measured_against_the_floor() {
+        let stats = alternating(64, 0.0, 0.002);
+        let standard_error = (stats.variance() / 64.0).sqrt();
+        let error = 1.96 * standard_error / LUMINANCE_FLOOR;
+        assert!((stats.relative_error() - error).abs() < 1e-9);
+        // Against its own mean of 0.001 the same noise would be ten times the error
+        assert!(1.96 * standard_error / stats.mean() > 0.2);
+        assert!(enabled().converged(&stats));
+        // Black has no error at all
+        assert_eq!(alternating(8, 0.0, 0.0).relative_error(), 0.0);
+    }
+
+    #[test]
+    fn converges_only_after_min_samples() {
+        let adaptive = enabled();
+        assert!(!adaptive.converged(&alternating(7, 0.5, 0.5)));
+        assert!(adaptive.converged(&alternating(8, 0.5, 0.5)));
+        // A single sample has no variance to go on, whatever the minimum
+        let anything = AdaptiveSampling {
+            min_samples: 0,
+            ..adaptive
+        };
+        assert!(!anything.converged(&alternating(1, 0.5, 0.5)));
+        assert!(anything.converged(&alternating(2, 0.5, 0.5)));
+        let disabled = AdaptiveSampling::default();
+        assert!(!disabled.converged(&alternating(1000, 0.5, 0.5)));
+    }
+
+    #[test]
+    fn merge_adds_the_samples() {
+        let mut merged = alternating(6, 0.1, 0.3);
+        merged.merge(&alternating(10, 0.1, 0.3));
+        let all = alternating(16, 0.1, 0.3);
+        assert_eq!(merged.samples, 16);
+        assert!((merged.mean() - all.mean()).abs() < 1e-9);
+        assert!((merged.variance() - all.variance()).abs() < 1e-9);
+    }
+}
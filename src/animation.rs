@@ -0,0 +1,344 @@
+use nalgebra::Vector3;
+use std::ops::{Add, Mul, Sub};
+
+// INTERPOLATION -----------------------------------------------------------------
+/// How a curve moves from one keyframe to the next
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interpolation {
+    /// Hold the value of the keyframe until the next one
+    Step,
+    /// Straight line between the two keyframes
+    Linear,
+    /// Cubic Bezier with smooth tangents taken from the neighbouring keyframes,
+    /// the first and last keyframes ease in and out
+    Bezier,
+}
+
+impl Interpolation {
+    /// Parse the names used from Rhai, "step", "linear" or "bezier"
+    pub fn from_name(name: &str) -> Option<Interpolation> {
+        match name.to_lowercase().as_str() {
+            "step" | "constant" => Some(Interpolation::Step),
+            "linear" => Some(Interpolation::Linear),
+            "bezier" | "smooth" => Some(Interpolation::Bezier),
+            _ => None,
+        }
+    }
+}
+
+/// Values that can be interpolated along a curve
+pub trait Animatable:
+    Copy + Add<Output = Self> + Sub<Output = Self> + Mul<f64, Output = Self>
+{
+}
+impl<T> Animatable for T where
+    T: Copy + Add<Output = Self> + Sub<Output = Self> + Mul<f64, Output = Self>
+{
+}
+
+// CURVE -----------------------------------------------------------------
+#[derive(Clone, Debug)]
+pub struct Keyframe<T> {
+    pub time: f64,
+    pub value: T,
+    /// Interpolation used from this keyframe to the next
+    pub interpolation: Interpolation,
+}
+
+/// Keyframes sorted by time, sampled at any time in between
+#[derive(Clone, Debug)]
+pub struct Curve<T> {
+    keys: Vec<Keyframe<T>>,
+}
+
+impl<T: Animatable> Curve<T> {
+    pub fn new() -> Curve<T> {
+        Curve { keys: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn keys(&self) -> &[Keyframe<T>] {
+        &self.keys
+    }
+
+    /// Add a keyframe, replacing any keyframe already at that time
+    pub fn insert(&mut self, time: f64, value: T, interpolation: Interpolation) {
+        let key = Keyframe {
+            time,
+            value,
+            interpolation,
+        };
+        match self.keys.binary_search_by(|k| k.time.total_cmp(&time)) {
+            Ok(index) => self.keys[index] = key,
+            Err(index) => self.keys.insert(index, key),
+        }
+    }
+
+    /// Value of the curve at a time, None if it has no keyframes.
+    /// Before the first and after the last keyframe the curve is held constant.
+    pub fn sample(&self, time: f64) -> Option<T> {
+        let first = self.keys.first()?;
+        let last = self.keys.last()?;
+        if time <= first.time {
+            return Some(first.value);
+        }
+        if time >= last.time {
+            return Some(last.value);
+        }
+
+        // Index of the keyframe that starts the segment containing time
+        let i = self.keys.partition_point(|k| k.time <= time) - 1;
+        let (k1, k2) = (&self.keys[i], &self.keys[i + 1]);
+        let span = k2.time - k1.time;
+        let u = (time - k1.time) / span;
+
+        let value = match k1.interpolation {
+            Interpolation::Step => k1.value,
+            Interpolation::Linear => k1.value + (k2.value - k1.value) * u,
+            Interpolation::Bezier => {
+                // Catmull-Rom tangents scaled to the segment give the inner control points
+                let tangent = |k: usize| match (k.checked_sub(1), self.keys.get(k + 1)) {
+                    (Some(prev), Some(next)) => {
+                        let prev = &self.keys[prev];
+                        Some((next.value - prev.value) * (1.0 / (next.time - prev.time)))
+                    }
+                    _ => None,
+                };
+                let c1 = match tangent(i) {
+                    Some(m) => k1.value + m * (span / 3.0),
+                    None => k1.value,
+                };
+                let c2 = match tangent(i + 1) {
+                    Some(m) => k2.value - m * (span / 3.0),
+                    None => k2.value,
+                };
+                let v = 1.0 - u;
+                k1.value * (v * v * v)
+                    + c1 * (3.0 * v * v * u)
+                    + c2 * (3.0 * v * u * u)
+                    + k2.value * (u * u * u)
+            }
+        };
+        Some(value)
+    }
+}
+
+impl<T: Animatable + PartialEq> Curve<T> {
+    /// If the curve holds one value from start to end. Every keyframe shaping the
+    /// segments in between, tangent neighbours included, has to share it.
+    pub fn holds(&self, start: f64, end: f64) -> bool {
+        let len = self.keys.len();
+        if len == 0 {
+            return true;
+        }
+        // Segments holding start and end, held constant outside the keys
+        let first = self.keys.partition_point(|k| k.time <= start);
+        let last = self.keys.partition_point(|k| k.time < end);
+        let from = match first == len {
+            true => len - 1,
+            false => first.saturating_sub(2),
+        };
+        let to = match last {
+            0 => 1,
+            last => (last + 2).min(len),
+        };
+        let keys = &self.keys[from..to];
+        keys.iter().all(|k| k.value == keys[0].value)
+    }
+}
+
+impl<T: Animatable> Default for Curve<T> {
+    fn default() -> Self {
+        Curve::new()
+    }
+}
+
+// TRACKS -----------------------------------------------------------------
+/// Keyframed transformations of a node
+#[derive(Clone, Default)]
+pub struct NodeAnimation {
+    pub translation: Curve<Vector3<f64>>,
+    pub rotation: Curve<Vector3<f64>>,
+    pub scale: Curve<Vector3<f64>>,
+}
+
+impl NodeAnimation {
+    pub fn is_empty(&self) -> bool {
+        self.translation.is_empty() && self.rotation.is_empty() && self.scale.is_empty()
+    }
+
+    /// If no transformation changes from start to end
+    pub fn holds(&self, start: f64, end: f64) -> bool {
+        self.translation.holds(start, end)
+            && self.rotation.holds(start, end)
+            && self.scale.holds(start, end)
+    }
+}
+
+/// Keyframed coefficients of a material
+#[derive(Clone, Default)]
+pub struct MaterialAnimation {
+    pub kd: Curve<Vector3<f64>>,
+    pub ks: Curve<Vector3<f64>>,
+    pub kr: Curve<Vector3<f64>>,
+    pub kt: Curve<Vector3<f64>>,
+    pub shininess: Curve<f64>,
+    pub ior: Curve<f64>,
+    pub absorption: Curve<Vector3<f64>>,
+}
+
+/// Keyframed position, colour and falloff of a light
+#[derive(Clone, Default)]
+pub struct LightAnimation {
+    pub position: Curve<Vector3<f64>>,
+    pub colour: Curve<Vector3<f64>>,
+    pub falloff: Curve<Vector3<f64>>,
+}
+
+/// Keyframed eye, target and up vector of a camera
+#[derive(Clone, Default)]
+pub struct CameraAnimation {
+    pub eye: Curve<Vector3<f64>>,
+    pub target: Curve<Vector3<f64>>,
+    pub up: Curve<Vector3<f64>>,
+}
+
+impl CameraAnimation {
+    pub fn is_empty(&self) -> bool {
+        self.eye.is_empty() && self.target.is_empty() && self.up.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} is not {expected}"
+        );
+    }
+
+    // Keyframes at 1, 2 and 4 with values 10, 30 and 20, all interpolated the same way
+    fn curve(interpolation: Interpolation) -> Curve<f64> {
+        let mut curve = Curve::new();
+        curve.insert(4.0, 20.0, interpolation);
+        curve.insert(1.0, 10.0, interpolation);
+        curve.insert(2.0, 30.0, interpolation);
+        curve
+    }
+
+    #[test]
+    fn holds_only_where_no_key_changes_the_value() {
+        let mut curve = curve(Interpolation::Bezier);
+        curve.insert(6.0, 20.0, Interpolation::Bezier);
+        curve.insert(8.0, 20.0, Interpolation::Bezier);
+        assert!(Curve::<f64>::new().holds(0.0, 10.0));
+        assert!(curve.holds(-1.0, 0.5));
+        assert!(curve.holds(9.0, 12.0));
+        assert!(curve.holds(6.5, 9.0));
+        assert!(!curve.holds(0.5, 1.5));
+        assert!(!curve.holds(3.0, 5.0));
+        // The tangent at 4 bends the segment to 6 even though both keys are 20
+        assert!(!curve.holds(4.5, 5.5));
+        assert!((curve.sample(5.0).unwrap() - 20.0).abs() > 1e-3);
+    }
+
+    #[test]
+    fn empty_curve_has_no_value() {
+        assert_eq!(Curve::<f64>::new().sample(0.0), None);
+    }
+
+    #[test]
+    fn keys_are_sorted_and_replaced() {
+        let mut curve = curve(Interpolation::Linear);
+        curve.insert(2.0, 40.0, Interpolation::Step);
+        let times: Vec<f64> = curve.keys().iter().map(|k| k.time).collect();
+        assert_eq!(times, [1.0, 2.0, 4.0]);
+        assert_eq!(curve.keys()[1].value, 40.0);
+        assert_eq!(curve.keys()[1].interpolation, Interpolation::Step);
+    }
+
+    #[test]
+    fn clamps_outside_the_keys() {
+        for interpolation in [
+            Interpolation::Step,
+            Interpolation::Linear,
+            Interpolation::Bezier,
+        ] {
+            let curve = curve(interpolation);
+            assert_eq!(curve.sample(-5.0), Some(10.0));
+            assert_eq!(curve.sample(1.0), Some(10.0));
+            assert_eq!(curve.sample(4.0), Some(20.0));
+            assert_eq!(curve.sample(9.0), Some(20.0));
+        }
+    }
+
+    #[test]
+    fn passes_through_every_key() {
+        for interpolation in [
+            Interpolation::Step,
+            Interpolation::Linear,
+            Interpolation::Bezier,
+        ] {
+            let curve = curve(interpolation);
+            for key in curve.keys() {
+                assert_close(curve.sample(key.time).unwrap(), key.value);
+            }
+        }
+    }
+
+    #[test]
+    fn step_holds_until_the_next_key() {
+        let curve = curve(Interpolation::Step);
+        assert_eq!(curve.sample(1.5), Some(10.0));
+        assert_eq!(curve.sample(1.999), Some(10.0));
+        assert_eq!(curve.sample(3.9), Some(30.0));
+    }
+
+    #[test]
+    fn linear_is_a_straight_line_between_keys() {
+        let curve = curve(Interpolation::Linear);
+        assert_close(curve.sample(1.5).unwrap(), 20.0);
+        assert_close(curve.sample(1.25).unwrap(), 15.0);
+        assert_close(curve.sample(3.0).unwrap(), 25.0);
+    }
+
+    #[test]
+    fn each_key_picks_the_interpolation_of_its_segment() {
+        let mut curve = curve(Interpolation::Linear);
+        curve.insert(1.0, 10.0, Interpolation::Step);
+        assert_eq!(curve.sample(1.5), Some(10.0));
+        assert_close(curve.sample(3.0).unwrap(), 25.0);
+    }
+
+    #[test]
+    fn bezier_follows_catmull_rom_tangents() {
+        let curve = curve(Interpolation::Bezier);
+        // The end keys ease in and out, the middle key takes the slope from 10 to 20 over
+        // three time units, so the control points of the first segment are 10 and 30 - 10/9
+        let (c1, c2) = (10.0, 30.0 - 10.0 / 9.0);
+        let expected = (10.0 + 3.0 * c1 + 3.0 * c2 + 30.0) / 8.0;
+        assert_close(curve.sample(1.5).unwrap(), expected);
+        // Second segment, from 30 with a control point of 30 + 2/3 * 10/3 to 20 held flat
+        let (c1, c2) = (30.0 + 20.0 / 9.0, 20.0);
+        let expected = (30.0 + 3.0 * c1 + 3.0 * c2 + 20.0) / 8.0;
+        assert_close(curve.sample(3.0).unwrap(), expected);
+    }
+
+    #[test]
+    fn bezier_through_evenly_spaced_keys_on_a_line_stays_on_it() {
+        let mut curve = Curve::new();
+        for i in 0..4 {
+            curve.insert(i as f64, 2.0 * i as f64 + 1.0, Interpolation::Bezier);
+        }
+        // Only the end segments ease, the middle one keeps the slope of the line
+        for time in [1.25, 1.5, 1.75] {
+            assert_close(curve.sample(time).unwrap(), 2.0 * time + 1.0);
+        }
+    }
+}
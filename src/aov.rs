@@ -0,0 +1,227 @@
+use crate::{bvh::BVH, node::Node, ray::Intersection, ray::Ray, scene::Scene};
+use nalgebra::{Point3, Vector3};
+
+// AOV -----------------------------------------------------------------
+/// Arbitrary output variables, images of the first hit alongside the shaded colour
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Aov {
+    /// The shaded colour
+    Beauty,
+    /// World space normal
+    Normal,
+    /// Distance in front of the camera along its view direction
+    Depth,
+    /// World space position
+    Position,
+    /// Diffuse colour `kd` of the material
+    Albedo,
+    /// Number derived from the label of the node
+    ObjectId,
+    /// Fraction of the point lights that reach the surface
+    Shadow,
+    /// Samples traced through the pixel, shown as a heatmap
+    Samples,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [
+        Aov::Beauty,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::Albedo,
+        Aov::ObjectId,
+        Aov::Shadow,
+        Aov::Samples,
+    ];
+
+    /// Parse the names used on the command line, e.g. "normal" or "id"
+    pub fn from_name(name: &str) -> Option<Aov> {
+        match name.to_lowercase().as_str() {
+            "beauty" | "colour" | "color" => Some(Aov::Beauty),
+            "normal" => Some(Aov::Normal),
+            "depth" | "z" => Some(Aov::Depth),
+            "position" => Some(Aov::Position),
+            "albedo" => Some(Aov::Albedo),
+            "id" | "objectid" | "object_id" => Some(Aov::ObjectId),
+            "shadow" => Some(Aov::Shadow),
+            "samples" | "spp" => Some(Aov::Samples),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Beauty => "beauty",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "id",
+            Aov::Shadow => "shadow",
+            Aov::Samples => "samples",
+        }
+    }
+
+    /// Names of the channels of the AOV when written as an EXR layer
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Beauty | Aov::Albedo => &["R", "G", "B"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId => &["ID"],
+            Aov::Shadow | Aov::Samples => &["Y"],
+        }
+    }
+}
+
+/// Hash of a node label used as its object ID, never 0 which marks a miss.
+/// Kept below 2^24 so it survives being stored as an f32.
+pub fn object_id(label: &str) -> u32 {
+    // FNV-1a
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in label.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    (hash & 0x00ff_ffff).max(1)
+}
+
+// AOV SAMPLE -----------------------------------------------------------------
+/// Every AOV of one pixel, taken from its first sample
+#[derive(Clone, Copy, Debug)]
+pub struct AovSample {
+    pub normal: Vector3<f32>,
+    pub depth: f32,
+    pub position: Vector3<f32>,
+    pub albedo: Vector3<f32>,
+    pub object_id: u32,
+    pub shadow: f32,
+}
+
+impl AovSample {
+    /// Values of a ray that hits nothing
+    pub fn miss() -> AovSample {
+        AovSample {
+            normal: Vector3::zeros(),
+            depth: f32::INFINITY,
+            position: Vector3::zeros(),
+            albedo: Vector3::zeros(),
+            object_id: 0,
+            shadow: 0.0,
+        }
+    }
+
+    /// Follow a camera ray to its first hit, depth is measured from `eye` along the unit view
+    /// direction `forward` of the camera
+    pub fn trace(
+        ray: &Ray,
+        eye: &Point3<f64>,
+        forward: &Vector3<f64>,
+        scene: &Scene,
+        bvh: &Option<BVH>,
+    ) -> AovSample {
+        match ray.first_hit(scene, bvh) {
+            Some((node, intersect)) => {
+                AovSample::hit(ray, eye, forward, scene, bvh, node, &intersect)
+            }
+            None => AovSample::miss(),
+        }
+    }
+
+    fn hit(
+        ray: &Ray,
+        eye: &Point3<f64>,
+        forward: &Vector3<f64>,
+        scene: &Scene,
+        bvh: &Option<BVH>,
+        node: &Node,
+        intersect: &Intersection,
+    ) -> AovSample {
+        let point = intersect.point;
+
+        // Count the point lights with nothing in the way
+        let mut lights = 0;
+        let mut visible = 0;
+        for light in scene.lights.values() {
+            if !light.active || light.ambient {
+                continue;
+            }
+            lights += 1;
+            let to_light =
+                Ray::new(point, (light.position - point).normalize()).with_time(ray.time);
+            if !to_light.light_blocked(scene, light, bvh, None) {
+                visible += 1;
+            }
+        }
+        let shadow = match lights {
+            0 => 1.0,
+            lights => visible as f32 / lights as f32,
+        };
+
+        AovSample {
+            normal: intersect.normal.normalize().cast(),
+            depth: (point - eye).dot(forward) as f32,
+            position: point.coords.cast(),
+            albedo: node.material.kd,
+            object_id: object_id(&node.label),
+            shadow,
+        }
+    }
+
+    /// Raw value of an AOV, single channel AOVs are repeated across all three.
+    /// The shaded colour and sample counts are kept by the renderer, not here.
+    pub fn value(&self, aov: Aov) -> Vector3<f32> {
+        match aov {
+            Aov::Beauty | Aov::Samples => Vector3::zeros(),
+            Aov::Normal => self.normal,
+            Aov::Depth => Vector3::repeat(self.depth),
+            Aov::Position => self.position,
+            Aov::Albedo => self.albedo,
+            Aov::ObjectId => Vector3::repeat(self.object_id as f32),
+            Aov::Shadow => Vector3::repeat(self.shadow),
+        }
+    }
+
+    /// An AOV squeezed into 0 to 1 so it can be looked at
+    pub fn display(&self, aov: Aov) -> Vector3<f32> {
+        match aov {
+            Aov::Beauty | Aov::Samples => Vector3::zeros(),
+            Aov::Normal => self.normal * 0.5 + Vector3::repeat(0.5),
+            // Near is white, fading out with distance, misses are black
+            Aov::Depth => Vector3::repeat(1.0 / (1.0 + self.depth.max(0.0))),
+            Aov::Position => self.position.map(|c| c / (1.0 + c.abs()) * 0.5 + 0.5),
+            Aov::Albedo => self.albedo.map(|c| c.clamp(0.0, 1.0)),
+            Aov::ObjectId => id_colour(self.object_id),
+            Aov::Shadow => Vector3::repeat(self.shadow),
+        }
+    }
+}
+
+// A distinct colour for every object ID, black for misses
+fn id_colour(id: u32) -> Vector3<f32> {
+    if id == 0 {
+        return Vector3::zeros();
+    }
+    let channel = |shift: u32| {
+        let mixed = id.wrapping_mul(0x9e37_79b1).rotate_left(shift);
+        0.2 + 0.8 * (mixed & 0xff) as f32 / 255.0
+    };
+    Vector3::new(channel(8), channel(16), channel(24))
+}
+
+/// Colour ramp from dark blue through green and yellow to red for a fraction in 0 to 1
+pub fn heatmap(t: f32) -> Vector3<f32> {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 0.3],
+        [0.0, 0.5, 1.0],
+        [0.0, 0.9, 0.2],
+        [1.0, 0.9, 0.0],
+        [1.0, 0.0, 0.0],
+    ];
+    let t = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (t.floor() as usize).min(STOPS.len() - 2);
+    let f = t - i as f32;
+    let (a, b) = (Vector3::from(STOPS[i]), Vector3::from(STOPS[i + 1]));
+    a + (b - a) * f
+}
@@ -0,0 +1,1137 @@
+use crate::sampler::{cosine_hemisphere, tangents};
+use nalgebra::Vector3;
+use std::f64::consts::PI;
+
+// Microfacet alphas below this are treated as perfectly smooth
+const SMOOTH_ALPHA: f64 = 1e-3;
+
+// LOBE -----------------------------------------------------------------
+/// Which part of a BSDF a sample was drawn from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Lobe {
+    Diffuse,
+    /// A rough microfacet reflection or transmission
+    Glossy,
+    /// A perfectly smooth reflection or transmission, which only sampling can find
+    Specular,
+}
+
+/// A direction picked by a BSDF
+pub struct BsdfSample {
+    /// Direction the light arrives from, in the shading frame
+    pub incoming: Vector3<f64>,
+    /// BSDF times the cosine over the density, what the light arriving along incoming is scaled by
+    pub weight: Vector3<f32>,
+    /// Density over solid angle, 0 for specular samples
+    pub pdf: f64,
+    pub lobe: Lobe,
+}
+
+// BSDF -----------------------------------------------------------------
+/// How a surface scatters light. Directions are in the shading frame, with the normal along +z,
+/// and both point away from the surface. Surfaces that do not transmit are only asked about
+/// an outgoing direction above them.
+pub trait Bsdf {
+    /// Light scattered towards outgoing per unit of light arriving from incoming, leaving out
+    /// the specular lobes
+    fn eval(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> Vector3<f32>;
+
+    /// Pick an incoming direction for outgoing, with one number to choose a lobe and two for
+    /// the direction. None if the path ends here.
+    fn sample(
+        &self,
+        outgoing: &Vector3<f64>,
+        choice: f64,
+        sample: (f64, f64),
+    ) -> Option<BsdfSample>;
+
+    /// Density of `sample` picking incoming, leaving out the specular lobes
+    fn pdf(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> f64;
+
+    /// If light passes through the surface, so it matters which side outgoing is on
+    fn transmits(&self) -> bool {
+        false
+    }
+}
+
+// SHADING FRAME -----------------------------------------------------------------
+/// Tangents and normal of a surface point, turning directions to and from the frame of a BSDF
+pub struct ShadingFrame {
+    tangent: Vector3<f64>,
+    bitangent: Vector3<f64>,
+    normal: Vector3<f64>,
+}
+
+impl ShadingFrame {
+    /// Frame around a unit normal
+    pub fn new(normal: Vector3<f64>) -> ShadingFrame {
+        let (tangent, bitangent) = tangents(&normal);
+        ShadingFrame {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    pub fn to_local(&self, v: &Vector3<f64>) -> Vector3<f64> {
+        Vector3::new(
+            v.dot(&self.tangent),
+            v.dot(&self.bitangent),
+            v.dot(&self.normal),
+        )
+    }
+
+    pub fn to_world(&self, v: &Vector3<f64>) -> Vector3<f64> {
+        self.tangent * v.x + self.bitangent * v.y + self.normal * v.z
+    }
+}
+
+// PHONG -----------------------------------------------------------------
+/// What `Material(kd, ks, kr, shininess)` scatters with when path traced: a Lambertian `kd`
+/// lobe, a perfect mirror `kr` and a smooth boundary letting `kt` through, picked in
+/// proportion to their brightest channels. The Phong highlight only exists in the whitted
+/// integrator.
+pub struct Phong {
+    pub diffuse: Vector3<f32>,
+    pub mirror: Vector3<f32>,
+    /// Tint of the light the boundary reflects or refracts by the Fresnel equations
+    pub transmission: Vector3<f32>,
+    pub ior: f64,
+}
+
+impl Phong {
+    // Chances of sampling the diffuse lobe and the mirror, the boundary takes the rest.
+    // Light inside a transparent material never meets the diffuse lobe.
+    fn shares(&self, outgoing: &Vector3<f64>) -> (f64, f64) {
+        let diffuse = match outgoing.z > 0.0 {
+            true => self.diffuse.max(),
+            false => 0.0,
+        };
+        let mirror = self.mirror.max();
+        let total = diffuse + mirror + self.transmission.max();
+        match total > 0.0 {
+            true => ((diffuse / total) as f64, (mirror / total) as f64),
+            false => (0.0, 0.0),
+        }
+    }
+}
+
+impl Bsdf for Phong {
+    fn eval(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> Vector3<f32> {
+        match upper(outgoing, incoming) {
+            true => self.diffuse / PI as f32,
+            false => Vector3::zeros(),
+        }
+    }
+
+    fn sample(
+        &self,
+        outgoing: &Vector3<f64>,
+        choice: f64,
+        sample: (f64, f64),
+    ) -> Option<BsdfSample> {
+        let (diffuse, mirror_share) = self.shares(outgoing);
+        if choice >= diffuse + mirror_share {
+            let share = 1.0 - diffuse - mirror_share;
+            if self.transmission.max() <= 0.0 {
+                return None;
+            }
+            // What is left of choice picks reflection or refraction
+            let choice = (choice - diffuse - mirror_share) / share;
+            let (incoming, scale) = match choice < fresnel_dielectric(outgoing.z, self.ior) {
+                true => (mirror(outgoing), 1.0),
+                false => {
+                    let (incoming, eta) = refract(outgoing, &Vector3::z(), self.ior)?;
+                    (incoming, 1.0 / (eta * eta))
+                }
+            };
+            return Some(BsdfSample {
+                incoming,
+                weight: self.transmission * (scale / share) as f32,
+                pdf: 0.0,
+                lobe: Lobe::Specular,
+            });
+        }
+        if choice >= diffuse {
+            return Some(BsdfSample {
+                incoming: mirror(outgoing),
+                weight: self.mirror / mirror_share as f32,
+                pdf: 0.0,
+                lobe: Lobe::Specular,
+            });
+        }
+        let incoming = cosine_hemisphere(sample);
+        if !upper(outgoing, &incoming) {
+            return None;
+        }
+        // The cosine and 1 / pi of the Lambertian lobe cancel against the sample density
+        Some(BsdfSample {
+            incoming,
+            weight: self.diffuse / diffuse as f32,
+            pdf: diffuse * incoming.z / PI,
+            lobe: Lobe::Diffuse,
+        })
+    }
+
+    fn pdf(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> f64 {
+        match upper(outgoing, incoming) {
+            true => self.shares(outgoing).0 * incoming.z / PI,
+            false => 0.0,
+        }
+    }
+
+    fn transmits(&self) -> bool {
+        self.transmission != Vector3::zeros()
+    }
+}
+
+// LAMBERTIAN -----------------------------------------------------------------
+/// Matte surface scattering light evenly in every direction
+pub struct Lambertian {
+    pub albedo: Vector3<f32>,
+}
+
+impl Bsdf for Lambertian {
+    fn eval(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> Vector3<f32> {
+        match upper(outgoing, incoming) {
+            true => self.albedo / PI as f32,
+            false => Vector3::zeros(),
+        }
+    }
+
+    fn sample(
+        &self,
+        outgoing: &Vector3<f64>,
+        _choice: f64,
+        sample: (f64, f64),
+    ) -> Option<BsdfSample> {
+        let incoming = cosine_hemisphere(sample);
+        if !upper(outgoing, &incoming) {
+            return None;
+        }
+        Some(BsdfSample {
+            incoming,
+            weight: self.albedo,
+            pdf: incoming.z / PI,
+            lobe: Lobe::Diffuse,
+        })
+    }
+
+    fn pdf(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> f64 {
+        match upper(outgoing, incoming) {
+            true => incoming.z / PI,
+            false => 0.0,
+        }
+    }
+}
+
+// OREN-NAYAR -----------------------------------------------------------------
+/// Rough matte surface such as clay or plaster, which looks flatter than a Lambertian one and
+/// brightens towards the light. The qualitative model of Oren and Nayar, with the slope of its
+/// facets spread by `sigma`.
+pub struct OrenNayar {
+    albedo: Vector3<f32>,
+    a: f64,
+    b: f64,
+}
+
+impl OrenNayar {
+    /// Surface with a standard deviation of its facet angles of sigma degrees
+    pub fn new(albedo: Vector3<f32>, sigma: f32) -> OrenNayar {
+        let sigma2 = (sigma as f64).to_radians().powi(2);
+        OrenNayar {
+            albedo,
+            a: 1.0 - sigma2 / (2.0 * (sigma2 + 0.33)),
+            b: 0.45 * sigma2 / (sigma2 + 0.09),
+        }
+    }
+}
+
+impl Bsdf for OrenNayar {
+    fn eval(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> Vector3<f32> {
+        if !upper(outgoing, incoming) {
+            return Vector3::zeros();
+        }
+        let (sin_o, sin_i) = (sin_theta(outgoing), sin_theta(incoming));
+        // Cosine of the azimuth between the two directions
+        let cos_phi = match sin_o > 1e-4 && sin_i > 1e-4 {
+            true => {
+                ((incoming.x * outgoing.x + incoming.y * outgoing.y) / (sin_i * sin_o)).max(0.0)
+            }
+            false => 0.0,
+        };
+        let (sin_alpha, tan_beta) = match incoming.z > outgoing.z {
+            true => (sin_o, sin_i / incoming.z),
+            false => (sin_i, sin_o / outgoing.z),
+        };
+        self.albedo * ((self.a + self.b * cos_phi * sin_alpha * tan_beta) / PI) as f32
+    }
+
+    fn sample(
+        &self,
+        outgoing: &Vector3<f64>,
+        _choice: f64,
+        sample: (f64, f64),
+    ) -> Option<BsdfSample> {
+        let incoming = cosine_hemisphere(sample);
+        if !upper(outgoing, &incoming) {
+            return None;
+        }
+        Some(BsdfSample {
+            incoming,
+            weight: self.eval(outgoing, &incoming) * PI as f32,
+            pdf: incoming.z / PI,
+            lobe: Lobe::Diffuse,
+        })
+    }
+
+    fn pdf(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> f64 {
+        match upper(outgoing, incoming) {
+            true => incoming.z / PI,
+            false => 0.0,
+        }
+    }
+}
+
+// CONDUCTOR -----------------------------------------------------------------
+/// Metal with a complex index of refraction `eta + i k` per channel, smooth or rough with
+/// a GGX distribution of microfacets
+pub struct Conductor {
+    eta: Vector3<f64>,
+    k: Vector3<f64>,
+    // None for a perfect mirror
+    ggx: Option<Ggx>,
+}
+
+impl Conductor {
+    /// Metals `preset` knows, by name
+    pub const PRESETS: [&'static str; 3] = ["gold", "copper", "aluminium"];
+
+    pub fn new(eta: Vector3<f32>, k: Vector3<f32>, roughness: f32) -> Conductor {
+        Conductor {
+            eta: eta.cast(),
+            k: k.cast(),
+            ggx: Ggx::new(roughness),
+        }
+    }
+
+    /// Complex index of refraction of a metal at red, green and blue wavelengths
+    pub fn preset(name: &str) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        match name.to_lowercase().as_str() {
+            "gold" | "au" => Some((
+                Vector3::new(0.143, 0.374, 1.442),
+                Vector3::new(3.983, 2.385, 1.603),
+            )),
+            "copper" | "cu" => Some((
+                Vector3::new(0.200, 0.924, 1.102),
+                Vector3::new(3.912, 2.452, 2.142),
+            )),
+            "aluminium" | "aluminum" | "al" => Some((
+                Vector3::new(1.657, 0.880, 0.521),
+                Vector3::new(9.224, 6.270, 4.837),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Share of the light reflected at an angle with the cosine cos
+    pub fn fresnel(&self, cos: f64) -> Vector3<f32> {
+        Vector3::from_fn(|i, _| fresnel_conductor(cos, self.eta[i], self.k[i]) as f32)
+    }
+}
+
+impl Bsdf for Conductor {
+    fn eval(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> Vector3<f32> {
+        let Some(ggx) = &self.ggx else {
+            return Vector3::zeros();
+        };
+        if !upper(outgoing, incoming) {
+            return Vector3::zeros();
+        }
+        let half = (outgoing + incoming).normalize();
+        let specular = ggx.d(&half) * ggx.g(outgoing, incoming) / (4.0 * outgoing.z * incoming.z);
+        self.fresnel(outgoing.dot(&half)) * specular as f32
+    }
+
+    fn sample(
+        &self,
+        outgoing: &Vector3<f64>,
+        _choice: f64,
+        sample: (f64, f64),
+    ) -> Option<BsdfSample> {
+        let Some(ggx) = &self.ggx else {
+            return Some(BsdfSample {
+                incoming: mirror(outgoing),
+                weight: self.fresnel(outgoing.z),
+                pdf: 0.0,
+                lobe: Lobe::Specular,
+            });
+        };
+        let half = ggx.sample_visible(outgoing, sample);
+        let incoming = reflect(outgoing, &half);
+        if !upper(outgoing, &incoming) {
+            return None;
+        }
+        let pdf = self.pdf(outgoing, &incoming);
+        Some(BsdfSample {
+            incoming,
+            weight: self.eval(outgoing, &incoming) * (incoming.z / pdf) as f32,
+            pdf,
+            lobe: Lobe::Glossy,
+        })
+    }
+
+    fn pdf(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> f64 {
+        match &self.ggx {
+            Some(ggx) if upper(outgoing, incoming) => {
+                let half = (outgoing + incoming).normalize();
+                ggx.visible_pdf(outgoing, &half) / (4.0 * outgoing.dot(&half).abs())
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+// DIELECTRIC -----------------------------------------------------------------
+/// Glass-like boundary between the outside and a medium of index of refraction `ior`, which
+/// reflects or refracts by the Fresnel equations, tinting what it refracts. Rough boundaries scatter through GGX
+/// microfacets as in Walter et al., "Microfacet Models for Refraction through Rough Surfaces".
+pub struct Dielectric {
+    transmission: Vector3<f32>,
+    ior: f64,
+    // None for a perfectly smooth boundary
+    ggx: Option<Ggx>,
+}
+
+impl Dielectric {
+    pub fn new(transmission: Vector3<f32>, ior: f32, roughness: f32) -> Dielectric {
+        Dielectric {
+            transmission,
+            ior: ior as f64,
+            ggx: Ggx::new(roughness),
+        }
+    }
+
+    // Microfacet normal turning outgoing into incoming, facing +z, with the relative index of
+    // refraction across it, 1 for a reflection. None for configurations no facet produces.
+    fn half_vector(
+        &self,
+        outgoing: &Vector3<f64>,
+        incoming: &Vector3<f64>,
+    ) -> Option<(Vector3<f64>, f64)> {
+        let (cos_o, cos_i) = (outgoing.z, incoming.z);
+        if cos_o == 0.0 || cos_i == 0.0 {
+            return None;
+        }
+        let eta = match cos_o * cos_i > 0.0 {
+            true => 1.0,
+            false if cos_o > 0.0 => self.ior,
+            false => 1.0 / self.ior,
+        };
+        let half = incoming * eta + outgoing;
+        if half.norm_squared() == 0.0 {
+            return None;
+        }
+        let mut half = half.normalize();
+        if half.z < 0.0 {
+            half = -half;
+        }
+        // Facets seen from behind by either direction
+        if half.dot(incoming) * cos_i < 0.0 || half.dot(outgoing) * cos_o < 0.0 {
+            return None;
+        }
+        Some((half, eta))
+    }
+}
+
+impl Bsdf for Dielectric {
+    fn eval(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> Vector3<f32> {
+        let Some(ggx) = &self.ggx else {
+            return Vector3::zeros();
+        };
+        let Some((half, eta)) = self.half_vector(outgoing, incoming) else {
+            return Vector3::zeros();
+        };
+        let fresnel = fresnel_dielectric(outgoing.dot(&half), self.ior);
+        let (cos_o, cos_i) = (outgoing.z, incoming.z);
+        match eta == 1.0 {
+            true => {
+                let value = ggx.d(&half) * ggx.g(outgoing, incoming) * fresnel
+                    / (4.0 * cos_i * cos_o).abs();
+                Vector3::repeat(value as f32)
+            }
+            false => {
+                let denominator = (incoming.dot(&half) + outgoing.dot(&half) / eta).powi(2);
+                let value = ggx.d(&half)
+                    * ggx.g(outgoing, incoming)
+                    * (1.0 - fresnel)
+                    * (incoming.dot(&half) * outgoing.dot(&half) / (cos_i * cos_o * denominator))
+                        .abs();
+                // Radiance is compressed into the denser medium
+                self.transmission * (value / (eta * eta)) as f32
+            }
+        }
+    }
+
+    fn sample(
+        &self,
+        outgoing: &Vector3<f64>,
+        choice: f64,
+        sample: (f64, f64),
+    ) -> Option<BsdfSample> {
+        let Some(ggx) = &self.ggx else {
+            let fresnel = fresnel_dielectric(outgoing.z, self.ior);
+            if choice < fresnel {
+                return Some(BsdfSample {
+                    incoming: mirror(outgoing),
+                    weight: Vector3::repeat(1.0),
+                    pdf: 0.0,
+                    lobe: Lobe::Specular,
+                });
+            }
+            let (incoming, eta) = refract(outgoing, &Vector3::z(), self.ior)?;
+            return Some(BsdfSample {
+                incoming,
+                weight: self.transmission / (eta * eta) as f32,
+                pdf: 0.0,
+                lobe: Lobe::Specular,
+            });
+        };
+
+        let half = ggx.sample_visible(outgoing, sample);
+        let fresnel = fresnel_dielectric(outgoing.dot(&half), self.ior);
+        let incoming = match choice < fresnel {
+            true => {
+                let incoming = reflect(outgoing, &half);
+                if outgoing.z * incoming.z <= 0.0 {
+                    return None;
+                }
+                incoming
+            }
+            false => {
+                let (incoming, _) = refract(outgoing, &half, self.ior)?;
+                if outgoing.z * incoming.z >= 0.0 {
+                    return None;
+                }
+                incoming
+            }
+        };
+        let pdf = self.pdf(outgoing, &incoming);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            incoming,
+            weight: self.eval(outgoing, &incoming) * (incoming.z.abs() / pdf) as f32,
+            pdf,
+            lobe: Lobe::Glossy,
+        })
+    }
+
+    fn pdf(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> f64 {
+        let Some(ggx) = &self.ggx else {
+            return 0.0;
+        };
+        let Some((half, eta)) = self.half_vector(outgoing, incoming) else {
+            return 0.0;
+        };
+        // Reflections are sampled as often as the Fresnel equations reflect
+        let share = fresnel_dielectric(outgoing.dot(&half), self.ior);
+        match eta == 1.0 {
+            true => ggx.visible_pdf(outgoing, &half) / (4.0 * outgoing.dot(&half).abs()) * share,
+            false => {
+                let denominator = (incoming.dot(&half) + outgoing.dot(&half) / eta).powi(2);
+                let jacobian = incoming.dot(&half).abs() / denominator;
+                ggx.visible_pdf(outgoing, &half) * jacobian * (1.0 - share)
+            }
+        }
+    }
+
+    fn transmits(&self) -> bool {
+        true
+    }
+}
+
+// PLASTIC -----------------------------------------------------------------
+/// Diffuse base under a clear dielectric coat of index of refraction `ior`, such as plastic or
+/// varnished wood. The coat reflects by the Fresnel equations, smooth or through GGX
+/// microfacets, and the base is lit by what the coat lets through.
+pub struct Plastic {
+    diffuse: Vector3<f32>,
+    ior: f64,
+    // None for a perfectly smooth coat
+    ggx: Option<Ggx>,
+}
+
+impl Plastic {
+    pub fn new(diffuse: Vector3<f32>, ior: f32, roughness: f32) -> Plastic {
+        Plastic {
+            diffuse,
+            ior: ior as f64,
+            ggx: Ggx::new(roughness),
+        }
+    }
+
+    // Chance of sampling the coat rather than the base, from how much of each is seen
+    fn coat_share(&self, outgoing: &Vector3<f64>) -> f64 {
+        let coat = fresnel_dielectric(outgoing.z, self.ior);
+        let base = (1.0 - coat) * self.diffuse.max() as f64;
+        match coat + base > 0.0 {
+            true => coat / (coat + base),
+            false => 1.0,
+        }
+    }
+
+    fn base(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> Vector3<f32> {
+        let through = (1.0 - fresnel_dielectric(incoming.z, self.ior))
+            * (1.0 - fresnel_dielectric(outgoing.z, self.ior));
+        self.diffuse * (through / PI) as f32
+    }
+}
+
+impl Bsdf for Plastic {
+    fn eval(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> Vector3<f32> {
+        if !upper(outgoing, incoming) {
+            return Vector3::zeros();
+        }
+        let mut value = self.base(outgoing, incoming);
+        if let Some(ggx) = &self.ggx {
+            let half = (outgoing + incoming).normalize();
+            let fresnel = fresnel_dielectric(outgoing.dot(&half), self.ior);
+            let coat = ggx.d(&half) * ggx.g(outgoing, incoming) * fresnel
+                / (4.0 * outgoing.z * incoming.z);
+            value += Vector3::repeat(coat as f32);
+        }
+        value
+    }
+
+    fn sample(
+        &self,
+        outgoing: &Vector3<f64>,
+        choice: f64,
+        sample: (f64, f64),
+    ) -> Option<BsdfSample> {
+        let share = self.coat_share(outgoing);
+        let (incoming, lobe) = match (choice < share, &self.ggx) {
+            (true, None) => {
+                let fresnel = fresnel_dielectric(outgoing.z, self.ior);
+                return Some(BsdfSample {
+                    incoming: mirror(outgoing),
+                    weight: Vector3::repeat((fresnel / share) as f32),
+                    pdf: 0.0,
+                    lobe: Lobe::Specular,
+                });
+            }
+            (true, Some(ggx)) => (
+                reflect(outgoing, &ggx.sample_visible(outgoing, sample)),
+                Lobe::Glossy,
+            ),
+            (false, _) => (cosine_hemisphere(sample), Lobe::Diffuse),
+        };
+        if !upper(outgoing, &incoming) {
+            return None;
+        }
+        let pdf = self.pdf(outgoing, &incoming);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            incoming,
+            weight: self.eval(outgoing, &incoming) * (incoming.z / pdf) as f32,
+            pdf,
+            lobe,
+        })
+    }
+
+    fn pdf(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> f64 {
+        if !upper(outgoing, incoming) {
+            return 0.0;
+        }
+        let share = self.coat_share(outgoing);
+        let coat = match &self.ggx {
+            Some(ggx) => {
+                let half = (outgoing + incoming).normalize();
+                ggx.visible_pdf(outgoing, &half) / (4.0 * outgoing.dot(&half).abs())
+            }
+            None => 0.0,
+        };
+        share * coat + (1.0 - share) * incoming.z / PI
+    }
+}
+
+// GGX -----------------------------------------------------------------
+// Trowbridge-Reitz distribution of microfacet normals, sampled by the normals a direction
+// sees as in Heitz, "Sampling the GGX Distribution of Visible Normals"
+struct Ggx {
+    alpha: f64,
+}
+
+impl Ggx {
+    // Distribution for a roughness in [0, 1], the perceptual square root of alpha.
+    // None when it is smooth enough to be a perfect mirror.
+    fn new(roughness: f32) -> Option<Ggx> {
+        let alpha = (roughness as f64).powi(2);
+        (alpha >= SMOOTH_ALPHA).then_some(Ggx { alpha })
+    }
+
+    // Density of facets with the normal half
+    fn d(&self, half: &Vector3<f64>) -> f64 {
+        let cos2 = half.z * half.z;
+        if cos2 <= 0.0 {
+            return 0.0;
+        }
+        let alpha2 = self.alpha * self.alpha;
+        let tan2 = (1.0 - cos2) / cos2;
+        let e = 1.0 + tan2 / alpha2;
+        1.0 / (PI * alpha2 * cos2 * cos2 * e * e)
+    }
+
+    // Smith's auxiliary function, the facets hidden from a direction
+    fn lambda(&self, w: &Vector3<f64>) -> f64 {
+        let cos2 = w.z * w.z;
+        if cos2 <= 0.0 {
+            return f64::INFINITY;
+        }
+        let tan2 = (1.0 - cos2) / cos2;
+        ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0) / 2.0
+    }
+
+    // Share of the facets a direction sees
+    fn g1(&self, w: &Vector3<f64>) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Share of the facets both directions see, height correlated
+    fn g(&self, outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> f64 {
+        1.0 / (1.0 + self.lambda(outgoing) + self.lambda(incoming))
+    }
+
+    // Density of sample_visible picking half for w
+    fn visible_pdf(&self, w: &Vector3<f64>, half: &Vector3<f64>) -> f64 {
+        self.g1(w) / w.z.abs() * self.d(half) * w.dot(half).abs()
+    }
+
+    // Facet normal picked in proportion to how much of it w sees
+    fn sample_visible(&self, w: &Vector3<f64>, (u, v): (f64, f64)) -> Vector3<f64> {
+        // Stretch to the unit hemisphere, where the visible normals form a disk
+        let mut stretched = Vector3::new(self.alpha * w.x, self.alpha * w.y, w.z).normalize();
+        if stretched.z < 0.0 {
+            stretched = -stretched;
+        }
+        let t1 = match stretched.z < 0.99999 {
+            true => Vector3::z().cross(&stretched).normalize(),
+            false => Vector3::x(),
+        };
+        let t2 = stretched.cross(&t1);
+        let r = u.sqrt();
+        let phi = 2.0 * PI * v;
+        let p1 = r * phi.cos();
+        let s = (1.0 + stretched.z) / 2.0;
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let pz = (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        let normal = t1 * p1 + t2 * p2 + stretched * pz;
+        Vector3::new(
+            self.alpha * normal.x,
+            self.alpha * normal.y,
+            normal.z.max(1e-6),
+        )
+        .normalize()
+    }
+}
+
+// FRESNEL -----------------------------------------------------------------
+/// How the whitted integrator splits light between reflection and refraction
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FresnelKind {
+    /// The Fresnel equations for unpolarised light
+    Exact,
+    /// Schlick's approximation, cheaper and close for glass and water
+    Schlick,
+}
+
+impl FresnelKind {
+    pub const ALL: [FresnelKind; 2] = [FresnelKind::Exact, FresnelKind::Schlick];
+
+    /// Parse the names used from the command line
+    pub fn from_name(name: &str) -> Option<FresnelKind> {
+        match name.to_lowercase().as_str() {
+            "exact" => Some(FresnelKind::Exact),
+            "schlick" => Some(FresnelKind::Schlick),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FresnelKind::Exact => "exact",
+            FresnelKind::Schlick => "schlick",
+        }
+    }
+
+    /// Share of the light reflected, as `fresnel_dielectric` takes its arguments
+    pub fn reflectance(&self, cos: f64, eta: f64) -> f64 {
+        match self {
+            FresnelKind::Exact => fresnel_dielectric(cos, eta),
+            FresnelKind::Schlick => fresnel_schlick(cos, eta),
+        }
+    }
+}
+
+/// Share of unpolarised light a dielectric of relative index of refraction eta reflects, at
+/// an angle with the cosine cos. Negative cosines arrive from inside, 1 past the critical angle.
+pub fn fresnel_dielectric(cos: f64, eta: f64) -> f64 {
+    let (cos, eta) = match cos < 0.0 {
+        true => (-cos.max(-1.0), 1.0 / eta),
+        false => (cos.min(1.0), eta),
+    };
+    let sin2_t = (1.0 - cos * cos) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos - cos_t) / (eta * cos + cos_t);
+    let perpendicular = (cos - eta * cos_t) / (cos + eta * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+/// Schlick's approximation of `fresnel_dielectric`. Light leaving the denser medium uses the
+/// angle it refracts to, so total internal reflection still starts at the critical angle.
+pub fn fresnel_schlick(cos: f64, eta: f64) -> f64 {
+    let (mut cos, eta) = match cos < 0.0 {
+        true => (-cos.max(-1.0), 1.0 / eta),
+        false => (cos.min(1.0), eta),
+    };
+    if eta < 1.0 {
+        let sin2_t = (1.0 - cos * cos) / (eta * eta);
+        if sin2_t >= 1.0 {
+            return 1.0;
+        }
+        cos = (1.0 - sin2_t).sqrt();
+    }
+    let r0 = ((eta - 1.0) / (eta + 1.0)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos).powi(5)
+}
+
+/// Share of unpolarised light a conductor of index of refraction eta + i k reflects, at an
+/// angle with the cosine cos
+pub fn fresnel_conductor(cos: f64, eta: f64, k: f64) -> f64 {
+    let cos = cos.clamp(0.0, 1.0);
+    let cos2 = cos * cos;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let t1 = a2b2 + cos2;
+    let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos * a;
+    let perpendicular = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let parallel = perpendicular * (t3 - t4) / (t3 + t4);
+    (parallel + perpendicular) / 2.0
+}
+
+// DIRECTIONS -----------------------------------------------------------------
+// If both directions are above the surface
+fn upper(outgoing: &Vector3<f64>, incoming: &Vector3<f64>) -> bool {
+    outgoing.z > 0.0 && incoming.z > 0.0
+}
+
+fn sin_theta(w: &Vector3<f64>) -> f64 {
+    (1.0 - w.z * w.z).max(0.0).sqrt()
+}
+
+// Mirror image of w about the normal
+fn mirror(w: &Vector3<f64>) -> Vector3<f64> {
+    Vector3::new(-w.x, -w.y, w.z)
+}
+
+// Mirror image of w about a facet normal
+fn reflect(w: &Vector3<f64>, normal: &Vector3<f64>) -> Vector3<f64> {
+    -w + normal * (2.0 * w.dot(normal))
+}
+
+/// Direction w bends into across a boundary with the normal into a medium of index of
+/// refraction ior, with the relative index of refraction it crossed. w points away from the
+/// boundary and is outside when on the side the normal points to. None on total internal
+/// reflection.
+pub fn refract(w: &Vector3<f64>, normal: &Vector3<f64>, ior: f64) -> Option<(Vector3<f64>, f64)> {
+    let mut cos_i = normal.dot(w);
+    let (eta, normal) = match cos_i < 0.0 {
+        true => {
+            cos_i = -cos_i;
+            (1.0 / ior, -normal)
+        }
+        false => (ior, *normal),
+    };
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some((-w / eta + normal * (cos_i / eta - cos_t), eta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::uniform_sphere;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    const SAMPLES: usize = 40_000;
+    // Cells along each side of the grid the sphere is integrated over
+    const STRATA: usize = 200;
+    const IOR: f32 = 1.5;
+
+    fn phong(diffuse: f32, mirror: f32, transmission: f32) -> Phong {
+        Phong {
+            diffuse: Vector3::new(diffuse, diffuse * 0.5, diffuse * 0.25),
+            mirror: Vector3::repeat(mirror),
+            transmission: Vector3::repeat(transmission),
+            ior: IOR as f64,
+        }
+    }
+
+    // Every model with a lobe that is not specular, which both sampling and eval see
+    fn rough_models() -> Vec<(&'static str, Box<dyn Bsdf>)> {
+        let albedo = Vector3::new(0.9, 0.6, 0.3);
+        let (eta, k) = Conductor::preset("gold").unwrap();
+        vec![
+            ("phong", Box::new(phong(0.8, 0.0, 0.0))),
+            ("lambertian", Box::new(Lambertian { albedo })),
+            ("oren-nayar", Box::new(OrenNayar::new(albedo, 30.0))),
+            (
+                "conductor",
+                Box::new(Conductor::new(eta.cast(), k.cast(), 0.5)),
+            ),
+            (
+                "dielectric",
+                Box::new(Dielectric::new(Vector3::repeat(1.0), IOR, 0.5)),
+            ),
+            ("plastic", Box::new(Plastic::new(albedo, IOR, 0.5))),
+        ]
+    }
+
+    // The rough models and their smooth counterparts
+    fn all_models() -> Vec<(&'static str, Box<dyn Bsdf>)> {
+        let (eta, k) = Conductor::preset("aluminium").unwrap();
+        let mut models = rough_models();
+        models.push(("glass phong", Box::new(phong(0.2, 0.2, 0.6))));
+        models.push((
+            "smooth conductor",
+            Box::new(Conductor::new(eta.cast(), k.cast(), 0.0)),
+        ));
+        models.push((
+            "smooth dielectric",
+            Box::new(Dielectric::new(Vector3::repeat(1.0), IOR, 0.0)),
+        ));
+        models.push((
+            "smooth plastic",
+            Box::new(Plastic::new(Vector3::repeat(0.9), IOR, 0.0)),
+        ));
+        models
+    }
+
+    // Directions from near the normal to near grazing, below the surface too for models that
+    // transmit
+    fn outgoing_directions(bsdf: &dyn Bsdf) -> Vec<Vector3<f64>> {
+        let mut directions: Vec<Vector3<f64>> = [0.95, 0.7, 0.4, 0.15]
+            .iter()
+            .map(|&cos: &f64| Vector3::new((1.0 - cos * cos).sqrt(), 0.0, cos))
+            .collect();
+        if bsdf.transmits() {
+            let below: Vec<Vector3<f64>> = directions.iter().map(|w| -w).collect();
+            directions.extend(below);
+        }
+        directions
+    }
+
+    fn sample(
+        bsdf: &dyn Bsdf,
+        outgoing: &Vector3<f64>,
+        rng: &mut ChaCha8Rng,
+    ) -> Option<BsdfSample> {
+        bsdf.sample(outgoing, rng.gen(), (rng.gen(), rng.gen()))
+    }
+
+    fn assert_close(name: &str, actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance * expected.abs().max(1.0),
+            "{name}: {actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn sample_weight_is_eval_times_cosine_over_pdf() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        for (name, bsdf) in rough_models() {
+            for outgoing in outgoing_directions(bsdf.as_ref()) {
+                for _ in 0..2000 {
+                    let Some(sample) = sample(bsdf.as_ref(), &outgoing, &mut rng) else {
+                        continue;
+                    };
+                    if sample.lobe == Lobe::Specular {
+                        continue;
+                    }
+                    assert!(
+                        sample.pdf > 0.0,
+                        "{name}: sampled a direction of no density"
+                    );
+                    let pdf = bsdf.pdf(&outgoing, &sample.incoming);
+                    assert_close(name, sample.pdf, pdf, 1e-6);
+                    let expected = bsdf.eval(&outgoing, &sample.incoming)
+                        * (sample.incoming.z.abs() / sample.pdf) as f32;
+                    for c in 0..3 {
+                        assert_close(name, sample.weight[c] as f64, expected[c] as f64, 1e-4);
+                    }
+                }
+            }
+        }
+    }
+
+    // Integrated over the sphere, the density pdf gives has to be the share of samples that
+    // are not specular, or it is not the density sample draws from
+    #[test]
+    fn pdf_is_the_density_sample_draws_from() {
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        for (name, bsdf) in all_models() {
+            for outgoing in outgoing_directions(bsdf.as_ref()) {
+                // Jittered over a grid of the sphere, as the rough lobes are peaked
+                let mut integral = 0.0;
+                for i in 0..STRATA {
+                    for j in 0..STRATA {
+                        let u = (i as f64 + rng.gen::<f64>()) / STRATA as f64;
+                        let v = (j as f64 + rng.gen::<f64>()) / STRATA as f64;
+                        integral += bsdf.pdf(&outgoing, &uniform_sphere((u, v))) * 4.0 * PI;
+                    }
+                }
+                let integral = integral / (STRATA * STRATA) as f64;
+                let sampled = (0..SAMPLES)
+                    .filter_map(|_| sample(bsdf.as_ref(), &outgoing, &mut rng))
+                    .filter(|sample| sample.lobe != Lobe::Specular)
+                    .count() as f64
+                    / SAMPLES as f64;
+                assert!(
+                    (integral - sampled).abs() < 0.02,
+                    "{name} from {outgoing:?}: pdf integrates to {integral}, {sampled} sampled"
+                );
+            }
+        }
+    }
+
+    // Swapping the directions leaves a reflection unchanged. Radiance refracted into a denser
+    // medium is compressed, so a transmission holds f(o, i) / n_o^2 = f(i, o) / n_i^2.
+    #[test]
+    fn eval_is_reciprocal() {
+        let mut rng = ChaCha8Rng::seed_from_u64(3);
+        for (name, bsdf) in rough_models() {
+            for _ in 0..2000 {
+                let outgoing = uniform_sphere((rng.gen(), rng.gen()));
+                let mut incoming = uniform_sphere((rng.gen(), rng.gen()));
+                let outgoing = Vector3::new(outgoing.x, outgoing.y, outgoing.z.abs());
+                if !bsdf.transmits() {
+                    incoming.z = incoming.z.abs();
+                }
+                let scale = match incoming.z < 0.0 {
+                    true => IOR * IOR,
+                    false => 1.0,
+                };
+                let forward = bsdf.eval(&outgoing, &incoming) * scale;
+                let backward = bsdf.eval(&incoming, &outgoing);
+                for c in 0..3 {
+                    assert_close(name, forward[c] as f64, backward[c] as f64, 1e-4);
+                }
+            }
+        }
+    }
+
+    // Seen from outside, no model reflects and transmits more light than arrives
+    #[test]
+    fn albedo_is_at_most_one() {
+        let mut rng = ChaCha8Rng::seed_from_u64(4);
+        for (name, bsdf) in all_models() {
+            for outgoing in outgoing_directions(bsdf.as_ref()) {
+                if outgoing.z < 0.0 {
+                    continue;
+                }
+                let mut albedo = Vector3::<f64>::zeros();
+                for _ in 0..SAMPLES {
+                    if let Some(sample) = sample(bsdf.as_ref(), &outgoing, &mut rng) {
+                        albedo += sample.weight.cast();
+                    }
+                }
+                let albedo = albedo / SAMPLES as f64;
+                assert!(
+                    albedo.max() <= 1.01,
+                    "{name} from {outgoing:?} has an albedo of {albedo:?}"
+                );
+            }
+        }
+    }
+
+    // Inside a transparent Phong material only the boundary and the mirror are sampled, so
+    // every path carries on
+    #[test]
+    fn transparent_phong_samples_from_inside() {
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        let bsdf = phong(0.5, 0.1, 0.5);
+        for outgoing in outgoing_directions(&bsdf) {
+            if outgoing.z > 0.0 {
+                continue;
+            }
+            for _ in 0..2000 {
+                let sample = sample(&bsdf, &outgoing, &mut rng);
+                assert!(sample.is_some(), "path from {outgoing:?} ended");
+            }
+        }
+    }
+
+    // Whatever the Fresnel equations do not reflect is refracted: a smooth boundary picks
+    // each in proportion and, with the compression of refracted radiance undone, sends on
+    // all the light arriving
+    #[test]
+    fn fresnel_reflection_and_transmission_sum_to_one() {
+        let bsdf = Dielectric::new(Vector3::repeat(1.0), IOR, 0.0);
+        for outgoing in outgoing_directions(&bsdf) {
+            let fresnel = fresnel_dielectric(outgoing.z, IOR as f64);
+            let (mut reflected, mut refracted) = (0.0, 0.0);
+            for i in 0..STRATA {
+                let choice = (i as f64 + 0.5) / STRATA as f64;
+                let sample = bsdf.sample(&outgoing, choice, (0.5, 0.5)).unwrap();
+                match sample.incoming.z * outgoing.z > 0.0 {
+                    true => reflected += sample.weight.x as f64,
+                    false => {
+                        let (_, eta) = refract(&outgoing, &Vector3::z(), IOR as f64).unwrap();
+                        refracted += sample.weight.x as f64 * eta * eta;
+                    }
+                }
+            }
+            let (reflected, refracted) = (reflected / STRATA as f64, refracted / STRATA as f64);
+            assert_close("reflected", reflected, fresnel, 0.01);
+            assert_close("sum", reflected + refracted, 1.0, 1e-6);
+        }
+    }
+
+    // Light crossing either way reflects as much, 1 past the critical angle, and Schlick
+    // stays close to the exact equations
+    #[test]
+    fn fresnel_is_the_same_from_both_sides() {
+        let eta = IOR as f64;
+        for i in 0..=100 {
+            let cos = i as f64 / 100.0;
+            let outside = fresnel_dielectric(cos, eta);
+            let w = Vector3::new((1.0 - cos * cos).sqrt(), 0.0, cos);
+            if let Some((inside, _)) = refract(&w, &Vector3::z(), eta) {
+                assert_close("inside", fresnel_dielectric(inside.z, eta), outside, 1e-9);
+            }
+            let schlick = fresnel_schlick(cos, eta);
+            assert!((schlick - outside).abs() < 0.05, "schlick at {cos}");
+            assert!((0.0..=1.0).contains(&schlick));
+        }
+        let critical = (1.0 - 1.0 / (eta * eta)).sqrt();
+        for kind in FresnelKind::ALL {
+            assert_eq!(kind.reflectance(-(critical - 1e-3), eta), 1.0);
+            assert!(kind.reflectance(-(critical + 1e-3), eta) < 1.0);
+        }
+    }
+}
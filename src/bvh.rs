@@ -3,13 +3,6 @@ use nalgebra::{distance, Matrix4, Point3, Vector3};
 use std::collections::HashMap;
 use std::fmt;
 
-// Debuging statics
-static mut STATIC0: i32 = 0;
-static mut STATIC1: i32 = 0;
-static mut STATIC2: i32 = 0;
-static mut STATIC3: i32 = 0;
-static mut STATIC4: i32 = 0;
-
 // BOUNDING BOX -----------------------------------------------------------------
 #[derive(Clone)]
 pub struct AABB {
@@ -18,6 +11,7 @@ pub struct AABB {
     pub centroid: Point3<f64>,
 }
 
+#[allow(dead_code)]
 impl AABB {
     // New box with respective coordinates
     pub fn new(bln: Point3<f64>, trf: Point3<f64>) -> AABB {
@@ -149,7 +143,7 @@ impl AABB {
     }
     pub fn area(&self) -> f64 {
         let extent = self.trf - self.bln;
-        return extent.x * extent.y + extent.y * extent.z + extent.z * extent.x;
+        extent.x * extent.y + extent.y * extent.z + extent.z * extent.x
     }
     // Volume of the AABB
     pub fn volume(&self) -> f64 {
@@ -201,15 +195,19 @@ pub struct BVH {
 }
 
 impl BVH {
-    //Build a bvh by subdividing recursively
-    pub fn build(in_nodes: &HashMap<String, Node>) -> BVH {
+    //Build a bvh by subdividing recursively, the shutter is open from `shutter.0` to
+    //`shutter.1` seconds after the scene time and moving nodes are bounded over all of it
+    pub fn build(in_nodes: &HashMap<String, Node>, shutter: (f64, f64)) -> BVH {
         /*
         Make our own vec of nodes so that we can refer to it by index
         This might be expensive so another method is preferred
         */
         let mut nodes = vec![];
-        for (_, node) in in_nodes {
-            nodes.push(node.clone());
+        for node in in_nodes.values() {
+            let mut node = node.clone();
+            node.set_shutter(shutter.0, shutter.1);
+            node.aabb = node.swept_aabb(shutter.0, shutter.1);
+            nodes.push(node);
         }
 
         //A BVH tree will be maximum size of 2*n + 1
@@ -246,13 +244,6 @@ impl BVH {
             let node = &self.nodes[first_prim + i]; //Get the node from the Vec<Node>
             bvh_node_aabb.join_mut(&node.aabb); //Join it with the BVH node's AABB
         }
-
-        // unsafe {
-        //     println!("UPDATE TO AABB ---- {STATIC0}");
-        //     STATIC0 += 1;
-        //     let bvh_node = &mut self.bvh_nodes[index]; //Get the BVHNode we are working on
-        //     println!("{bvh_node}");
-        // }
     }
     // Subdivision, will subdivide a split
     fn subdivide(&mut self, index: usize) {
@@ -263,7 +254,7 @@ impl BVH {
         // Recurse into each of the child nodes.
 
         //Leaf node case, we cannot sub-divide any more
-        if self.bvh_nodes[index].prim_count == 1 {
+        if self.bvh_nodes[index].prim_count <= 1 {
             return;
         };
 
@@ -325,7 +316,11 @@ impl BVH {
                     i += 1; // On Left-Hand-Side
                 } else {
                     self.nodes.swap(i, j);
-                    j -= 1; // On Right-Hand-Side
+                    // On Right-Hand-Side, stop before j wraps below the first index
+                    match j.checked_sub(1) {
+                        Some(next) => j = next,
+                        None => break,
+                    }
                 }
             }
             //Now we have two splits
@@ -339,15 +334,9 @@ impl BVH {
                 return;
             }
         }
-        // unsafe {
-        //     println!("SUBDIVIDE: {STATIC1}");
-        //     println!("SPLIT INTO: {left_count} ");
-        //     STATIC1 += 1;
-        // }
-
         let l_idx = self.nodes_used; //Left child
         self.bvh_nodes[index].l_idx = l_idx;
-        self.nodes_used = self.nodes_used + 2;
+        self.nodes_used += 2;
 
         //Set left node information
         self.bvh_nodes[l_idx].first_prim = self.bvh_nodes[index].first_prim; //Left split begins at parent split
@@ -369,8 +358,12 @@ impl BVH {
     }
     // Traverse the BVH, 0 will be needed to start at root node
     pub fn traverse(&self, ray: &Ray, idx: usize) -> Option<(&Node, Intersection)> {
+        if self.nodes.is_empty() {
+            // Nothing to hit in an empty scene
+            return None;
+        }
         let bvh_node = &self.bvh_nodes[idx];
-        if !bvh_node.aabb.intersect_ray(&ray) {
+        if !bvh_node.aabb.intersect_ray(ray) {
             // No intersection with BVH in world coordinates
             return None;
         }
@@ -383,14 +376,14 @@ impl BVH {
                 if !node.active {
                     continue;
                 }
-                if let Some(intersect) = node.intersect_ray(&ray) {
+                if let Some(intersect) = node.intersect_ray(ray) {
                     if intersect.distance >= EPSILON && intersect.distance < closest_dist {
                         closest_dist = intersect.distance;
                         closest = Some((node, intersect));
                     }
                 }
             }
-            return closest;
+            closest
         } else {
             //Recurse down the BVH
             //Recurse down the BVH right node
@@ -398,22 +391,23 @@ impl BVH {
             let intersect_r = self.traverse(ray, bvh_node.l_idx + 1);
 
             match (intersect_l, intersect_r) {
-                (None, None) => return None,
-                (Some(intersect), None) => return Some(intersect),
-                (None, Some(intersect)) => return Some(intersect),
+                (None, None) => None,
+                (Some(intersect), None) => Some(intersect),
+                (None, Some(intersect)) => Some(intersect),
                 (Some((node_l, inter_l)), Some((node_r, inter_r))) => {
                     //Compare intersect distance
                     let dist_l = distance(&ray.a, &inter_l.point);
                     let dist_r = distance(&ray.a, &inter_r.point);
                     if dist_l < dist_r {
-                        return Some((node_l, inter_l));
+                        Some((node_l, inter_l))
                     } else {
-                        return Some((node_r, inter_r));
+                        Some((node_r, inter_r))
                     }
                 }
             }
         }
     }
+    #[allow(dead_code)]
     fn evaluate_sah(&self, node: &BVHNode, axis: usize, pos: f64) -> f64 {
         // determine triangle counts and bounds for this split candidate
         let mut l_aabb = AABB::empty();
@@ -431,7 +425,11 @@ impl BVH {
             }
         }
         let cost = l_count as f64 * l_aabb.area() + r_count as f64 * r_aabb.area();
-        if cost > 0.0 { cost } else { 1e30 }
+        if cost > 0.0 {
+            cost
+        } else {
+            1e30
+        }
     }
 }
 
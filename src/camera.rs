@@ -1,18 +1,94 @@
-use nalgebra::{Matrix4, Point3, Vector3};
+use crate::animation::{CameraAnimation, Interpolation};
+use crate::ray::Ray;
+use crate::sampler::{concentric_disk, regular_polygon, Sampler};
+use nalgebra::{Matrix4, Point3, Vector2, Vector3};
+use std::f64::consts::PI;
 
+// PROJECTION -----------------------------------------------------------------
+/// How the directions around a camera are laid out on the image
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Projection {
+    /// Pinhole perspective with a vertical field of view
+    Perspective,
+    /// Parallel rays over a view width, without perspective foreshortening
+    Orthographic,
+    /// Equidistant fisheye, the angle from the view direction grows evenly across the image
+    Fisheye,
+    /// Every direction, 360 degrees across the width and 180 degrees down the height
+    Equirectangular,
+}
+
+impl Projection {
+    pub const ALL: [Projection; 4] = [
+        Projection::Perspective,
+        Projection::Orthographic,
+        Projection::Fisheye,
+        Projection::Equirectangular,
+    ];
+
+    /// Parse the names used from Rhai
+    pub fn from_name(name: &str) -> Option<Projection> {
+        match name.to_lowercase().as_str() {
+            "perspective" => Some(Projection::Perspective),
+            "orthographic" | "ortho" => Some(Projection::Orthographic),
+            "fisheye" => Some(Projection::Fisheye),
+            "equirectangular" | "panorama" | "latlong" => Some(Projection::Equirectangular),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Projection::Perspective => "perspective",
+            Projection::Orthographic => "orthographic",
+            Projection::Fisheye => "fisheye",
+            Projection::Equirectangular => "equirectangular",
+        }
+    }
+}
+
+// CAMERA -----------------------------------------------------------------
 /// Annotate the Camera struct
 #[derive(Clone)]
 pub struct Camera {
     pub eye: Point3<f64>,
     pub target: Point3<f64>,
     pub up: Vector3<f64>,
+    /// Vertical field of view in degrees of a perspective camera
+    pub fov: f64,
+    /// Width over height of the sensor, 0 to match the image so pixels stay square
+    pub aspect: f64,
+    /// Offset of the image from the view direction, in widths and heights of the image
+    pub shift: Vector2<f64>,
+    /// Distance in front of the camera that rays start at, hiding anything closer
+    pub near: f64,
+    /// Radius of the lens aperture, 0 for a pinhole with everything in focus
+    pub aperture: f64,
+    /// Distance along the view direction of the plane in perfect focus
+    pub focus_distance: f64,
+    /// Sides of a polygonal aperture for shaped bokeh, below 3 for a round one
+    pub blades: u32,
+    /// Rotation of the aperture polygon in degrees
+    pub blade_rotation: f64,
+    /// Seconds after the frame time the shutter opens and closes, moving nodes blur
+    /// over the time between. Equal times give no motion blur.
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    /// How directions around the camera are laid out on the image
+    pub projection: Projection,
+    /// World units across the width of the image for an orthographic camera
+    pub view_width: f64,
+    /// Degrees across the height of the image for a fisheye camera, the image circle
+    /// it fits in is empty outside
+    pub fisheye_fov: f64,
     pub _view: Matrix4<f64>,
     pub _inv_view: Matrix4<f64>,
+    pub animation: CameraAnimation,
 }
 
 #[allow(dead_code)]
 impl Camera {
-    /// Create a new camera with the given eye, target, and up vectors
+    /// Create a new pinhole camera with the given eye, target, and up vectors, focused on the target
     pub fn new(eye: Point3<f64>, target: Point3<f64>, up: Vector3<f64>) -> Self {
         let view = Matrix4::look_at_lh(&eye, &target, &up);
         let inv_view = view.try_inverse().unwrap();
@@ -20,8 +96,37 @@ impl Camera {
             eye,
             target,
             up,
+            fov: 70.0,
+            aspect: 0.0,
+            shift: Vector2::zeros(),
+            near: 0.0,
+            aperture: 0.0,
+            focus_distance: (target - eye).norm(),
+            blades: 0,
+            blade_rotation: 0.0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            projection: Projection::Perspective,
+            view_width: 4.0,
+            fisheye_fov: 180.0,
             _view: view,
             _inv_view: inv_view,
+            animation: CameraAnimation::default(),
+        }
+    }
+
+    /// Create a thin lens camera with an aperture radius and a focus distance
+    pub fn with_lens(
+        eye: Point3<f64>,
+        target: Point3<f64>,
+        up: Vector3<f64>,
+        aperture: f64,
+        focus_distance: f64,
+    ) -> Self {
+        Camera {
+            aperture,
+            focus_distance,
+            ..Camera::new(eye, target, up)
         }
     }
 
@@ -35,13 +140,21 @@ impl Camera {
 
     /// Set the position of the camera's eye
     pub fn set_eye(&mut self, new_eye: Point3<f64>) {
-        self.eye = new_eye;
-        self.recalculate_matrix();
+        self.look_at(new_eye, self.target);
     }
 
     /// Set the position the camera is looking at
     pub fn set_target(&mut self, new_target: Point3<f64>) {
-        self.target = new_target;
+        self.look_at(self.eye, new_target);
+    }
+
+    /// Move the eye and the target together. The plane of focus keeps its distance from the
+    /// target, so a camera focused on its target stays focused on it.
+    pub fn look_at(&mut self, eye: Point3<f64>, target: Point3<f64>) {
+        let before = (self.target - self.eye).norm();
+        self.eye = eye;
+        self.target = target;
+        self.focus_distance += (self.target - self.eye).norm() - before;
         self.recalculate_matrix();
     }
 
@@ -64,25 +177,19 @@ impl Camera {
     /// Move the camera forward/backward along its view direction (moves both eye and target)
     pub fn move_forward(&mut self, amount: f64) {
         let dir = self.forward() * amount;
-        self.eye += dir;
-        self.target += dir;
-        self.recalculate_matrix();
+        self.look_at(self.eye + dir, self.target + dir);
     }
 
     /// Strafe the camera left/right (moves both eye and target)
     pub fn move_right(&mut self, amount: f64) {
         let dir = self.right() * amount;
-        self.eye += dir;
-        self.target += dir;
-        self.recalculate_matrix();
+        self.look_at(self.eye + dir, self.target + dir);
     }
 
     /// Move the camera up/down along the up vector (moves both eye and target)
     pub fn move_up(&mut self, amount: f64) {
         let dir = self.up.normalize() * amount;
-        self.eye += dir;
-        self.target += dir;
-        self.recalculate_matrix();
+        self.look_at(self.eye + dir, self.target + dir);
     }
 
     /// Orbit the camera around the target point by yaw (horizontal) and pitch (vertical) angles in radians
@@ -107,8 +214,30 @@ impl Camera {
             radius * new_pitch.cos() * new_yaw.sin(),
         );
 
-        self.eye = self.target + new_offset;
-        self.recalculate_matrix();
+        self.look_at(self.target + new_offset, self.target);
+    }
+
+    /// Record the current eye, target and up vector as a keyframe at time
+    pub fn keyframe(&mut self, time: f64, interpolation: Interpolation) {
+        let animation = &mut self.animation;
+        animation.eye.insert(time, self.eye.coords, interpolation);
+        animation
+            .target
+            .insert(time, self.target.coords, interpolation);
+        animation.up.insert(time, self.up, interpolation);
+    }
+
+    /// Move the camera to where its keyframes place it at time
+    pub fn pose(&mut self, time: f64) {
+        if self.animation.is_empty() {
+            return;
+        }
+        if let Some(up) = self.animation.up.sample(time) {
+            self.up = up;
+        }
+        let eye = self.animation.eye.sample(time).map(Point3::from);
+        let target = self.animation.target.sample(time).map(Point3::from);
+        self.look_at(eye.unwrap_or(self.eye), target.unwrap_or(self.target));
     }
 
     /// Recalculate the view and inverse view matrices based on the current eye, target, and up vectors
@@ -117,3 +246,250 @@ impl Camera {
         self._inv_view = self._view.try_inverse().unwrap();
     }
 }
+
+// FILM -----------------------------------------------------------------
+/// The image plane of a camera at one resolution, turns positions on it into rays
+#[derive(Clone, Copy)]
+pub struct Film {
+    eye: Point3<f64>,
+    forward: Vector3<f64>,
+    right: Vector3<f64>,
+    up: Vector3<f64>,
+    projection: Projection,
+    // Width and height of a pixel, on the image plane one unit in front of the eye for
+    // perspective, in world units for orthographic and in radians for the fisheye and panorama
+    pixel: Vector2<f64>,
+    // Largest angle from the view direction a fisheye sees, half its field of view in radians
+    fisheye_radius: f64,
+    // Steps across one pixel to the right and one pixel up
+    dx: Vector3<f64>,
+    dy: Vector3<f64>,
+    width: f64,
+    height: f64,
+    shift: Vector2<f64>,
+    near: f64,
+    aperture: f64,
+    focus_distance: f64,
+    blades: u32,
+    blade_rotation: f64,
+    shutter_open: f64,
+    shutter_close: f64,
+}
+
+impl Film {
+    /// Film of `width` by `height` pixels, everything else comes from the camera
+    pub fn new(camera: &Camera, width: u32, height: u32) -> Film {
+        let (width, height) = (width as f64, height as f64);
+        // How much wider than square a pixel is on a sensor with a different aspect
+        let stretch = match camera.aspect > 0.0 {
+            true => camera.aspect * height / width,
+            false => 1.0,
+        };
+        // Vectors pointing forward, right and up
+        let forward = camera.forward();
+        let right = camera.right();
+        let up = right.cross(&forward).normalize();
+        let pixel = match camera.projection {
+            Projection::Perspective => {
+                let pixel = 2.0 * (camera.fov.to_radians() / 2.0).tan() / height;
+                Vector2::new(pixel * stretch, pixel)
+            }
+            Projection::Orthographic => {
+                let pixel = camera.view_width / width;
+                Vector2::new(pixel, pixel / stretch)
+            }
+            Projection::Fisheye => {
+                let pixel = camera.fisheye_fov.to_radians() / height;
+                Vector2::new(pixel * stretch, pixel)
+            }
+            // Always covers every direction, whatever the aspect
+            Projection::Equirectangular => Vector2::new(2.0 * PI / width, PI / height),
+        };
+        Film {
+            eye: camera.eye,
+            forward,
+            right,
+            up,
+            projection: camera.projection,
+            pixel,
+            fisheye_radius: camera.fisheye_fov.to_radians() / 2.0,
+            dx: right * pixel.x,
+            dy: up * pixel.y,
+            width,
+            height,
+            shift: camera.shift,
+            near: camera.near,
+            aperture: camera.aperture,
+            focus_distance: camera.focus_distance,
+            blades: camera.blades,
+            blade_rotation: camera.blade_rotation,
+            shutter_open: camera.shutter_open,
+            shutter_close: camera.shutter_close,
+        }
+    }
+
+    /// Time within the shutter for one sample. A shutter that never opens takes no number
+    /// from the sampler, leaving every dimension to the bounces.
+    pub fn time(&self, sampler: &mut Sampler) -> f64 {
+        match self.shutter_close > self.shutter_open {
+            true => {
+                let u = sampler.next_1d();
+                self.shutter_open + (self.shutter_close - self.shutter_open) * u
+            }
+            false => self.shutter_open,
+        }
+    }
+
+    /// Time halfway between the shutter opening and closing
+    pub fn shutter_middle(&self) -> f64 {
+        (self.shutter_open + self.shutter_close) / 2.0
+    }
+
+    /// Ray through a position on the film in pixels, (0, 0) is the top left corner,
+    /// leaving the lens at a position given by two numbers in [0, 1) at a time in the shutter.
+    /// None where the projection does not cover the film, outside the fisheye circle.
+    pub fn ray(&self, x: f64, y: f64, lens: (f64, f64), time: f64) -> Option<Ray> {
+        let (origin, direction) = self.aim(x, y)?;
+        let thin_lens = matches!(
+            self.projection,
+            Projection::Perspective | Projection::Orthographic
+        );
+        if self.aperture <= 0.0 || !thin_lens {
+            return Some(self.clip(origin, direction, time));
+        }
+        // Every ray through this film position meets on the focus plane
+        let focus = origin + direction * self.focus_distance;
+        let (lx, ly) = match self.blades {
+            0..=2 => concentric_disk(lens),
+            blades => regular_polygon(lens, blades, self.blade_rotation.to_radians()),
+        };
+        let origin = origin + (self.right * lx + self.up * ly) * self.aperture;
+        Some(self.clip(origin, focus - origin, time))
+    }
+
+    /// Ray through a position on the film leaving the centre of the lens, as a pinhole
+    /// camera would trace it. None where the projection does not cover the film.
+    pub fn centre_ray(&self, x: f64, y: f64, time: f64) -> Option<Ray> {
+        let (origin, direction) = self.aim(x, y)?;
+        Some(self.clip(origin, direction, time))
+    }
+
+    // Origin and direction of the ray through a position on the film from the centre of
+    // the lens, None outside the fisheye circle
+    fn aim(&self, x: f64, y: f64) -> Option<(Point3<f64>, Vector3<f64>)> {
+        // Offset from the centre of the view, y pointing up
+        let fx = x - self.width / 2.0 + self.shift.x * self.width;
+        let fy = self.height / 2.0 - y + self.shift.y * self.height;
+        let across = fx * self.dx + fy * self.dy;
+        // Directions with a forward component of 1, so the focus plane is one distance away
+        match self.projection {
+            Projection::Perspective => Some((self.eye, self.forward + across)),
+            Projection::Orthographic => Some((self.eye + across, self.forward)),
+            // Angle from the view direction grows evenly with distance from the centre
+            Projection::Fisheye => {
+                let theta = (fx * self.pixel.x).hypot(fy * self.pixel.y);
+                if theta > self.fisheye_radius.min(PI) {
+                    return None;
+                }
+                let phi = fy.atan2(fx);
+                let sideways = self.right * phi.cos() + self.up * phi.sin();
+                Some((
+                    self.eye,
+                    self.forward * theta.cos() + sideways * theta.sin(),
+                ))
+            }
+            // Longitude across the width, latitude from the top to the bottom
+            Projection::Equirectangular => {
+                let longitude = fx * self.pixel.x;
+                let latitude = (fy * self.pixel.y).clamp(-PI / 2.0, PI / 2.0);
+                let level = self.forward * longitude.cos() + self.right * longitude.sin();
+                Some((self.eye, level * latitude.cos() + self.up * latitude.sin()))
+            }
+        }
+    }
+
+    // Ray moved forward to the near clip, a plane across the view for perspective and
+    // orthographic cameras and a sphere around the eye for the others
+    fn clip(&self, origin: Point3<f64>, direction: Vector3<f64>, time: f64) -> Ray {
+        if self.near <= 0.0 {
+            return Ray::new(origin, direction).with_time(time);
+        }
+        let distance = match self.projection {
+            Projection::Perspective | Projection::Orthographic => {
+                self.near / direction.normalize().dot(&self.forward)
+            }
+            Projection::Fisheye | Projection::Equirectangular => self.near,
+        };
+        Ray::new(origin + direction.normalize() * distance, direction).with_time(time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        Camera::new(
+            Point3::new(0.0, 1.0, 5.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        )
+    }
+
+    fn distance(camera: &Camera) -> f64 {
+        (camera.target - camera.eye).norm()
+    }
+
+    #[test]
+    fn focus_stays_on_the_target() {
+        let mut camera = camera();
+        camera.move_forward(1.5);
+        camera.move_right(-0.7);
+        camera.move_up(0.3);
+        camera.orbit(0.8, -0.4);
+        assert!((camera.focus_distance - distance(&camera)).abs() < 1e-9);
+        camera.set_eye(Point3::new(3.0, 2.0, 1.0));
+        assert!((camera.focus_distance - distance(&camera)).abs() < 1e-9);
+        camera.set_target(Point3::new(-1.0, 0.0, -2.0));
+        assert!((camera.focus_distance - distance(&camera)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn focus_keeps_its_offset_from_the_target() {
+        let mut camera = camera();
+        camera.focus_distance = 3.0;
+        camera.set_eye(Point3::new(0.0, 1.0, 9.0));
+        assert!((camera.focus_distance - 7.0).abs() < 1e-9);
+        camera.orbit(1.0, 0.5);
+        camera.move_forward(2.0);
+        assert!((camera.focus_distance - 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn focus_follows_keyframed_moves() {
+        let mut camera = camera();
+        camera.keyframe(0.0, Interpolation::Linear);
+        camera.set_eye(Point3::new(0.0, 1.0, 2.0));
+        camera.keyframe(1.0, Interpolation::Linear);
+        for time in [0.0, 0.5, 1.0, 0.25] {
+            camera.pose(time);
+            assert!((camera.focus_distance - distance(&camera)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn centre_ray_leaves_the_middle_of_any_aperture() {
+        let pinhole = Film::new(&camera(), 64, 48);
+        let expected = pinhole.ray(20.5, 30.5, (0.5, 0.5), 0.0).unwrap();
+        for blades in [0, 3, 5, 6] {
+            let mut camera = camera();
+            camera.aperture = 0.3;
+            camera.blades = blades;
+            let ray = Film::new(&camera, 64, 48)
+                .centre_ray(20.5, 30.5, 0.0)
+                .unwrap();
+            assert!((ray.a - expected.a).norm() < 1e-12, "{blades} blades");
+            assert!((ray.b - expected.b).norm() < 1e-12, "{blades} blades");
+        }
+    }
+}
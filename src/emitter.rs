@@ -0,0 +1,179 @@
+use crate::{
+    light::Light,
+    node::Node,
+    ray::Ray,
+    sampler::{orient, uniform_cone, uniform_sphere},
+    scene::Scene,
+    INFINITY,
+};
+use nalgebra::{Point3, Vector3};
+use std::f64::consts::PI;
+
+// Shadow rays towards a point on an emissive node stop this fraction of the way short of it,
+// so they are not blocked by the node itself
+const SHADOW_CLEARANCE: f64 = 1e-4;
+
+// EMITTER -----------------------------------------------------------------
+/// A light with an area. The path tracer aims shadow rays at it and bounce rays can run into it,
+/// so it can be reached by either sampling strategy.
+pub enum Emitter<'a> {
+    /// A light with a radius, glowing evenly over its sphere
+    Sphere { label: &'a str, light: &'a Light },
+    /// A node with an emission, glowing evenly over the front of its surface
+    Area(&'a Node),
+    /// The ambient lights together, a uniform sky around the scene
+    Sky(Vector3<f32>),
+}
+
+/// A direction towards an emitter picked by light sampling
+pub struct EmitterSample {
+    pub direction: Vector3<f64>,
+    /// How far a shadow ray goes before it reaches the emitter
+    pub distance: f64,
+    pub radiance: Vector3<f32>,
+    /// Density of the direction over solid angle
+    pub pdf: f64,
+}
+
+impl<'a> Emitter<'a> {
+    /// Every emitter of the scene, ordered by label so each one draws the same sampler
+    /// dimensions from one render to the next
+    pub fn gather(scene: &'a Scene) -> Vec<Emitter<'a>> {
+        let mut emitters: Vec<Emitter> = scene
+            .lights
+            .iter()
+            .filter(|(_, light)| light.active && !light.ambient && light.radius > 0.0)
+            .map(|(label, light)| Emitter::Sphere { label, light })
+            .collect();
+        emitters.extend(Emitter::areas(scene));
+        emitters.sort_by(|a, b| a.label().cmp(b.label()));
+        let sky: Vector3<f32> = scene
+            .lights
+            .values()
+            .filter(|light| light.active && light.ambient)
+            .map(|light| light.colour)
+            .sum();
+        if sky != Vector3::zeros() {
+            emitters.push(Emitter::Sky(sky));
+        }
+        emitters
+    }
+
+    /// The emissive nodes whose surface can be sampled, ordered by label
+    pub fn areas(scene: &'a Scene) -> Vec<Emitter<'a>> {
+        let mut areas: Vec<Emitter> = scene
+            .nodes
+            .values()
+            .filter(|node| node.active && node.emits() && node.primitive.area().is_some())
+            .map(Emitter::Area)
+            .collect();
+        areas.sort_by(|a, b| a.label().cmp(b.label()));
+        areas
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            Emitter::Sphere { label, .. } => label,
+            Emitter::Area(node) => &node.label,
+            Emitter::Sky(_) => "sky",
+        }
+    }
+
+    pub fn is_sky(&self) -> bool {
+        matches!(self, Emitter::Sky(_))
+    }
+
+    /// Distance along the ray to the emitter, the sky is hit by everything at `INFINITY`.
+    /// Rays meet emissive nodes as they do any other node, so those are never hit here.
+    pub fn hit(&self, ray: &Ray) -> Option<f64> {
+        match self {
+            Emitter::Sphere { light, .. } => light.hit(ray),
+            Emitter::Area(_) => None,
+            Emitter::Sky(_) => Some(INFINITY),
+        }
+    }
+
+    /// Pick a direction towards the emitter from point at a ray's time, None from inside a
+    /// sphere or behind the picked point of a node
+    pub fn sample(
+        &self,
+        point: &Point3<f64>,
+        time: f64,
+        falloff: bool,
+        sample: (f64, f64),
+    ) -> Option<EmitterSample> {
+        match self {
+            Emitter::Sphere { light, .. } => {
+                // Only the cone of directions that meet the sphere is sampled
+                let to_centre = light.position - point;
+                let centre_distance = to_centre.norm();
+                let cos_max = light.cone(centre_distance)?;
+                let axis = to_centre / centre_distance;
+                let local = uniform_cone(sample, cos_max);
+                let direction = orient(&local, &axis);
+                // Nearer of the two points where the direction meets the sphere
+                let sin = (1.0 - local.z * local.z).max(0.0).sqrt();
+                let across = light.radius * light.radius - (centre_distance * sin).powi(2);
+                Some(EmitterSample {
+                    direction,
+                    distance: centre_distance * local.z - across.max(0.0).sqrt(),
+                    radiance: light.sphere_radiance(centre_distance, falloff),
+                    pdf: cone_pdf(cos_max),
+                })
+            }
+            Emitter::Area(node) => {
+                let surface = node.sample_surface(time, sample)?;
+                let to_surface = surface.point - point;
+                let distance = to_surface.norm();
+                let direction = to_surface / distance;
+                let radiance = node.emitted(&surface.normal, &-direction);
+                if radiance == Vector3::zeros() {
+                    return None;
+                }
+                // From density over area to density over solid angle
+                let cos = -surface.normal.dot(&direction);
+                Some(EmitterSample {
+                    direction,
+                    distance: distance * (1.0 - SHADOW_CLEARANCE),
+                    radiance,
+                    pdf: surface.pdf * distance * distance / cos,
+                })
+            }
+            Emitter::Sky(colour) => Some(EmitterSample {
+                direction: uniform_sphere(sample),
+                distance: INFINITY,
+                radiance: *colour,
+                pdf: 1.0 / (4.0 * PI),
+            }),
+        }
+    }
+
+    /// Density over solid angle of `sample` picking a direction from point that meets the
+    /// emitter. It depends on where a node is met, see `Node::light_pdf`.
+    pub fn pdf(&self, point: &Point3<f64>) -> f64 {
+        match self {
+            Emitter::Sphere { light, .. } => light
+                .cone((light.position - point).norm())
+                .map_or(0.0, cone_pdf),
+            Emitter::Area(_) => 0.0,
+            Emitter::Sky(_) => 1.0 / (4.0 * PI),
+        }
+    }
+
+    /// Radiance reaching point from the emitter. It depends on where a node is met, see
+    /// `Node::emitted`.
+    pub fn radiance(&self, point: &Point3<f64>, falloff: bool) -> Vector3<f32> {
+        match self {
+            Emitter::Sphere { light, .. } => {
+                light.sphere_radiance((light.position - point).norm(), falloff)
+            }
+            Emitter::Area(_) => Vector3::zeros(),
+            Emitter::Sky(colour) => *colour,
+        }
+    }
+}
+
+// Density of a direction picked uniformly from a cone
+fn cone_pdf(cos_max: f64) -> f64 {
+    1.0 / (2.0 * PI * (1.0 - cos_max))
+}
@@ -0,0 +1,220 @@
+use std::f32::consts::PI;
+
+// FILTER KIND -----------------------------------------------------------------
+/// Shape of the weight given to a sample by the pixels around it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilterKind {
+    /// Equal weight for every sample in reach, at radius 0.5 a plain average per pixel
+    Box,
+    /// Weight falling off linearly to the radius
+    Tent,
+    /// Gaussian with a standard deviation of a third of the radius, shifted to reach 0 at it
+    Gaussian,
+    /// Mitchell-Netravali cubic with B = C = 1/3, sharpens a little with small negative lobes
+    Mitchell,
+    /// Sinc windowed by a wider sinc, one lobe for every pixel of radius
+    Lanczos,
+}
+
+impl FilterKind {
+    pub const ALL: [FilterKind; 5] = [
+        FilterKind::Box,
+        FilterKind::Tent,
+        FilterKind::Gaussian,
+        FilterKind::Mitchell,
+        FilterKind::Lanczos,
+    ];
+
+    /// Parse the names used from the command line
+    pub fn from_name(name: &str) -> Option<FilterKind> {
+        match name.to_lowercase().as_str() {
+            "box" => Some(FilterKind::Box),
+            "tent" | "triangle" => Some(FilterKind::Tent),
+            "gaussian" => Some(FilterKind::Gaussian),
+            "mitchell" => Some(FilterKind::Mitchell),
+            "lanczos" => Some(FilterKind::Lanczos),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterKind::Box => "box",
+            FilterKind::Tent => "tent",
+            FilterKind::Gaussian => "gaussian",
+            FilterKind::Mitchell => "mitchell",
+            FilterKind::Lanczos => "lanczos",
+        }
+    }
+
+    /// Radius in pixels the filter is usually used with
+    pub fn default_radius(&self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0,
+        }
+    }
+}
+
+// FILTER -----------------------------------------------------------------
+/// Pixel reconstruction filter, every sample is splatted into the pixels
+/// whose centre lies within `radius` of it, weighted by the filter
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Filter {
+    pub kind: FilterKind,
+    /// Reach of the filter in pixels
+    pub radius: f32,
+}
+
+impl Filter {
+    pub fn default() -> Filter {
+        Filter::new(FilterKind::Box)
+    }
+
+    /// Filter of a kind at its usual radius
+    pub fn new(kind: FilterKind) -> Filter {
+        Filter {
+            kind,
+            radius: kind.default_radius(),
+        }
+    }
+
+    /// Pixels past the one a sample lands in that it can still reach
+    pub fn margin(&self) -> u32 {
+        (self.radius - 0.5).ceil().max(0.0) as u32
+    }
+
+    /// Weight of a sample at an offset in pixels from a pixel centre, along one axis.
+    /// The filters are separable so the 2D weight is the product over x and y.
+    pub fn weight(&self, offset: f32) -> f32 {
+        let radius = self.radius;
+        let x = offset.abs();
+        if x >= radius {
+            // A box owns the sample on its right edge, so the one on its left does not
+            return match self.kind == FilterKind::Box && offset == radius {
+                true => 1.0,
+                false => 0.0,
+            };
+        }
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => radius - x,
+            FilterKind::Gaussian => {
+                let sigma = radius / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            FilterKind::Mitchell => mitchell(2.0 * x / radius),
+            FilterKind::Lanczos => sinc(x) * sinc(x / radius),
+        }
+    }
+}
+
+// Mitchell-Netravali cubic over 0 to 2 with B = C = 1/3
+fn mitchell(x: f32) -> f32 {
+    const B: f32 = 1.0 / 3.0;
+    const C: f32 = 1.0 / 3.0;
+    let value = match x < 1.0 {
+        true => {
+            (12.0 - 9.0 * B - 6.0 * C) * x * x * x
+                + (-18.0 + 12.0 * B + 6.0 * C) * x * x
+                + (6.0 - 2.0 * B)
+        }
+        false => {
+            (-B - 6.0 * C) * x * x * x
+                + (6.0 * B + 30.0 * C) * x * x
+                + (-12.0 * B - 48.0 * C) * x
+                + (8.0 * B + 24.0 * C)
+        }
+    };
+    value / 6.0
+}
+
+fn sinc(x: f32) -> f32 {
+    match x.abs() < 1e-5 {
+        true => 1.0,
+        false => (PI * x).sin() / (PI * x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Steps per pixel of the numerical integrals
+    const STEPS: usize = 10_000;
+
+    fn integral(filter: &Filter) -> f32 {
+        let step = 2.0 * filter.radius / STEPS as f32;
+        (0..STEPS)
+            .map(|i| filter.weight(-filter.radius + (i as f32 + 0.5) * step) * step)
+            .sum()
+    }
+
+    #[test]
+    fn weight_at_the_centre() {
+        let centre = |kind| Filter::new(kind).weight(0.0);
+        assert_eq!(centre(FilterKind::Box), 1.0);
+        assert_eq!(centre(FilterKind::Tent), 1.0);
+        assert!((centre(FilterKind::Gaussian) - (1.0 - (-4.5f32).exp())).abs() < 1e-6);
+        assert!((centre(FilterKind::Mitchell) - 8.0 / 9.0).abs() < 1e-6);
+        assert_eq!(centre(FilterKind::Lanczos), 1.0);
+    }
+
+    #[test]
+    fn weight_reaches_zero_at_the_radius() {
+        for kind in FilterKind::ALL {
+            let filter = Filter::new(kind);
+            let radius = filter.radius;
+            assert_eq!(filter.weight(-radius), 0.0, "{}", kind.name());
+            assert_eq!(filter.weight(radius + 0.01), 0.0, "{}", kind.name());
+            if kind != FilterKind::Box {
+                assert_eq!(filter.weight(radius), 0.0, "{}", kind.name());
+                // And fades out towards it rather than stopping short
+                assert!(filter.weight(radius - 1e-3).abs() < 1e-2, "{}", kind.name());
+            }
+        }
+        // A sample on the edge between two pixels belongs to just one of them
+        assert_eq!(Filter::new(FilterKind::Box).weight(0.5), 1.0);
+    }
+
+    #[test]
+    fn weight_is_symmetric() {
+        for kind in FilterKind::ALL {
+            let filter = Filter::new(kind);
+            for i in 0..100 {
+                let offset = i as f32 / 100.0 * filter.radius;
+                assert_eq!(
+                    filter.weight(offset),
+                    filter.weight(-offset),
+                    "{} at {offset}",
+                    kind.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mitchell_and_lanczos_integrate_to_one() {
+        for kind in [FilterKind::Mitchell, FilterKind::Lanczos] {
+            let integral = integral(&Filter::new(kind));
+            assert!(
+                (integral - 1.0).abs() < 0.02,
+                "{} is {integral}",
+                kind.name()
+            );
+        }
+    }
+
+    #[test]
+    fn margin_covers_the_radius() {
+        let margins: Vec<u32> = FilterKind::ALL
+            .iter()
+            .map(|kind| Filter::new(*kind).margin())
+            .collect();
+        assert_eq!(margins, [0, 1, 1, 2, 3]);
+    }
+}
@@ -1,16 +1,23 @@
-use crate::{
-    camera::Camera,
-    light::Light,
-    material::*,
-    node::*,
-    primitive::*,
-    scene::*,
-    state::{RaytracingOption, INIT_FILE, SAVE_FILE},
-};
+use crate::state::{INIT_FILE, INSPECT_FILE, SAVE_FILE};
 use imgui::*;
-use nalgebra::{Point3, Vector3};
+use nalgebra::Vector3;
 use pixels::{wgpu, PixelsContext};
 use rhai::Engine;
+use rust_opengl::{
+    aov::Aov,
+    bsdf::FresnelKind,
+    camera::{Camera, Projection},
+    filter::{Filter, FilterKind},
+    inspect::RayRecord,
+    integrator::{IntegratorKind, MisHeuristic},
+    material::BsdfModel,
+    renderer::{Pick, RaytracingOption, RenderProgress},
+    sampler::SamplerKind,
+    scene::Scene,
+    script::{eval_scene, init_engine},
+    tile::TileOrder,
+    tonemap::{DisplayTransform, ToneMapper},
+};
 use std::time::{Duration, Instant};
 
 //BUFFER CONSTANTS
@@ -20,16 +27,24 @@ const BUFFER_PROPORTION_MAX: f32 = 1.0;
 //RAY CONSTANTS
 const MIN_THREADS: u32 = 1;
 const MAX_THREADS: u32 = 12;
-const RAYS_MIN: u32 = 100;
-const RAYS_MAX: u32 = 10000;
+const MIN_TILE_SIZE: u32 = 4;
+const MAX_TILE_SIZE: u32 = 128;
 const MIN_DEPTH: u8 = 1;
 const MAX_DEPTH: u8 = 10;
+const MIN_BOUNCES: u8 = 1;
+const MAX_BOUNCES: u8 = 64;
+const MAX_ROULETTE_BOUNCES: u8 = 16;
 const MIN_SAMPLES: u32 = 1;
 const MAX_SAMPLES: u32 = 10;
-const MIN_RANDOM: f64 = 100.0;
-const MAX_RANDOM: f64 = 1000.0;
-const MIN_EPSILON: f64 = 1e-11;
-const MAX_EPSILON: f64 = 1.0;
+const MIN_PASSES: u32 = 1;
+const MAX_PASSES: u32 = 4096;
+const MIN_FILTER_RADIUS: f32 = 0.5;
+const MAX_FILTER_RADIUS: f32 = 4.0;
+const MIN_NOISE_THRESHOLD: f32 = 0.001;
+const MAX_NOISE_THRESHOLD: f32 = 0.5;
+const MIN_ADAPTIVE_SAMPLES: u32 = 2;
+const MAX_ADAPTIVE_SAMPLES: u32 = 256;
+const DEFAULT_PASSES: u32 = 64;
 
 //DIFFUSE CONSTANTS
 const MIN_DIFFUSE_RAYS: u8 = 1;
@@ -40,6 +55,11 @@ const MAX_DIFFUSE_COEFFICIENT: f32 = 1.0;
 //MATERIAL CONSTANTS
 const MIN_SHINE: f32 = 0.0;
 const MAX_SHINE: f32 = 50.0;
+const MAX_SIGMA: f32 = 90.0;
+const MIN_IOR: f32 = 1.0;
+const MAX_IOR: f32 = 3.0;
+const MAX_ABSORPTION: f32 = 10.0;
+const MAX_STRENGTH: f32 = 50.0;
 
 //TRANSFORMATION CONSTANTS
 const MIN_FALLOFF: f32 = 0.0;
@@ -49,22 +69,50 @@ const MIN_ROTATION: f64 = -180.0;
 const MIN_TRANSLATE: f64 = -10.0;
 //--
 const MAX_FALLOFF: f32 = 1.0;
+const MAX_LIGHT_RADIUS: f64 = 2.0;
 const MAX_SCALE: f64 = 3.0;
 //const MAX_POSITION: f64 = 10.0;
 const MAX_ROTATION: f64 = 180.0;
 const MAX_TRANSLATE: f64 = 10.0;
 
+// ANIMATION CONSTANTS
+const MIN_TIME: f64 = 0.0;
+const MAX_TIME: f64 = 60.0;
+
+// DISPLAY CONSTANTS
+const MIN_EXPOSURE: f32 = -10.0;
+const MAX_EXPOSURE: f32 = 10.0;
+
 // CAMERA CONSTANTS
+const MIN_APERTURE: f64 = 0.0;
+const MAX_APERTURE: f64 = 1.0;
+const MIN_FOCUS: f64 = 0.01;
+const MAX_FOCUS: f64 = 100.0;
+const MAX_BLADES: u32 = 12;
 const MIN_FOV: f64 = 10.0;
 const MAX_FOV: f64 = 160.0;
+const MAX_ASPECT: f64 = 4.0;
+const MIN_SHIFT: f64 = -1.0;
+const MAX_SHIFT: f64 = 1.0;
+const MAX_NEAR: f64 = 100.0;
+const MIN_SHUTTER: f64 = -1.0;
+const MAX_SHUTTER: f64 = 1.0;
+const MIN_VIEW_WIDTH: f64 = 0.1;
+const MAX_VIEW_WIDTH: f64 = 100.0;
+const MAX_FISHEYE_FOV: f64 = 360.0;
 //const CAMERA_INIT: f32 = 5.0;
 
 /// Manages all state required for rendering Dear ImGui over `Pixels`test.
+#[allow(clippy::large_enum_variant)]
 pub enum GuiEvent {
     RaytracerOption(RaytracingOption),
     CameraUpdate(Camera),
     SceneLoad(Scene),
     SaveImage(String),
+    DisplayUpdate(DisplayTransform),
+    AovView(Aov),
+    SaveAovs(String),
+    SaveLayers(String),
 }
 pub struct Gui {
     imgui: imgui::Context,
@@ -77,6 +125,7 @@ pub struct Gui {
 
     render_start: Option<Instant>,
     render_elapsed: Option<Duration>,
+    render_progress: Option<RenderProgress>,
 
     script_filename: String,
     script: String,
@@ -88,6 +137,25 @@ pub struct Gui {
     camera: Camera,
 
     image_filename: String,
+
+    aov_view: Aov,
+
+    time: f64,
+
+    // Node last clicked in the viewport, its tree node is opened once after each click
+    selection: Option<Pick>,
+    reveal_selection: bool,
+
+    // Rays recorded through the pixel last shift-clicked in the viewport
+    inspection: Option<Inspection>,
+    reveal_inspection: bool,
+    inspect_filename: String,
+}
+
+// Pixel a ray tree was recorded through, None where no ray leaves the camera
+struct Inspection {
+    pixel: (u32, u32),
+    record: Option<RayRecord>,
 }
 
 impl Gui {
@@ -140,17 +208,32 @@ impl Gui {
 
             render_start: None,
             render_elapsed: None,
+            render_progress: None,
 
             script_filename: String::from(INIT_FILE),
             script: String::new(),
             engine: init_engine(),
             scene: Scene::empty(),
 
-            raytracing_option: RaytracingOption::default(),
+            // Keep refining a still image for a while
+            raytracing_option: RaytracingOption {
+                passes: DEFAULT_PASSES,
+                ..RaytracingOption::default()
+            },
 
             camera: Camera::unit(),
 
             image_filename: String::from(SAVE_FILE),
+            aov_view: Aov::Beauty,
+
+            time: 0.0,
+
+            selection: None,
+            reveal_selection: false,
+
+            inspection: None,
+            reveal_inspection: false,
+            inspect_filename: String::from(INSPECT_FILE),
         };
 
         // ------------ TESTING CODE (LOAD SCENE ON START) -----------------
@@ -160,10 +243,10 @@ impl Gui {
             }
             Err(e) => println!("{}", e),
         }
-        match gui.engine.eval(&gui.script) {
+        match eval_scene(&gui.engine, &gui.script, gui.time) {
             Ok(scene) => {
                 gui.scene = scene;
-                gui.event = Some(GuiEvent::SceneLoad(gui.scene.clone()));
+                gui.event = Some(GuiEvent::SceneLoad(gui.scene.at_time(gui.time)));
             }
             Err(e) => println!("{e}"),
         }
@@ -176,6 +259,38 @@ impl Gui {
         self.render_elapsed = None;
     }
 
+    pub fn update_render_progress(&mut self, progress: RenderProgress) {
+        self.render_progress = Some(progress);
+    }
+
+    pub fn update_display(&mut self, display: DisplayTransform) {
+        self.raytracing_option.display = display;
+    }
+
+    pub fn raytracing_option(&self) -> &RaytracingOption {
+        &self.raytracing_option
+    }
+
+    /// Select the node picked in the viewport, or clear the selection on a miss
+    pub fn select(&mut self, pick: Option<Pick>) {
+        self.reveal_selection = pick.is_some();
+        self.selection = pick;
+    }
+
+    /// Show the rays recorded through a pixel
+    pub fn inspect(&mut self, x: u32, y: u32, record: Option<RayRecord>) {
+        self.reveal_inspection = true;
+        self.inspection = Some(Inspection {
+            pixel: (x, y),
+            record,
+        });
+    }
+
+    /// If the mouse is over a Dear ImGui window, so clicks are not meant for the viewport
+    pub fn wants_mouse(&self) -> bool {
+        self.imgui.io().want_capture_mouse
+    }
+
     pub fn stop_render_timer(&mut self) {
         if let Some(start) = self.render_start.take() {
             self.render_elapsed = Some(start.elapsed());
@@ -226,11 +341,24 @@ impl Gui {
                 MAX_THREADS,
                 &mut self.raytracing_option.threads,
             );
-            // Numbers of rays to render per pass
-            Drag::new("Rays Per Pass")
-                .range(RAYS_MIN, RAYS_MAX)
-                .speed(50.0)
-                .build(ui, &mut self.raytracing_option.pixels_per_thread);
+            // Size of the tiles each thread renders at a time
+            ui.slider(
+                "Tile Size",
+                MIN_TILE_SIZE,
+                MAX_TILE_SIZE,
+                &mut self.raytracing_option.tile_size,
+            );
+            // Order the tiles are rendered in
+            let mut order = TileOrder::ALL
+                .iter()
+                .position(|order| *order == self.raytracing_option.tile_order)
+                .unwrap_or(0);
+            if ui.combo("Tile Order", &mut order, &TileOrder::ALL, |order| {
+                order.name().into()
+            }) {
+                self.raytracing_option.tile_order = TileOrder::ALL[order];
+            }
+            ui.checkbox("Coarse Preview", &mut self.raytracing_option.coarse_pass);
             // Proportion of the window the buffer occupies
             Drag::new("% Buffer: ")
                 .range(BUFFER_PROPORTION_MIN, BUFFER_PROPORTION_MAX)
@@ -244,10 +372,16 @@ impl Gui {
                 self.raytracing_option.clear_color[2] as f32 / 255.0,
                 self.raytracing_option.clear_color[3] as f32 / 255.0,
             ];
-            if ui.color_edit4_config("Clear Colour", &mut clear_f32).alpha_bar(true).build() {
+            if ui
+                .color_edit4_config("Clear Colour", &mut clear_f32)
+                .alpha_bar(true)
+                .build()
+            {
                 self.raytracing_option.clear_color = [
-                    (clear_f32[0] * 255.0) as u8, (clear_f32[1] * 255.0) as u8,
-                    (clear_f32[2] * 255.0) as u8, (clear_f32[3] * 255.0) as u8,
+                    (clear_f32[0] * 255.0) as u8,
+                    (clear_f32[1] * 255.0) as u8,
+                    (clear_f32[2] * 255.0) as u8,
+                    (clear_f32[3] * 255.0) as u8,
                 ];
             }
             //Clear colour if no intersect
@@ -257,12 +391,54 @@ impl Gui {
                 self.raytracing_option.pixel_clear[2] as f32 / 255.0,
                 self.raytracing_option.pixel_clear[3] as f32 / 255.0,
             ];
-            if ui.color_edit4_config("Pixel Clear Colour", &mut pixel_clear_f32).alpha_bar(true).build() {
+            if ui
+                .color_edit4_config("Pixel Clear Colour", &mut pixel_clear_f32)
+                .alpha_bar(true)
+                .build()
+            {
                 self.raytracing_option.pixel_clear = [
-                    (pixel_clear_f32[0] * 255.0) as u8, (pixel_clear_f32[1] * 255.0) as u8,
-                    (pixel_clear_f32[2] * 255.0) as u8, (pixel_clear_f32[3] * 255.0) as u8,
+                    (pixel_clear_f32[0] * 255.0) as u8,
+                    (pixel_clear_f32[1] * 255.0) as u8,
+                    (pixel_clear_f32[2] * 255.0) as u8,
+                    (pixel_clear_f32[3] * 255.0) as u8,
                 ];
             }
+            //Light transport, whitted for a quick look and path tracing for the final image
+            let mut integrator = IntegratorKind::ALL
+                .iter()
+                .position(|kind| *kind == self.raytracing_option.integrator)
+                .unwrap_or(0);
+            if ui.combo(
+                "Integrator",
+                &mut integrator,
+                &IntegratorKind::ALL,
+                |kind| kind.name().into(),
+            ) {
+                self.raytracing_option.integrator = IntegratorKind::ALL[integrator];
+            }
+            //Path length limits of the path tracer
+            ui.slider(
+                "Max Bounces",
+                MIN_BOUNCES,
+                MAX_BOUNCES,
+                &mut self.raytracing_option.max_bounces,
+            );
+            ui.slider(
+                "Roulette Bounces",
+                0,
+                MAX_ROULETTE_BOUNCES,
+                &mut self.raytracing_option.roulette_bounces,
+            );
+            //Weighting of light samples against bounces reaching the same light
+            let mut mis = MisHeuristic::ALL
+                .iter()
+                .position(|kind| *kind == self.raytracing_option.mis)
+                .unwrap_or(0);
+            if ui.combo("MIS Heuristic", &mut mis, &MisHeuristic::ALL, |kind| {
+                kind.name().into()
+            }) {
+                self.raytracing_option.mis = MisHeuristic::ALL[mis];
+            }
             //Ray depth slider
             ui.slider(
                 "Ray Depth",
@@ -277,12 +453,52 @@ impl Gui {
                 MAX_SAMPLES,
                 &mut self.raytracing_option.ray_samples,
             );
-            //Ray randomness
-            Drag::new("Ray Randomness")
-                .range(MIN_RANDOM, MAX_RANDOM)
-                .speed(5.0)
-                .display_format("%.1f")
-                .build(ui, &mut self.raytracing_option.ray_randomness);
+            //Passes accumulated while the camera is still
+            Drag::new("Target Passes")
+                .range(MIN_PASSES, MAX_PASSES)
+                .speed(1.0)
+                .build(ui, &mut self.raytracing_option.passes);
+            //Sampler spreading the samples over each pixel
+            let mut sampler = SamplerKind::ALL
+                .iter()
+                .position(|kind| *kind == self.raytracing_option.sampler)
+                .unwrap_or(0);
+            if ui.combo("Sampler", &mut sampler, &SamplerKind::ALL, |kind| {
+                kind.name().into()
+            }) {
+                self.raytracing_option.sampler = SamplerKind::ALL[sampler];
+            }
+            //Reconstruction filter, picking one resets the radius to its usual reach
+            let filter = &mut self.raytracing_option.filter;
+            let mut kind = FilterKind::ALL
+                .iter()
+                .position(|kind| *kind == filter.kind)
+                .unwrap_or(0);
+            if ui.combo("Filter", &mut kind, &FilterKind::ALL, |kind| {
+                kind.name().into()
+            }) {
+                *filter = Filter::new(FilterKind::ALL[kind]);
+            }
+            Drag::new("Filter Radius")
+                .range(MIN_FILTER_RADIUS, MAX_FILTER_RADIUS)
+                .speed(0.05)
+                .display_format("%.2f")
+                .build(ui, &mut filter.radius);
+            //Adaptive sampling, the samples AOV shows where the samples went
+            let adaptive = &mut self.raytracing_option.adaptive;
+            ui.checkbox("Adaptive Sampling", &mut adaptive.enabled);
+            Drag::new("Noise Threshold")
+                .range(MIN_NOISE_THRESHOLD, MAX_NOISE_THRESHOLD)
+                .speed(0.001)
+                .display_format("%.3f")
+                .build(ui, &mut adaptive.threshold);
+            Drag::new("Min Samples")
+                .range(MIN_ADAPTIVE_SAMPLES, MAX_ADAPTIVE_SAMPLES)
+                .speed(1.0)
+                .build(ui, &mut adaptive.min_samples);
+            //Random seed
+            ui.input_scalar("Seed", &mut self.raytracing_option.seed)
+                .build();
             //Number of diffuse rays
             ui.slider(
                 "Diffuse Rays",
@@ -296,17 +512,21 @@ impl Gui {
                 .speed(0.005)
                 .display_format("%.3f")
                 .build(ui, &mut self.raytracing_option.diffuse_coefficient);
-            // Fov of the buffer
-            ui.slider(
-                "fov",
-                MIN_FOV,
-                MAX_FOV,
-                &mut self.raytracing_option.buffer_fov,
-            );
             // Enable BVH
             ui.checkbox("Enable BVH", &mut self.raytracing_option.bvh_active);
             ui.checkbox("Enable Shadows", &mut self.raytracing_option.shadows);
             ui.checkbox("Enable Reflections", &mut self.raytracing_option.reflect);
+            ui.checkbox("Enable Refractions", &mut self.raytracing_option.refract);
+            //Reflection against refraction at transparent materials
+            let mut fresnel = FresnelKind::ALL
+                .iter()
+                .position(|kind| *kind == self.raytracing_option.fresnel)
+                .unwrap_or(0);
+            if ui.combo("Fresnel", &mut fresnel, &FresnelKind::ALL, |kind| {
+                kind.name().into()
+            }) {
+                self.raytracing_option.fresnel = FresnelKind::ALL[fresnel];
+            }
             ui.checkbox("Enable Specular", &mut self.raytracing_option.specular);
             ui.checkbox("Enable Diffuse", &mut self.raytracing_option.diffuse);
             // Render timer display
@@ -317,31 +537,152 @@ impl Gui {
             } else if let Some(elapsed) = &self.render_elapsed {
                 ui.text(format!("Render time: {:.2}s", elapsed.as_secs_f64()));
             }
+            if let Some(progress) = &self.render_progress {
+                ui.text(format!(
+                    "Pass: {} / {}",
+                    progress.passes_done, progress.passes_total
+                ));
+            }
             ui.separator();
             // Apply stored changes
             if ui.button("Apply") {
                 self.event = Some(GuiEvent::RaytracerOption(self.raytracing_option.clone()));
             };
         }
+        // DISPLAY OPTIONS ----------------------------------------
+        if CollapsingHeader::new("Display").build(ui) {
+            let display = &mut self.raytracing_option.display;
+            let mut changed = false;
+            // Exposure in stops
+            changed |= Drag::new("Exposure (EV)")
+                .range(MIN_EXPOSURE, MAX_EXPOSURE)
+                .speed(0.05)
+                .display_format("%.2f")
+                .build(ui, &mut display.exposure);
+            // Tone mapping operator
+            let mut mapper = ToneMapper::ALL
+                .iter()
+                .position(|mapper| *mapper == display.tone_mapper)
+                .unwrap_or(0);
+            if ui.combo("Tone Mapper", &mut mapper, &ToneMapper::ALL, |mapper| {
+                mapper.name().into()
+            }) {
+                display.tone_mapper = ToneMapper::ALL[mapper];
+                changed = true;
+            }
+            changed |= ui.checkbox("sRGB Output", &mut display.srgb);
+            // Applied straight away, nothing has to be traced again
+            if changed {
+                self.event = Some(GuiEvent::DisplayUpdate(*display));
+            }
+            // Which AOV is shown in the viewport
+            let mut view = Aov::ALL
+                .iter()
+                .position(|aov| *aov == self.aov_view)
+                .unwrap_or(0);
+            if ui.combo("AOV", &mut view, &Aov::ALL, |aov| aov.name().into()) {
+                self.aov_view = Aov::ALL[view];
+                self.event = Some(GuiEvent::AovView(self.aov_view));
+            }
+        }
         // CAMERA OPTIONS ----------------------------------------
         if CollapsingHeader::new("Camera").build(ui) {
             // Eye, target and up vector inputs
             ui.text("Camera options:");
+            let (mut eye, mut target) = (self.camera.eye, self.camera.target);
             Drag::new("Eye")
                 .range(MIN_TRANSLATE, MAX_TRANSLATE)
                 .speed(0.05)
                 .display_format("%.2f")
-                .build_array(ui, self.camera.eye.coords.as_mut_slice());
+                .build_array(ui, eye.coords.as_mut_slice());
             Drag::new("Target")
                 .range(MIN_TRANSLATE, MAX_TRANSLATE)
                 .speed(0.05)
                 .display_format("%.2f")
-                .build_array(ui, self.camera.target.coords.as_mut_slice());
+                .build_array(ui, target.coords.as_mut_slice());
+            // Through look_at, so the plane of focus follows the target
+            if (eye, target) != (self.camera.eye, self.camera.target) && eye != target {
+                self.camera.look_at(eye, target);
+            }
             Drag::new("Up")
                 .range(0.0, 1.0)
                 .speed(0.005)
                 .display_format("%.3f")
                 .build_array(ui, self.camera.up.as_mut_slice());
+            // Intrinsics, kept with the camera so switching back restores the framing
+            ui.slider("fov", MIN_FOV, MAX_FOV, &mut self.camera.fov);
+            // A sensor aspect of 0 follows the image
+            Drag::new("Sensor Aspect")
+                .range(0.0, MAX_ASPECT)
+                .speed(0.01)
+                .display_format("%.2f")
+                .build(ui, &mut self.camera.aspect);
+            Drag::new("Lens Shift")
+                .range(MIN_SHIFT, MAX_SHIFT)
+                .speed(0.005)
+                .display_format("%.3f")
+                .build_array(ui, self.camera.shift.as_mut_slice());
+            Drag::new("Near Clip")
+                .range(0.0, MAX_NEAR)
+                .speed(0.01)
+                .display_format("%.2f")
+                .build(ui, &mut self.camera.near);
+            // Projection, the view width and fisheye fov only apply to their own projection
+            let mut projection = Projection::ALL
+                .iter()
+                .position(|projection| *projection == self.camera.projection)
+                .unwrap_or(0);
+            if ui.combo(
+                "Projection",
+                &mut projection,
+                &Projection::ALL,
+                |projection| projection.name().into(),
+            ) {
+                self.camera.projection = Projection::ALL[projection];
+            }
+            Drag::new("View Width")
+                .range(MIN_VIEW_WIDTH, MAX_VIEW_WIDTH)
+                .speed(0.02)
+                .display_format("%.2f")
+                .build(ui, &mut self.camera.view_width);
+            ui.slider(
+                "Fisheye FOV",
+                MIN_FOV,
+                MAX_FISHEYE_FOV,
+                &mut self.camera.fisheye_fov,
+            );
+            // Thin lens, an aperture of 0 is a pinhole. Fisheye and panorama stay sharp
+            Drag::new("Aperture")
+                .range(MIN_APERTURE, MAX_APERTURE)
+                .speed(0.002)
+                .display_format("%.3f")
+                .build(ui, &mut self.camera.aperture);
+            Drag::new("Focus Distance")
+                .range(MIN_FOCUS, MAX_FOCUS)
+                .speed(0.02)
+                .display_format("%.2f")
+                .build(ui, &mut self.camera.focus_distance);
+            if ui.button("Focus on Target") {
+                self.camera.focus_distance = (self.camera.target - self.camera.eye).norm();
+            }
+            // Below 3 blades the aperture is round
+            ui.slider("Blades", 0, MAX_BLADES, &mut self.camera.blades);
+            Drag::new("Blade Rotation")
+                .range(MIN_ROTATION, MAX_ROTATION)
+                .speed(0.5)
+                .display_format("%.1f")
+                .build(ui, &mut self.camera.blade_rotation);
+            // Seconds around the frame time, moving nodes blur while the shutter is open
+            Drag::new("Shutter Open")
+                .range(MIN_SHUTTER, MAX_SHUTTER)
+                .speed(0.001)
+                .display_format("%.3f")
+                .build(ui, &mut self.camera.shutter_open);
+            Drag::new("Shutter Close")
+                .range(MIN_SHUTTER, MAX_SHUTTER)
+                .speed(0.001)
+                .display_format("%.3f")
+                .build(ui, &mut self.camera.shutter_close);
             if ui.button("Apply Camera") {
                 println!("Camera changed");
                 self.event = Some(GuiEvent::CameraUpdate(self.camera.clone()));
@@ -363,10 +704,10 @@ impl Gui {
             ui.same_line();
             // Load scene from multiline script using engine
             if ui.button("Load scene") {
-                match self.engine.eval(&self.script) {
+                match eval_scene(&self.engine, &self.script, self.time) {
                     Ok(scene) => {
                         self.scene = scene;
-                        self.event = Some(GuiEvent::SceneLoad(self.scene.clone()));
+                        self.event = Some(GuiEvent::SceneLoad(self.scene.at_time(self.time)));
                     }
                     Err(e) => println!("{e}"),
                 }
@@ -392,19 +733,104 @@ impl Gui {
             if ui.button("Save Image") {
                 self.event = Some(GuiEvent::SaveImage(self.image_filename.clone()));
             }
+            // Save the linear framebuffer next to the image, keeping values above 1.0
+            for extension in ["exr", "hdr", "pfm"] {
+                ui.same_line();
+                if ui.button(format!("Save {}", extension.to_uppercase())) {
+                    let path = std::path::Path::new(&self.image_filename).with_extension(extension);
+                    self.event = Some(GuiEvent::SaveImage(path.to_string_lossy().to_string()));
+                }
+            }
+            // Every AOV as its own image, or all of them as layers of one EXR
+            if ui.button("Save AOVs") {
+                self.event = Some(GuiEvent::SaveAovs(self.image_filename.clone()));
+            }
+            ui.same_line();
+            if ui.button("Save EXR Layers") {
+                let path = std::path::Path::new(&self.image_filename).with_extension("exr");
+                self.event = Some(GuiEvent::SaveLayers(path.to_string_lossy().to_string()));
+            }
+        }
+        // RAY INSPECTOR --------------------------------------------
+        if std::mem::take(&mut self.reveal_inspection) {
+            // Open the header so the new recording can be seen
+            unsafe { sys::igSetNextItemOpen(true, Condition::Always as sys::ImGuiCond) };
+        }
+        if CollapsingHeader::new("Ray Inspector").build(ui) {
+            match &self.inspection {
+                Some(inspection) => {
+                    let (x, y) = inspection.pixel;
+                    ui.text(format!("Pixel: {x} {y}"));
+                    match &inspection.record {
+                        Some(record) => {
+                            ui.text(format!("Rays: {}", record.count()));
+                            // Export the whole tree
+                            ui.input_text("JSON file", &mut self.inspect_filename)
+                                .build();
+                            if ui.button("Export JSON") {
+                                match std::fs::write(&self.inspect_filename, record.to_json()) {
+                                    Ok(_) => println!("Saved {}", self.inspect_filename),
+                                    Err(e) => println!("{}", e),
+                                }
+                            }
+                            ray_tree(ui, record, &mut 0);
+                        }
+                        None => ui.text("No ray leaves the camera through this pixel"),
+                    }
+                }
+                None => ui.text("Shift-click the viewport to record the rays through a pixel"),
+            }
         }
         // SCENE --------------------------------------------
+        let reveal = std::mem::take(&mut self.reveal_selection);
+        if reveal {
+            // Open the header so the picked node can be shown
+            unsafe { sys::igSetNextItemOpen(true, Condition::Always as sys::ImGuiCond) };
+        }
         if CollapsingHeader::new("Scene").build(ui) {
             if ui.button("Update Scene") {
                 self.scene.compute();
-                self.event = Some(GuiEvent::SceneLoad(self.scene.clone()));
+                self.event = Some(GuiEvent::SceneLoad(self.scene.at_time(self.time)));
+            }
+            // Scrub through keyframes, `time` in the script is set on load
+            if Drag::new("Time")
+                .range(MIN_TIME, MAX_TIME)
+                .speed(0.01)
+                .display_format("%.2f s")
+                .build(ui, &mut self.time)
+            {
+                self.event = Some(GuiEvent::SceneLoad(self.scene.at_time(self.time)));
+            }
+            // Node picked by left clicking the viewport
+            match &self.selection {
+                Some(pick) => {
+                    let (p, n) = (pick.position, pick.normal);
+                    ui.text(format!("Selected: {}", pick.label));
+                    ui.text(format!("Distance: {:.3}", pick.distance));
+                    ui.text(format!("Position: {:.3} {:.3} {:.3}", p.x, p.y, p.z));
+                    ui.text(format!("Normal: {:.3} {:.3} {:.3}", n.x, n.y, n.z));
+                }
+                None => ui.text("Left click the viewport to select a node"),
             }
             // Edit transformation of nodes
-            if let Some(_t) = ui.tree_node("Nodes") {
+            let mut nodes = ui.tree_node_config("Nodes");
+            if reveal {
+                nodes = nodes.opened(true, Condition::Always);
+            }
+            if let Some(_t) = nodes.push() {
                 for (label, node) in &mut self.scene.nodes {
+                    let selected = self
+                        .selection
+                        .as_ref()
+                        .is_some_and(|pick| pick.label == *label);
                     ui.checkbox(format!("##active{label}"), &mut node.active);
                     ui.same_line();
-                    if let Some(_t) = ui.tree_node(label) {
+                    let mut tree_node = ui.tree_node_config(label).selected(selected);
+                    if reveal && selected {
+                        tree_node = tree_node.opened(true, Condition::Always);
+                        ui.set_scroll_here_y();
+                    }
+                    if let Some(_t) = tree_node.push() {
                         Drag::new("Translation")
                             .range(MIN_TRANSLATE, MAX_TRANSLATE)
                             .speed(0.05)
@@ -420,6 +846,16 @@ impl Gui {
                             .speed(0.01)
                             .display_format("%.3f")
                             .build_array(ui, &mut node.scale);
+                        // Nodes with an emission are area lights
+                        let mut emission_arr: [f32; 3] = node.emission.into();
+                        if ui.color_edit3("Emission", &mut emission_arr) {
+                            node.emission = Vector3::from(emission_arr);
+                        }
+                        Drag::new("Strength")
+                            .range(0.0, MAX_STRENGTH)
+                            .speed(0.05)
+                            .display_format("%.2f")
+                            .build(ui, &mut node.strength);
                     }
                 }
             }
@@ -440,10 +876,46 @@ impl Gui {
                             .speed(0.5)
                             .display_format("%.1f")
                             .build(ui, &mut material.shininess);
+                        // Transparency, opaque while kt is black
+                        let mut kt_arr: [f32; 3] = material.kt.into();
+                        if ui.color_edit3("kt", &mut kt_arr) {
+                            material.kt = Vector3::from(kt_arr);
+                        }
+                        Drag::new("IOR")
+                            .range(MIN_IOR, MAX_IOR)
+                            .speed(0.005)
+                            .display_format("%.3f")
+                            .build(ui, &mut material.ior);
+                        Drag::new("Absorption")
+                            .range(0.0, MAX_ABSORPTION)
+                            .speed(0.01)
+                            .display_format("%.2f")
+                            .build_array(ui, material.absorption.as_mut_slice());
+                        // Parameters of the BSDF the path tracer scatters with
+                        ui.text(format!("Model: {}", material.model.name()));
+                        match &mut material.model {
+                            BsdfModel::Phong | BsdfModel::Lambertian => {}
+                            BsdfModel::OrenNayar { sigma } => {
+                                Drag::new("Sigma")
+                                    .range(0.0, MAX_SIGMA)
+                                    .speed(0.5)
+                                    .display_format("%.1f")
+                                    .build(ui, sigma);
+                            }
+                            BsdfModel::Conductor { roughness, .. }
+                            | BsdfModel::Dielectric { roughness }
+                            | BsdfModel::Plastic { roughness } => {
+                                Drag::new("Roughness")
+                                    .range(0.0, 1.0)
+                                    .speed(0.005)
+                                    .display_format("%.3f")
+                                    .build(ui, roughness);
+                            }
+                        }
                     }
                 }
             }
-            //Edit color, position and falloff of lights
+            //Edit color, position, falloff and radius of lights
             if let Some(_t) = ui.tree_node("Lights") {
                 for (label, light) in &mut self.scene.lights {
                     ui.checkbox(format!("##activelight{label}"), &mut light.active);
@@ -463,6 +935,12 @@ impl Gui {
                             .speed(0.005)
                             .display_format("%.3f")
                             .build_array(ui, light.falloff.as_mut_slice());
+                        // Lights with a radius are spheres the path tracer samples for soft shadows
+                        Drag::new("Radius")
+                            .range(0.0, MAX_LIGHT_RADIUS)
+                            .speed(0.005)
+                            .display_format("%.3f")
+                            .build(ui, &mut light.radius);
                     }
                 }
             }
@@ -470,8 +948,10 @@ impl Gui {
             if let Some(_t) = ui.tree_node("Cameras") {
                 for (label, camera) in &self.scene.cameras {
                     if ui.button(label) {
+                        let mut camera = camera.clone();
+                        camera.pose(self.time);
                         self.camera = camera.clone();
-                        self.event = Some(GuiEvent::CameraUpdate(camera.clone()));
+                        self.event = Some(GuiEvent::CameraUpdate(camera));
                     }
                 }
             }
@@ -515,101 +995,67 @@ impl Gui {
     }
 }
 
-pub fn init_engine() -> Engine {
-    let mut engine = Engine::new();
-
-    engine
-        .register_type::<Vector3<f64>>()
-        .register_fn("V", Vector3::<f64>::new);
-    engine
-        .register_type::<Point3<f64>>()
-        .register_fn("P", Point3::<f64>::new);
-    engine
-        .register_type::<Camera>()
-        .register_fn("Camera", Camera::new);
-    engine
-        .register_type::<Scene>()
-        .register_fn("Scene", Scene::empty)
-        .register_fn("addNode", Scene::add_node)
-        .register_fn("addLight", Scene::add_light)
-        .register_fn("addCamera", Scene::add_camera)
-        .register_fn("addMaterial", Scene::add_material);
-
-    engine
-        .register_type::<Node>()
-        .register_fn("Node", Node::new)
-        .register_fn("translate", Node::translate)
-        .register_fn("rotate", Node::rotate)
-        .register_fn("scale", Node::scale)
-        .register_fn("child", Node::child)
-        .register_fn("active", Node::set_active);
-    engine
-        .register_type::<Light>()
-        .register_fn("Light", Light::new)
-        .register_fn("Ambient", Light::ambient)
-        .register_fn("active", Light::set_active);
-    engine
-        .register_type::<Material>()
-        .register_fn("Material", Material::new)
-        .register_fn("MaterialRed", Material::red)
-        .register_fn("MaterialBlue", Material::blue)
-        .register_fn("MaterialGreen", Material::green)
-        .register_fn("MaterialMagenta", Material::magenta)
-        .register_fn("MaterialTurquoise", Material::turquoise);
-    engine
-        .register_type::<Sphere>()
-        .register_fn("Sphere", Sphere::new)
-        .register_fn("SphereUnit", Sphere::unit);
-    engine
-        .register_type::<Cube>()
-        .register_fn("Cube", Cube::new)
-        .register_fn("CubeUnit", Cube::unit);
-    engine
-        .register_type::<Triangle>()
-        .register_fn("Triangle", Triangle::new)
-        .register_fn("TriangleUnit", Triangle::unit);
-    engine
-        .register_type::<Cone>()
-        .register_fn("Cone", Cone::new)
-        .register_fn("ConeUnit", Cone::unit);
-    engine
-        .register_type::<Cylinder>()
-        .register_fn("Cylinder", Cylinder::new);
-    engine
-        .register_type::<Circle>()
-        .register_fn("Circle", Circle::new)
-        .register_fn("CircleUnit", Circle::unit);
-    engine
-        .register_type::<Cube>()
-        .register_fn("Cube", Cube::new)
-        .register_fn("CubeUnit", Cube::unit);
-    engine
-        .register_type::<Steiner>()
-        .register_fn("Steiner", Steiner::new);
-    engine
-        .register_type::<Steiner2>()
-        .register_fn("Steiner2", Steiner2::new);
-    engine
-        .register_type::<Roman>()
-        .register_fn("Roman", Roman::new);
-    engine
-        .register_type::<CrossCap>()
-        .register_fn("CrossCap", CrossCap::new);
-    engine
-        .register_type::<CrossCap2>()
-        .register_fn("CrossCap2", CrossCap2::new);
-    engine
-        .register_type::<Torus>()
-        .register_fn("Torus", Torus::new);
-    engine
-        .register_type::<Gnonom>()
-        .register_fn("Gnonom", Gnonom::new);
-    engine
-        .register_type::<Mesh>()
-        .register_fn("Mesh", Mesh::from_file);
-    engine
-        .register_type::<RectangleXY>()
-        .register_fn("Rectange", RectangleXY::new)
-        .register_fn("RectangleUnit", RectangleXY::unit);
-    engine
+// Collapsible tree of a recorded ray and the rays traced from its hit, `id` keeps the
+// labels of nodes with the same text apart
+fn ray_tree(ui: &Ui, record: &RayRecord, id: &mut usize) {
+    *id += 1;
+    let (o, d) = (record.origin, record.direction);
+    let target = match (&record.hit, &record.emitter) {
+        (Some(hit), _) => hit.node.as_str(),
+        (None, Some(emitter)) => emitter.as_str(),
+        (None, None) => "miss",
+    };
+    let label = format!(
+        "{} ray, depth {}: {}##ray{}",
+        record.kind.name(),
+        record.depth,
+        target,
+        id
+    );
+    let Some(_t) = ui
+        .tree_node_config(label)
+        .default_open(record.depth == 0)
+        .push()
+    else {
+        return;
+    };
+    ui.text(format!("Origin: {:.3} {:.3} {:.3}", o.x, o.y, o.z));
+    ui.text(format!("Direction: {:.3} {:.3} {:.3}", d.x, d.y, d.z));
+    match &record.colour {
+        Some(c) => ui.text(format!("Colour: {:.3} {:.3} {:.3}", c.x, c.y, c.z)),
+        None => ui.text("Colour: none"),
+    }
+    let Some(hit) = &record.hit else {
+        return;
+    };
+    let (p, n) = (hit.position, hit.normal);
+    ui.text(format!("Hit: {} at distance {:.3}", hit.node, hit.distance));
+    ui.text(format!("Position: {:.3} {:.3} {:.3}", p.x, p.y, p.z));
+    ui.text(format!("Normal: {:.3} {:.3} {:.3}", n.x, n.y, n.z));
+    let (r, t, i) = (hit.reflection, hit.refraction, hit.indirect);
+    ui.text(format!("Reflection: {:.3} {:.3} {:.3}", r.x, r.y, r.z));
+    ui.text(format!("Refraction: {:.3} {:.3} {:.3}", t.x, t.y, t.z));
+    ui.text(format!("Indirect: {:.3} {:.3} {:.3}", i.x, i.y, i.z));
+    if let Some(_t) = ui.tree_node_config(format!("Lights##lights{id}")).push() {
+        for light in &hit.lights {
+            let c = light.contribution;
+            let state = match (&light.shadow, light.ambient) {
+                (_, true) => String::from("ambient"),
+                (Some(shadow), _) => match &shadow.occluder {
+                    Some((occluder, distance)) => {
+                        format!("blocked by {occluder} at {distance:.3}")
+                    }
+                    None => format!("visible at {:.3}", shadow.light_distance),
+                },
+                (None, false) => String::from("no shadow ray"),
+            };
+            ui.text(format!(
+                "{}: {:.3} {:.3} {:.3}, {}",
+                light.label, c.x, c.y, c.z, state
+            ));
+        }
+    }
+    for ray in &hit.rays {
+        ray_tree(ui, ray, id);
+    }
 }
@@ -0,0 +1,495 @@
+use crate::state::SAVE_FILE;
+use rust_opengl::aov::Aov;
+use rust_opengl::bsdf::FresnelKind;
+use rust_opengl::filter::{Filter, FilterKind};
+use rust_opengl::integrator::{IntegratorKind, MisHeuristic};
+use rust_opengl::output::OutputFormat;
+use rust_opengl::sampler::SamplerKind;
+use rust_opengl::script::{eval_scene, init_engine};
+use rust_opengl::tile::TileOrder;
+use rust_opengl::tonemap::{DisplayTransform, ToneMapper};
+use rust_opengl::{Camera, RaytracingOption, Renderer, Scene};
+
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+const DEFAULT_WIDTH: u32 = 800;
+const DEFAULT_HEIGHT: u32 = 600;
+const DEFAULT_FPS: f64 = 24.0;
+
+pub const USAGE: &str = "\
+Usage: rust-opengl render <script.rhai> [options]
+
+Render a scene script to an image without opening a window.
+
+When more than one camera is rendered, each image is named after its camera,
+so `-o out/img.png` with cameras \"+X Cam\" and \"-X Cam\" writes
+out/img_+X_Cam.png and out/img_-X_Cam.png.
+
+Scripts can read the constant `time`, and keyframes set with `keyframe(t)` are
+evaluated at it. `--frames 0:47 --fps 24` renders an image sequence with the
+frame number appended to each name, e.g. img_0000.png to img_0047.png.
+
+Options:
+  -o, --output <file>           Image file to write (default: img.png)
+  -c, --camera <label>          Camera from the scene to render from, repeat to
+                                render several (default: first label in
+                                alphabetical order)
+      --all-cameras             Render every camera in the scene
+      --list-cameras            Print the cameras defined by the script and exit
+      --aov <name>              Also save an AOV next to the image, repeat for
+                                several: normal, depth, position, albedo, id,
+                                shadow, samples or all
+      --exr-layers              Write every AOV as layers of the .exr output
+      --time <seconds>          Scene time to render a still at (default: 0)
+      --frames <first>:<last>   Render every frame in the range, inclusive
+      --fps <n>                 Frames per second of the sequence (default: 24)
+      --width <pixels>          Image width (default: 800)
+      --height <pixels>         Image height (default: 600)
+      --fov <degrees>           Vertical field of view, overriding the camera's
+      --integrator <kind>       Light transport: whitted for a fast preview or
+                                path for unbiased path tracing (default: whitted)
+      --depth <n>               Maximum ray depth of the whitted integrator
+      --max-bounces <n>         Longest path the path tracer follows (default: 32)
+      --roulette-bounces <n>    Bounces before Russian roulette may end a path
+                                (default: 3)
+      --mis <heuristic>         How the path tracer weights light samples against
+                                bounces: balance or power (default: power)
+      --samples <n>             Rays per pixel in each pass
+      --passes <n>              Passes of samples accumulated into the image
+      --sampler <kind>          How samples spread over each pixel: independent,
+                                stratified, halton or sobol (default: sobol)
+      --filter <kind>           Pixel reconstruction filter: box, tent,
+                                gaussian, mitchell or lanczos (default: box)
+      --filter-radius <pixels>  Reach of the filter (default: depends on kind)
+      --adaptive                Stop sampling pixels once their noise is below
+                                the threshold, spending the passes elsewhere
+      --noise-threshold <f>     Relative 95% confidence interval a pixel
+                                converges at (default: 0.05)
+      --min-samples <n>         Samples before a pixel may converge (default: 8)
+      --seed <n>                Seed for the random numbers, renders with the
+                                same seed match exactly (default: 0)
+      --threads <n>             Worker threads
+      --tile-size <n>           Side of the square tiles each worker traces
+      --tile-order <order>      Order tiles are traced in: scanline, spiral or
+                                hilbert
+      --diffuse-rays <n>        Indirect diffuse rays per hit
+      --diffuse-coefficient <f> Weight of indirect diffuse light
+      --exposure <ev>           Exposure in stops (default: 0)
+      --tonemap <operator>      Tone mapper: clamp, reinhard, aces or hable
+                                (default: clamp)
+      --srgb / --no-srgb        Encode the image as sRGB (default: on)
+      --bvh / --no-bvh          Toggle the bounding volume hierarchy
+      --shadows / --no-shadows
+      --diffuse / --no-diffuse
+      --reflect / --no-reflect
+      --refract / --no-refract
+      --fresnel <kind>          How transparent materials split reflection and
+                                refraction: exact or schlick (default: exact)
+      --specular / --no-specular
+      --falloff / --no-falloff
+      --help                    Print this message
+";
+
+/// Settings for a single headless render, parsed from the command line
+pub struct HeadlessOptions {
+    pub script: String,
+    pub output: String,
+    pub cameras: Vec<String>,
+    pub all_cameras: bool,
+    pub list_cameras: bool,
+    pub aovs: Vec<Aov>,
+    pub exr_layers: bool,
+    pub time: f64,
+    pub frames: Option<(u32, u32)>,
+    pub fps: f64,
+    pub width: u32,
+    pub height: u32,
+    // Field of view given on the command line wins over the one of every camera
+    pub fov: Option<f64>,
+    pub raytracing: RaytracingOption,
+    // Display settings given on the command line win over any the script sets
+    pub exposure: Option<f32>,
+    pub tone_mapper: Option<ToneMapper>,
+    pub srgb: Option<bool>,
+}
+
+impl HeadlessOptions {
+    /// Parse the arguments that follow the `render` subcommand
+    pub fn parse(args: &[String]) -> Result<HeadlessOptions, Box<dyn Error>> {
+        let mut script = None;
+        let mut output = String::from(SAVE_FILE);
+        let mut cameras = Vec::new();
+        let mut all_cameras = false;
+        let mut list_cameras = false;
+        // Given radius wins over the default of a filter named after it
+        let mut filter_radius = None;
+        let mut aovs = Vec::new();
+        let mut exr_layers = false;
+        let mut time = 0.0;
+        let mut frames = None;
+        let mut fps = DEFAULT_FPS;
+        let mut width = DEFAULT_WIDTH;
+        let mut height = DEFAULT_HEIGHT;
+        let mut fov = None;
+        let mut exposure = None;
+        let mut tone_mapper = None;
+        let mut srgb = None;
+        let mut raytracing = RaytracingOption::default();
+        // Nobody sees the preview without a window
+        raytracing.coarse_pass = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "-o" | "--output" => output = value()?.clone(),
+                "-c" | "--camera" => cameras.push(value()?.clone()),
+                "--all-cameras" => all_cameras = true,
+                "--list-cameras" => list_cameras = true,
+                "--aov" => match value()?.as_str() {
+                    "all" => aovs.extend(Aov::ALL.iter().filter(|aov| **aov != Aov::Beauty)),
+                    name => aovs.push(Aov::from_name(name).ok_or_else(|| {
+                        format!("unknown AOV '{name}', expected normal, depth, position, albedo, id, shadow, samples or all")
+                    })?),
+                },
+                "--exr-layers" => exr_layers = true,
+                "--time" => time = parse_value(arg, value()?)?,
+                "--frames" => frames = Some(parse_frames(arg, value()?)?),
+                "--fps" => fps = parse_value(arg, value()?)?,
+                "--width" => width = parse_value(arg, value()?)?,
+                "--height" => height = parse_value(arg, value()?)?,
+                "--fov" => fov = Some(parse_value(arg, value()?)?),
+                "--integrator" => {
+                    let name = value()?;
+                    raytracing.integrator = IntegratorKind::from_name(name)
+                        .ok_or_else(|| format!("unknown integrator '{name}', expected whitted or path"))?
+                }
+                "--depth" => raytracing.ray_depth = parse_value(arg, value()?)?,
+                "--max-bounces" => raytracing.max_bounces = parse_value(arg, value()?)?,
+                "--roulette-bounces" => {
+                    raytracing.roulette_bounces = parse_value(arg, value()?)?
+                }
+                "--mis" => {
+                    let name = value()?;
+                    raytracing.mis = MisHeuristic::from_name(name).ok_or_else(|| {
+                        format!("unknown MIS heuristic '{name}', expected balance or power")
+                    })?
+                }
+                "--samples" => raytracing.ray_samples = parse_value(arg, value()?)?,
+                "--passes" => raytracing.passes = parse_value(arg, value()?)?,
+                "--sampler" => {
+                    let name = value()?;
+                    raytracing.sampler = SamplerKind::from_name(name).ok_or_else(|| {
+                        format!("unknown sampler '{name}', expected independent, stratified, halton or sobol")
+                    })?
+                }
+                "--filter" => {
+                    let name = value()?;
+                    let kind = FilterKind::from_name(name).ok_or_else(|| {
+                        format!("unknown filter '{name}', expected box, tent, gaussian, mitchell or lanczos")
+                    })?;
+                    raytracing.filter = Filter {
+                        kind,
+                        radius: filter_radius.unwrap_or(kind.default_radius()),
+                    };
+                }
+                "--filter-radius" => {
+                    let radius = parse_value(arg, value()?)?;
+                    filter_radius = Some(radius);
+                    raytracing.filter.radius = radius;
+                }
+                "--adaptive" => raytracing.adaptive.enabled = true,
+                "--noise-threshold" => {
+                    raytracing.adaptive.threshold = parse_value(arg, value()?)?
+                }
+                "--min-samples" => raytracing.adaptive.min_samples = parse_value(arg, value()?)?,
+                "--seed" => raytracing.seed = parse_value(arg, value()?)?,
+                "--threads" => raytracing.threads = parse_value(arg, value()?)?,
+                "--tile-size" => raytracing.tile_size = parse_value(arg, value()?)?,
+                "--tile-order" => {
+                    let name = value()?;
+                    raytracing.tile_order = TileOrder::from_name(name).ok_or_else(|| {
+                        format!("unknown tile order '{name}', expected scanline, spiral or hilbert")
+                    })?
+                }
+                "--diffuse-rays" => raytracing.diffuse_rays = parse_value(arg, value()?)?,
+                "--diffuse-coefficient" => {
+                    raytracing.diffuse_coefficient = parse_value(arg, value()?)?
+                }
+                "--exposure" => exposure = Some(parse_value(arg, value()?)?),
+                "--tonemap" => {
+                    let name = value()?;
+                    tone_mapper = Some(ToneMapper::from_name(name).ok_or_else(|| {
+                        format!(
+                            "unknown tone mapper '{name}', expected clamp, reinhard, aces or hable"
+                        )
+                    })?)
+                }
+                "--srgb" => srgb = Some(true),
+                "--no-srgb" => srgb = Some(false),
+                "--bvh" => raytracing.bvh_active = true,
+                "--no-bvh" => raytracing.bvh_active = false,
+                "--shadows" => raytracing.shadows = true,
+                "--no-shadows" => raytracing.shadows = false,
+                "--diffuse" => raytracing.diffuse = true,
+                "--no-diffuse" => raytracing.diffuse = false,
+                "--reflect" => raytracing.reflect = true,
+                "--no-reflect" => raytracing.reflect = false,
+                "--refract" => raytracing.refract = true,
+                "--no-refract" => raytracing.refract = false,
+                "--fresnel" => {
+                    let name = value()?;
+                    raytracing.fresnel = FresnelKind::from_name(name).ok_or_else(|| {
+                        format!("unknown Fresnel kind '{name}', expected exact or schlick")
+                    })?
+                }
+                "--specular" => raytracing.specular = true,
+                "--no-specular" => raytracing.specular = false,
+                "--falloff" => raytracing.falloff = true,
+                "--no-falloff" => raytracing.falloff = false,
+                flag if flag.starts_with('-') => {
+                    return Err(format!("unknown option {flag}").into())
+                }
+                path => match script {
+                    None => script = Some(path.to_string()),
+                    Some(_) => return Err(format!("unexpected argument {path}").into()),
+                },
+            }
+        }
+
+        let script = script.ok_or("no scene script given")?;
+        if exr_layers && OutputFormat::from_path(&output) != OutputFormat::Exr {
+            return Err("--exr-layers needs an .exr output".into());
+        }
+        if width == 0 || height == 0 {
+            return Err("image width and height must be greater than zero".into());
+        }
+        if fps <= 0.0 {
+            return Err("frames per second must be greater than zero".into());
+        }
+        if raytracing.threads == 0
+            || raytracing.ray_samples == 0
+            || raytracing.passes == 0
+            || raytracing.ray_depth == 0
+            || raytracing.max_bounces == 0
+            || raytracing.tile_size == 0
+        {
+            return Err(
+                "threads, samples, passes, depth, bounces and tile size must be greater than zero"
+                    .into(),
+            );
+        }
+        Ok(HeadlessOptions {
+            script,
+            output,
+            cameras,
+            all_cameras,
+            list_cameras,
+            aovs,
+            exr_layers,
+            time,
+            frames,
+            fps,
+            width,
+            height,
+            fov,
+            raytracing,
+            exposure,
+            tone_mapper,
+            srgb,
+        })
+    }
+
+    /// Display transform for a scene, the script's own if it sets one, then the command line flags
+    pub fn display(&self, scene: &Scene) -> DisplayTransform {
+        let mut display = scene.display.unwrap_or(self.raytracing.display);
+        if let Some(exposure) = self.exposure {
+            display.exposure = exposure;
+        }
+        if let Some(tone_mapper) = self.tone_mapper {
+            display.tone_mapper = tone_mapper;
+        }
+        if let Some(srgb) = self.srgb {
+            display.srgb = srgb;
+        }
+        display
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, Box<dyn Error>> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{value}' for {flag}").into())
+}
+
+// Parse an inclusive frame range such as 0:47
+fn parse_frames(flag: &str, value: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    let (first, last) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected <first>:<last> for {flag}, got '{value}'"))?;
+    let (first, last) = (parse_value(flag, first)?, parse_value(flag, last)?);
+    if last < first {
+        return Err(format!("frame range {value} for {flag} is empty").into());
+    }
+    Ok((first, last))
+}
+
+/// Entry point of the `render` subcommand
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    if args.iter().any(|arg| arg == "--help") {
+        print!("{USAGE}");
+        return Ok(());
+    }
+    let options = HeadlessOptions::parse(args)?;
+
+    // Evaluate the scene script with the same engine the GUI uses
+    let engine = init_engine();
+    let script = std::fs::read_to_string(&options.script)
+        .map_err(|e| format!("could not read {}: {e}", options.script))?;
+    let load_scene = |time: f64| -> Result<Scene, Box<dyn Error>> {
+        let scene = eval_scene(&engine, &script, time)
+            .map_err(|e| format!("could not evaluate {}: {e}", options.script))?;
+        Ok(scene.at_time(time))
+    };
+
+    if options.list_cameras {
+        for label in camera_labels(&load_scene(options.time)?) {
+            println!("{label}");
+        }
+        return Ok(());
+    }
+
+    // A still is a single unnumbered frame at the requested time
+    let frames: Vec<(Option<u32>, f64)> = match options.frames {
+        Some((first, last)) => (first..=last)
+            .map(|frame| (Some(frame), frame as f64 / options.fps))
+            .collect(),
+        None => vec![(None, options.time)],
+    };
+
+    // One renderer for every camera, so the scene and its BVH are only built once per frame
+    let mut renderer = Renderer::new(
+        Scene::empty(),
+        Camera::unit(),
+        options.raytracing.clone(),
+        options.width,
+        options.height,
+    );
+    for (frame, time) in frames {
+        let mut scene = load_scene(time)?;
+        scene.display = Some(options.display(&scene));
+        let cameras = select_cameras(&scene, &options.cameras, options.all_cameras)?;
+        let batch = cameras.len() > 1;
+        renderer.set_scene(scene);
+
+        for (label, mut camera) in cameras {
+            let mut output = options.output.clone();
+            if let (Some(label), true) = (&label, batch) {
+                output = suffixed_output(&output, label);
+            }
+            if let Some(frame) = frame {
+                output = suffixed_output(&output, &format!("{frame:04}"));
+                println!("Frame {frame} at {time:.3}s");
+            }
+            if let Some(label) = &label {
+                println!("Camera {label}");
+            }
+            if let Some(fov) = options.fov {
+                camera.fov = fov;
+            }
+            renderer.set_camera(camera);
+            render_frame(&mut renderer);
+            match options.exr_layers {
+                true => renderer.save_exr_layers(&output)?,
+                false => renderer.save_image(&output)?,
+            }
+            println!("Saved {output}");
+            for aov in &options.aovs {
+                let aov_output = suffixed_output(&output, aov.name());
+                renderer.save_aov(*aov, &aov_output)?;
+                println!("Saved {aov_output}");
+            }
+        }
+    }
+    Ok(())
+}
+
+// Render the current frame while printing its progress
+fn render_frame(renderer: &mut Renderer) {
+    let mut percent = None;
+    renderer.render_with(|_, progress| {
+        let done = (progress.fraction() * 100.0) as u32;
+        if percent != Some(done) {
+            percent = Some(done);
+            print!("\rRendering {done}%");
+            let _ = std::io::stdout().flush();
+        }
+    });
+    println!();
+}
+
+/// Insert a suffix such as a camera label or frame number before the extension of a file name
+fn suffixed_output(output: &str, suffix: &str) -> String {
+    let suffix: String = suffix
+        .chars()
+        .map(|c| match c.is_alphanumeric() || "+-_.".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    let path = Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}_{suffix}.{}", ext.to_string_lossy()),
+        None => format!("{stem}_{suffix}"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+fn camera_labels(scene: &Scene) -> Vec<&String> {
+    let mut labels: Vec<&String> = scene.cameras.keys().collect();
+    labels.sort();
+    labels
+}
+
+// A camera and the label it was registered under
+type LabelledCamera = (Option<String>, Camera);
+
+/// Pick cameras by label, falling back to the first camera or the unit camera.
+/// Labels are `None` only for the unit camera of a scene without cameras.
+fn select_cameras(
+    scene: &Scene,
+    labels: &[String],
+    all: bool,
+) -> Result<Vec<LabelledCamera>, Box<dyn Error>> {
+    let available = camera_labels(scene);
+    let chosen: Vec<&String> = match (all, labels.is_empty()) {
+        (true, _) => available.clone(),
+        (false, false) => labels.iter().collect(),
+        (false, true) => available.iter().take(1).copied().collect(),
+    };
+    if chosen.is_empty() {
+        return Ok(vec![(None, Camera::unit())]);
+    }
+
+    let mut cameras = Vec::with_capacity(chosen.len());
+    for label in chosen {
+        match scene.cameras.get(label) {
+            Some(camera) => cameras.push((Some(label.clone()), camera.clone())),
+            None => {
+                let labels = available
+                    .iter()
+                    .map(|label| format!("'{label}'"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                return Err(format!("no camera '{label}' in scene, available: [{labels}]").into());
+            }
+        }
+    }
+    Ok(cameras)
+}
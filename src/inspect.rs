@@ -0,0 +1,325 @@
+use crate::{
+    node::Node,
+    ray::{Intersection, Ray},
+};
+use nalgebra::{Point3, Vector3};
+use std::fmt::Write;
+
+// RAY KIND -----------------------------------------------------------------
+/// The shading term a ray was traced for
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RayKind {
+    /// Leaves the camera through the inspected pixel
+    Camera,
+    /// Mirror reflection, weighted by the material's `kr` and the Fresnel reflection of `kt`
+    /// or, when path tracing, a smooth reflection
+    Reflection,
+    /// Refraction into or out of a transparent material, weighted by what `kt` lets through
+    Refraction,
+    /// Indirect diffuse sample, weighted by the diffuse coefficient or, when path tracing,
+    /// by the material's BSDF
+    Diffuse,
+    /// Path tracer bounce off a rough microfacet surface
+    Glossy,
+}
+
+impl RayKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RayKind::Camera => "camera",
+            RayKind::Reflection => "reflection",
+            RayKind::Refraction => "refraction",
+            RayKind::Diffuse => "diffuse",
+            RayKind::Glossy => "glossy",
+        }
+    }
+}
+
+// RECORDS -----------------------------------------------------------------
+/// One ray traced while inspecting a pixel and everything it spawned
+#[derive(Clone, Debug)]
+pub struct RayRecord {
+    pub kind: RayKind,
+    pub depth: u8,
+    pub origin: Point3<f64>,
+    pub direction: Vector3<f64>,
+    /// None if the ray missed or was past the maximum depth
+    pub hit: Option<HitRecord>,
+    /// Light the path tracer's ray ran into instead of a node
+    pub emitter: Option<String>,
+    /// Colour the ray brought back, None where the shader returns nothing
+    pub colour: Option<Vector3<f32>>,
+}
+
+/// Where a ray hit and how that point was shaded
+#[derive(Clone, Debug)]
+pub struct HitRecord {
+    pub node: String,
+    pub distance: f64,
+    pub position: Point3<f64>,
+    pub normal: Vector3<f64>,
+    /// What every light added to the point
+    pub lights: Vec<LightRecord>,
+    /// Weighted colour of the reflection, the refraction and the indirect diffuse rays
+    pub reflection: Vector3<f32>,
+    pub refraction: Vector3<f32>,
+    pub indirect: Vector3<f32>,
+    /// Reflection, refraction and indirect diffuse rays traced from the point
+    pub rays: Vec<RayRecord>,
+}
+
+/// The contribution of one light to a shaded point
+#[derive(Clone, Debug)]
+pub struct LightRecord {
+    pub label: String,
+    pub ambient: bool,
+    /// None if shadows are off or the light is ambient
+    pub shadow: Option<ShadowRecord>,
+    pub contribution: Vector3<f32>,
+}
+
+/// A ray towards a light, and what stood in its way
+#[derive(Clone, Debug)]
+pub struct ShadowRecord {
+    pub origin: Point3<f64>,
+    pub direction: Vector3<f64>,
+    pub light_distance: f64,
+    /// Label of the node blocking the light and how far along the ray it is
+    pub occluder: Option<(String, f64)>,
+}
+
+impl RayRecord {
+    pub fn new(kind: RayKind, ray: &Ray, depth: u8) -> RayRecord {
+        RayRecord {
+            kind,
+            depth,
+            origin: ray.a,
+            direction: ray.b,
+            hit: None,
+            emitter: None,
+            colour: None,
+        }
+    }
+
+    /// Rays in the tree, this one included
+    pub fn count(&self) -> usize {
+        1 + self
+            .hit
+            .iter()
+            .flat_map(|hit| &hit.rays)
+            .map(RayRecord::count)
+            .sum::<usize>()
+    }
+
+    /// The tree written out as indented JSON
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        self.write_json(&mut json, 0);
+        json.push('\n');
+        json
+    }
+
+    fn write_json(&self, json: &mut String, indent: usize) {
+        let mut object = JsonObject::begin(json, indent);
+        object.field("kind", &string(self.kind.name()));
+        object.field("depth", &self.depth.to_string());
+        object.field("origin", &vector(&self.origin.coords));
+        object.field("direction", &vector(&self.direction));
+        match &self.colour {
+            Some(colour) => object.field("colour", &vector(&colour.cast())),
+            None => object.field("colour", "null"),
+        }
+        match &self.emitter {
+            Some(emitter) => object.field("emitter", &string(emitter)),
+            None => object.field("emitter", "null"),
+        }
+        match &self.hit {
+            Some(hit) => {
+                object.key("hit");
+                hit.write_json(object.json, indent + 1);
+            }
+            None => object.field("hit", "null"),
+        }
+        object.end();
+    }
+}
+
+impl HitRecord {
+    pub fn new(node: &Node, intersect: &Intersection) -> HitRecord {
+        HitRecord {
+            node: node.label.clone(),
+            distance: intersect.distance,
+            position: intersect.point,
+            normal: intersect.normal.normalize(),
+            lights: Vec::new(),
+            reflection: Vector3::zeros(),
+            refraction: Vector3::zeros(),
+            indirect: Vector3::zeros(),
+            rays: Vec::new(),
+        }
+    }
+
+    fn write_json(&self, json: &mut String, indent: usize) {
+        let mut object = JsonObject::begin(json, indent);
+        object.field("node", &string(&self.node));
+        object.field("distance", &number(self.distance));
+        object.field("position", &vector(&self.position.coords));
+        object.field("normal", &vector(&self.normal));
+        object.field("reflection", &vector(&self.reflection.cast()));
+        object.field("refraction", &vector(&self.refraction.cast()));
+        object.field("indirect", &vector(&self.indirect.cast()));
+        object.key("lights");
+        write_array(
+            object.json,
+            indent + 1,
+            &self.lights,
+            LightRecord::write_json,
+        );
+        object.key("rays");
+        write_array(object.json, indent + 1, &self.rays, RayRecord::write_json);
+        object.end();
+    }
+}
+
+impl LightRecord {
+    fn write_json(&self, json: &mut String, indent: usize) {
+        let mut object = JsonObject::begin(json, indent);
+        object.field("label", &string(&self.label));
+        object.field("ambient", &self.ambient.to_string());
+        object.field("contribution", &vector(&self.contribution.cast()));
+        match &self.shadow {
+            Some(shadow) => {
+                object.key("shadow");
+                shadow.write_json(object.json, indent + 1);
+            }
+            None => object.field("shadow", "null"),
+        }
+        object.end();
+    }
+}
+
+impl ShadowRecord {
+    pub fn new(ray: &Ray, light_distance: f64) -> ShadowRecord {
+        ShadowRecord {
+            origin: ray.a,
+            direction: ray.b,
+            light_distance,
+            occluder: None,
+        }
+    }
+
+    fn write_json(&self, json: &mut String, indent: usize) {
+        let mut object = JsonObject::begin(json, indent);
+        object.field("origin", &vector(&self.origin.coords));
+        object.field("direction", &vector(&self.direction));
+        object.field("light_distance", &number(self.light_distance));
+        match &self.occluder {
+            Some((label, distance)) => {
+                object.field("occluder", &string(label));
+                object.field("occluder_distance", &number(*distance));
+            }
+            None => object.field("occluder", "null"),
+        }
+        object.end();
+    }
+}
+
+// JSON -----------------------------------------------------------------
+// Writes the fields of one object, two spaces of indentation per level
+struct JsonObject<'a> {
+    json: &'a mut String,
+    indent: usize,
+    first: bool,
+}
+
+impl<'a> JsonObject<'a> {
+    fn begin(json: &'a mut String, indent: usize) -> JsonObject<'a> {
+        json.push('{');
+        JsonObject {
+            json,
+            indent,
+            first: true,
+        }
+    }
+
+    // Start a field, its value is written straight after
+    fn key(&mut self, key: &str) {
+        if !self.first {
+            self.json.push(',');
+        }
+        self.first = false;
+        let _ = write!(
+            self.json,
+            "\n{}{}: ",
+            "  ".repeat(self.indent + 1),
+            string(key)
+        );
+    }
+
+    fn field(&mut self, key: &str, value: &str) {
+        self.key(key);
+        self.json.push_str(value);
+    }
+
+    fn end(self) {
+        let _ = write!(self.json, "\n{}}}", "  ".repeat(self.indent));
+    }
+}
+
+fn write_array<T>(
+    json: &mut String,
+    indent: usize,
+    items: &[T],
+    write: impl Fn(&T, &mut String, usize),
+) {
+    if items.is_empty() {
+        json.push_str("[]");
+        return;
+    }
+    json.push('[');
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "\n{}", "  ".repeat(indent + 1));
+        write(item, json, indent + 1);
+    }
+    let _ = write!(json, "\n{}]", "  ".repeat(indent));
+}
+
+// JSON has no infinities or NaN, they are written as null
+fn number(value: f64) -> String {
+    match value.is_finite() {
+        true => value.to_string(),
+        false => String::from("null"),
+    }
+}
+
+fn vector(value: &Vector3<f64>) -> String {
+    format!(
+        "[{}, {}, {}]",
+        number(value.x),
+        number(value.y),
+        number(value.z)
+    )
+}
+
+fn string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
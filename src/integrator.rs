@@ -0,0 +1,531 @@
+use crate::{
+    bsdf::{Bsdf, Lobe, ShadingFrame},
+    bvh::BVH,
+    emitter::Emitter,
+    inspect::{HitRecord, LightRecord, RayKind, RayRecord, ShadowRecord},
+    node::Node,
+    ray::{Intersection, Ray},
+    renderer::RaytracingOption,
+    sampler::Sampler,
+    scene::Scene,
+    SURFACE_OFFSET,
+};
+use nalgebra::{distance, Point3, Vector3};
+use std::f64::consts::PI;
+
+// Highest chance of a path surviving Russian roulette, so even the brightest paths end
+const MAX_SURVIVAL: f32 = 0.95;
+
+// INTEGRATOR KIND -----------------------------------------------------------------
+/// How the light arriving along each camera ray is computed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IntegratorKind {
+    /// Phong direct lighting, mirror reflections and a few indirect diffuse rays, fast but biased
+    Whitted,
+    /// Unbiased path tracing, slow to converge but ground truth global illumination
+    Path,
+}
+
+impl IntegratorKind {
+    pub const ALL: [IntegratorKind; 2] = [IntegratorKind::Whitted, IntegratorKind::Path];
+
+    /// Parse the names used from the command line
+    pub fn from_name(name: &str) -> Option<IntegratorKind> {
+        match name.to_lowercase().as_str() {
+            "whitted" | "phong" => Some(IntegratorKind::Whitted),
+            "path" | "pathtracer" => Some(IntegratorKind::Path),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IntegratorKind::Whitted => "whitted",
+            IntegratorKind::Path => "path",
+        }
+    }
+
+    /// The integrator for a scene, built once per frame with whatever it gathers from the
+    /// scene ahead of tracing. Rays are then traced through that same scene.
+    pub fn integrator<'a>(&self, scene: &'a Scene) -> Box<dyn Integrator + 'a> {
+        match self {
+            IntegratorKind::Whitted => Box::new(Whitted::new(scene)),
+            IntegratorKind::Path => Box::new(PathTracer::new(scene)),
+        }
+    }
+}
+
+// MIS HEURISTIC -----------------------------------------------------------------
+/// How a light sample and a bounce that can both find the same emitter share its light
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MisHeuristic {
+    /// In proportion to the density of each strategy
+    Balance,
+    /// In proportion to the squared densities, favouring the better strategy more strongly
+    Power,
+}
+
+impl MisHeuristic {
+    pub const ALL: [MisHeuristic; 2] = [MisHeuristic::Balance, MisHeuristic::Power];
+
+    /// Parse the names used from the command line
+    pub fn from_name(name: &str) -> Option<MisHeuristic> {
+        match name.to_lowercase().as_str() {
+            "balance" => Some(MisHeuristic::Balance),
+            "power" => Some(MisHeuristic::Power),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MisHeuristic::Balance => "balance",
+            MisHeuristic::Power => "power",
+        }
+    }
+
+    /// Weight of a sample drawn with density pdf, where the other strategy has density other
+    pub fn weight(&self, pdf: f64, other: f64) -> f64 {
+        if pdf <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            MisHeuristic::Balance => pdf / (pdf + other),
+            MisHeuristic::Power => pdf * pdf / (pdf * pdf + other * other),
+        }
+    }
+}
+
+// INTEGRATOR -----------------------------------------------------------------
+/// Computes the colour of the light arriving along a camera ray
+pub trait Integrator: Send + Sync {
+    /// Light arriving along the ray, None where the pixel is left empty.
+    /// With a record every ray traced on the way is written into it.
+    fn radiance(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        options: &RaytracingOption,
+        bvh: &Option<BVH>,
+        sampler: &mut Sampler,
+        record: Option<&mut RayRecord>,
+    ) -> Option<Vector3<f32>>;
+}
+
+// WHITTED -----------------------------------------------------------------
+/// Phong shading with perfect mirror reflections, refraction through transparent materials
+/// and the indirect diffuse term weighted by `diffuse_coefficient`, up to `ray_depth` rays
+/// deep. Misses are left empty.
+pub struct Whitted<'a> {
+    // Emissive nodes, lit from like point lights
+    areas: Vec<Emitter<'a>>,
+}
+
+impl<'a> Whitted<'a> {
+    /// Whitted integrator lit by the lights and the emissive nodes of the scene
+    pub fn new(scene: &'a Scene) -> Whitted<'a> {
+        Whitted {
+            areas: Emitter::areas(scene),
+        }
+    }
+}
+
+impl Integrator for Whitted<'_> {
+    fn radiance(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        options: &RaytracingOption,
+        bvh: &Option<BVH>,
+        sampler: &mut Sampler,
+        record: Option<&mut RayRecord>,
+    ) -> Option<Vector3<f32>> {
+        ray.shade_ray(scene, &self.areas, 0, options, bvh, sampler, record)
+    }
+}
+
+// PATH TRACER -----------------------------------------------------------------
+/// Unidirectional path tracer. Every bounce is sampled from the BSDF of the material's model,
+/// with diffuse directions cosine weighted. Point lights are sampled at every hit. Lights with a
+/// radius, emissive nodes and the sky the ambient lights make are sampled too, and bounces that
+/// run into them add their light as well, the two weighted against each other by multiple
+/// importance sampling. Paths end at `max_bounces`, or earlier by Russian roulette once they are
+/// `roulette_bounces` long.
+pub struct PathTracer<'a> {
+    emitters: Vec<Emitter<'a>>,
+}
+
+impl<'a> PathTracer<'a> {
+    /// Path tracer lit by the emitters of the scene
+    pub fn new(scene: &'a Scene) -> PathTracer<'a> {
+        PathTracer {
+            emitters: Emitter::gather(scene),
+        }
+    }
+}
+
+impl Integrator for PathTracer<'_> {
+    fn radiance(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        options: &RaytracingOption,
+        bvh: &Option<BVH>,
+        sampler: &mut Sampler,
+        record: Option<&mut RayRecord>,
+    ) -> Option<Vector3<f32>> {
+        let path = Path {
+            scene,
+            options,
+            bvh,
+            emitters: &self.emitters,
+        };
+        Some(path.trace(ray, 0, Vector3::repeat(1.0), None, sampler, record))
+    }
+}
+
+// What every vertex of a path is traced against
+struct Path<'a> {
+    scene: &'a Scene,
+    options: &'a RaytracingOption,
+    bvh: &'a Option<BVH>,
+    emitters: &'a [Emitter<'a>],
+}
+
+impl Path<'_> {
+    // Light arriving along the ray after `bounce` bounces. Throughput is how much of it
+    // reaches the camera and bsdf_pdf the density the last bounce picked the direction with,
+    // None for camera rays and mirror reflections, which light sampling never finds.
+    fn trace(
+        &self,
+        ray: &Ray,
+        bounce: u8,
+        throughput: Vector3<f32>,
+        bsdf_pdf: Option<f64>,
+        sampler: &mut Sampler,
+        mut record: Option<&mut RayRecord>,
+    ) -> Vector3<f32> {
+        let hit = ray.first_hit(self.scene, self.bvh);
+        let hit_distance = hit.as_ref().map_or(f64::INFINITY, |(_, intersect)| {
+            distance(&ray.a, &intersect.point)
+        });
+        // Emitters closer than any node end the path
+        let emitter = self
+            .emitters
+            .iter()
+            .filter_map(|emitter| Some((emitter, emitter.hit(ray)?)))
+            .filter(|(_, distance)| *distance < hit_distance)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let colour = match (emitter, hit) {
+            (Some((emitter, _)), _) => {
+                if let Some(record) = record.as_deref_mut() {
+                    record.emitter = Some(emitter.label().to_string());
+                }
+                self.emitted(emitter, ray, bsdf_pdf)
+            }
+            (None, Some((node, intersect))) => {
+                let hit = record
+                    .as_deref_mut()
+                    .map(|record| record.hit.insert(HitRecord::new(node, &intersect)));
+                // Light from inside a transparent node crossed it and was absorbed on the way
+                let material = &node.material;
+                let absorbed = match material.transmits() && ray.b.dot(&intersect.normal) > 0.0 {
+                    true => material.transmittance(intersect.distance),
+                    false => Vector3::repeat(1.0),
+                };
+                let throughput = throughput.component_mul(&absorbed);
+                let colour = self
+                    .shade(ray, node, &intersect, bounce, throughput, sampler, hit)
+                    .component_mul(&absorbed);
+                colour + self.emitted_by(node, ray, &intersect, bsdf_pdf)
+            }
+            (None, None) => Vector3::zeros(),
+        };
+        if let Some(record) = record {
+            record.colour = Some(colour);
+        }
+        colour
+    }
+
+    // Light of an emitter a ray ran into, weighted against light sampling finding it
+    fn emitted(&self, emitter: &Emitter, ray: &Ray, bsdf_pdf: Option<f64>) -> Vector3<f32> {
+        let radiance = emitter.radiance(&ray.a, self.options.falloff);
+        match bsdf_pdf {
+            Some(pdf) => radiance * self.options.mis.weight(pdf, emitter.pdf(&ray.a)) as f32,
+            None => radiance,
+        }
+    }
+
+    // Light an emissive node gives off back along a ray that hit it, weighted against light
+    // sampling finding the same point. Nodes that cannot be sampled are only found this way.
+    fn emitted_by(
+        &self,
+        node: &Node,
+        ray: &Ray,
+        intersect: &Intersection,
+        bsdf_pdf: Option<f64>,
+    ) -> Vector3<f32> {
+        if !node.emits() {
+            return Vector3::zeros();
+        }
+        let radiance = node.emitted(&intersect.normal, &-ray.b);
+        match bsdf_pdf {
+            Some(pdf) => {
+                let light_pdf = node.light_pdf(&ray.a, intersect, ray.time);
+                radiance * self.options.mis.weight(pdf, light_pdf) as f32
+            }
+            None => radiance,
+        }
+    }
+
+    // Light leaving a hit point back along the ray
+    #[allow(clippy::too_many_arguments)]
+    fn shade(
+        &self,
+        ray: &Ray,
+        node: &Node,
+        intersect: &Intersection,
+        bounce: u8,
+        throughput: Vector3<f32>,
+        sampler: &mut Sampler,
+        mut record: Option<&mut HitRecord>,
+    ) -> Vector3<f32> {
+        let bsdf = node.material.bsdf();
+        // Opaque surfaces are lit from either side, so their normal faces the ray. Transmitting
+        // ones keep the normal of the intersection to tell the inside from the outside.
+        let mut normal = intersect.normal.normalize();
+        if !bsdf.transmits() && normal.dot(&ray.b) > 0.0 {
+            normal = -normal;
+        }
+        let frame = ShadingFrame::new(normal);
+        let outgoing = frame.to_local(&-ray.b);
+        let surface = Surface {
+            point: &intersect.point,
+            frame: &frame,
+            bsdf: bsdf.as_ref(),
+            outgoing: &outgoing,
+            bounces: bounce + 1 < self.options.max_bounces,
+        };
+
+        let mut colour = self.direct(ray, &surface, sampler, record.as_deref_mut());
+        if !surface.bounces {
+            return colour;
+        }
+
+        // Drawn before sampling so every bounce uses the same sampler dimensions
+        let choice = sampler.next_1d();
+        let sample = sampler.next_2d();
+        let Some(scatter) = bsdf.sample(&outgoing, choice, sample) else {
+            return colour;
+        };
+        let (kind, bsdf_pdf) = match scatter.lobe {
+            Lobe::Specular if scatter.incoming.z * outgoing.z < 0.0 => (RayKind::Refraction, None),
+            Lobe::Specular => (RayKind::Reflection, None),
+            Lobe::Glossy => (RayKind::Glossy, Some(scatter.pdf)),
+            Lobe::Diffuse => (RayKind::Diffuse, Some(scatter.pdf)),
+        };
+        let mut weight = scatter.weight;
+
+        // Russian roulette, dim paths are likely to end and the survivors brightened to match
+        let mut throughput = throughput.component_mul(&weight);
+        if bounce >= self.options.roulette_bounces {
+            let survival = throughput.max().min(MAX_SURVIVAL);
+            if sampler.next_1d() as f32 >= survival {
+                return colour;
+            }
+            weight /= survival;
+            throughput /= survival;
+        }
+
+        let direction = frame.to_world(&scatter.incoming);
+        let next = Ray::new(surface.leave(&direction), direction).with_time(ray.time);
+        let mut child = record
+            .is_some()
+            .then(|| RayRecord::new(kind, &next, bounce + 1));
+        let incoming = self
+            .trace(
+                &next,
+                bounce + 1,
+                throughput,
+                bsdf_pdf,
+                sampler,
+                child.as_mut(),
+            )
+            .component_mul(&weight);
+        colour += incoming;
+        if let Some(record) = record {
+            match kind {
+                RayKind::Reflection => record.reflection = incoming,
+                RayKind::Refraction => record.refraction = incoming,
+                _ => record.indirect = incoming,
+            }
+            record.rays.extend(child);
+        }
+        colour
+    }
+
+    // Light reflected straight from the lights. A point light of colour c lights a white
+    // Lambertian surface facing it as brightly as the whitted integrator's diffuse term does,
+    // a light with a radius the same from afar. Emitters, emissive nodes among them, are sampled
+    // once each, weighted against the bounce finding them.
+    fn direct(
+        &self,
+        ray: &Ray,
+        surface: &Surface,
+        sampler: &mut Sampler,
+        mut record: Option<&mut HitRecord>,
+    ) -> Vector3<f32> {
+        let mut colour = Vector3::zeros();
+        for (label, light) in &self.scene.lights {
+            if !light.active || light.ambient || light.radius > 0.0 {
+                continue;
+            }
+            let to_light = light.position - surface.point;
+            let light_distance = to_light.norm();
+            let to_light = to_light / light_distance;
+            let (reflected, cosine) = surface.scatter(&to_light);
+            if reflected == Vector3::zeros() {
+                continue;
+            }
+
+            let mut shadow = None;
+            if self.options.shadows {
+                let to_light_ray = Ray::new(surface.leave(&to_light), to_light).with_time(ray.time);
+                shadow = record
+                    .is_some()
+                    .then(|| ShadowRecord::new(&to_light_ray, light_distance));
+                if to_light_ray.light_blocked(self.scene, light, self.bvh, shadow.as_mut()) {
+                    push_light(&mut record, label, false, shadow, Vector3::zeros());
+                    continue;
+                }
+            }
+
+            let mut contribution = light.colour.component_mul(&reflected) * (cosine * PI) as f32;
+            if self.options.falloff {
+                contribution *= light.attenuation(light_distance as f32);
+            }
+            colour += contribution;
+            push_light(&mut record, label, false, shadow, contribution);
+        }
+
+        for (index, emitter) in self.emitters.iter().enumerate() {
+            // Drawn whatever happens next, so later bounces keep their sampler dimensions
+            let sample = sampler.next_2d();
+            let Some(light) = emitter.sample(surface.point, ray.time, self.options.falloff, sample)
+            else {
+                continue;
+            };
+            let (reflected, cosine) = surface.scatter(&light.direction);
+            if reflected == Vector3::zeros() {
+                continue;
+            }
+
+            let mut shadow = None;
+            if self.options.shadows {
+                let to_light_ray =
+                    Ray::new(surface.leave(&light.direction), light.direction).with_time(ray.time);
+                shadow = record
+                    .is_some()
+                    .then(|| ShadowRecord::new(&to_light_ray, light.distance));
+                // Other emitters cast shadows too, as bounces would run into them
+                let blocker = self
+                    .emitters
+                    .iter()
+                    .enumerate()
+                    .filter(|(other, _)| *other != index)
+                    .filter_map(|(_, other)| Some((other, other.hit(&to_light_ray)?)))
+                    .find(|(_, distance)| *distance < light.distance);
+                let blocked = match blocker {
+                    Some((other, distance)) => {
+                        if let Some(shadow) = shadow.as_mut() {
+                            shadow.occluder = Some((other.label().to_string(), distance));
+                        }
+                        true
+                    }
+                    None => {
+                        to_light_ray.blocked(self.scene, light.distance, self.bvh, shadow.as_mut())
+                    }
+                };
+                if blocked {
+                    push_light(
+                        &mut record,
+                        emitter.label(),
+                        emitter.is_sky(),
+                        shadow,
+                        Vector3::zeros(),
+                    );
+                    continue;
+                }
+            }
+
+            let weight = self
+                .options
+                .mis
+                .weight(light.pdf, surface.pdf(&light.direction));
+            let contribution =
+                light.radiance.component_mul(&reflected) * (cosine / light.pdf * weight) as f32;
+            colour += contribution;
+            push_light(
+                &mut record,
+                emitter.label(),
+                emitter.is_sky(),
+                shadow,
+                contribution,
+            );
+        }
+        colour
+    }
+}
+
+// A hit point being shaded
+struct Surface<'a> {
+    point: &'a Point3<f64>,
+    frame: &'a ShadingFrame,
+    bsdf: &'a dyn Bsdf,
+    // Direction back along the ray, in the shading frame
+    outgoing: &'a Vector3<f64>,
+    // If the path carries on from here, so a bounce could find what light sampling does
+    bounces: bool,
+}
+
+impl Surface<'_> {
+    // BSDF for light arriving from a world direction and the cosine of its angle to the normal
+    fn scatter(&self, direction: &Vector3<f64>) -> (Vector3<f32>, f64) {
+        let incoming = self.frame.to_local(direction);
+        (self.bsdf.eval(self.outgoing, &incoming), incoming.z.abs())
+    }
+
+    // Density of the bounce picking a world direction
+    fn pdf(&self, direction: &Vector3<f64>) -> f64 {
+        match self.bounces {
+            true => self
+                .bsdf
+                .pdf(self.outgoing, &self.frame.to_local(direction)),
+            false => 0.0,
+        }
+    }
+
+    // Start of a ray leaving in a world direction, just off the side of the surface it leaves
+    fn leave(&self, direction: &Vector3<f64>) -> Point3<f64> {
+        let normal = self.frame.to_world(&Vector3::z());
+        self.point + normal * SURFACE_OFFSET.copysign(normal.dot(direction))
+    }
+}
+
+// Note what a light added to a recorded hit
+fn push_light(
+    record: &mut Option<&mut HitRecord>,
+    label: &str,
+    ambient: bool,
+    shadow: Option<ShadowRecord>,
+    contribution: Vector3<f32>,
+) {
+    if let Some(record) = record.as_deref_mut() {
+        record.lights.push(LightRecord {
+            label: label.to_string(),
+            ambient,
+            shadow,
+            contribution,
+        });
+    }
+}
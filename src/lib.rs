@@ -0,0 +1,52 @@
+#![allow(
+    clippy::new_ret_no_self,
+    clippy::should_implement_trait,
+    clippy::upper_case_acronyms
+)]
+//! Raytracer for special geometric surfaces, scenes are built with Rhai scripts
+//!
+//! ```no_run
+//! use rust_opengl::{script::init_engine, Camera, RaytracingOption, Renderer, Scene};
+//!
+//! let script = std::fs::read_to_string("rhai/shapes.rhai").unwrap();
+//! let scene: Scene = init_engine().eval(&script).unwrap();
+//! let camera = scene.cameras.get("+Z Cam").cloned().unwrap_or_else(Camera::unit);
+//!
+//! let mut renderer = Renderer::new(scene, camera, RaytracingOption::default(), 640, 480);
+//! renderer.render_with(|_tile, progress| println!("{:.0}%", progress.fraction() * 100.0));
+//! renderer.save_image("shapes.png").unwrap();
+//! ```
+
+pub const EPSILON: f64 = 1e-7;
+pub const INFINITY: f64 = 1e10;
+/// Rays leaving a surface start this far along its normal so they do not hit it again
+pub const SURFACE_OFFSET: f64 = 1e-6;
+
+pub mod adaptive;
+pub mod animation;
+pub mod aov;
+pub mod bsdf;
+pub mod bvh;
+pub mod camera;
+pub mod emitter;
+pub mod filter;
+pub mod inspect;
+pub mod integrator;
+pub mod light;
+pub mod material;
+pub mod node;
+pub mod output;
+pub mod primitive;
+pub mod ray;
+pub mod renderer;
+pub mod sampler;
+pub mod scene;
+pub mod script;
+pub mod tile;
+pub mod tonemap;
+
+pub use camera::Camera;
+pub use node::Node;
+pub use primitive::Primitive;
+pub use renderer::{RaytracingOption, RenderProgress, Renderer, Tile};
+pub use scene::Scene;
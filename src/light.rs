@@ -1,3 +1,5 @@
+use crate::animation::{Interpolation, LightAnimation};
+use crate::ray::Ray;
 use nalgebra::{Point3, Vector3};
 
 #[derive(Clone)]
@@ -5,8 +7,12 @@ pub struct Light {
     pub position: Point3<f64>,
     pub colour: Vector3<f32>,
     pub falloff: Vector3<f32>,
+    // Radius of the sphere the light glows over, 0 for a point. Only the path tracer sees it,
+    // the whitted integrator lights from the centre.
+    pub radius: f64,
     pub ambient: bool,
     pub active: bool,
+    pub animation: LightAnimation,
 }
 
 impl Light {
@@ -17,8 +23,10 @@ impl Light {
             position,
             colour,
             falloff,
+            radius: 0.0,
             ambient: false,
             active: true,
+            animation: LightAnimation::default(),
         }
     }
     pub fn ambient(colour: Vector3<f64>) -> Light {
@@ -26,11 +34,79 @@ impl Light {
             position: Point3::new(0.0, 0.0, 0.0),
             colour: colour.cast(),
             falloff: Vector3::new(0.0, 0.0, 0.0),
+            radius: 0.0,
             ambient: true,
             active: true,
+            animation: LightAnimation::default(),
         }
     }
+    // Share of the light left after travelling distance, from the falloff coefficients
+    pub fn attenuation(&self, distance: f32) -> f32 {
+        1.0 / ((1.0 + self.falloff[0])
+            + self.falloff[1] * distance
+            + self.falloff[2] * distance * distance)
+    }
+    // Cosine of the half angle the sphere of the light covers from distance to its centre,
+    // None from inside it
+    pub fn cone(&self, distance: f64) -> Option<f64> {
+        if distance <= self.radius {
+            return None;
+        }
+        let sin = self.radius / distance;
+        Some((1.0 - sin * sin).sqrt())
+    }
+    // Radiance of the sphere seen from distance to its centre. It is chosen so a small sphere
+    // lights a surface as brightly as a point light of the same colour and falloff would.
+    pub fn sphere_radiance(&self, distance: f64, falloff: bool) -> Vector3<f32> {
+        let sin = self.radius / distance;
+        let mut radiance = self.colour / (sin * sin) as f32;
+        if falloff {
+            radiance *= self.attenuation(distance as f32);
+        }
+        radiance
+    }
+    // Distance along the ray to the sphere of the light, None if it has no radius or is missed
+    pub fn hit(&self, ray: &Ray) -> Option<f64> {
+        if self.radius <= 0.0 {
+            return None;
+        }
+        let to_centre = self.position - ray.a;
+        let along = to_centre.dot(&ray.b);
+        let discriminant = along * along - to_centre.norm_squared() + self.radius * self.radius;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let root = discriminant.sqrt();
+        [along - root, along + root]
+            .into_iter()
+            .find(|distance| *distance > 0.0)
+    }
     pub fn set_active(&mut self, active: bool) {
         self.active = active;
     }
+    // Record the current position, colour and falloff as a keyframe at time
+    pub fn keyframe(&mut self, time: f64, interpolation: Interpolation) {
+        let animation = &mut self.animation;
+        animation
+            .position
+            .insert(time, self.position.coords, interpolation);
+        animation
+            .colour
+            .insert(time, self.colour.cast(), interpolation);
+        animation
+            .falloff
+            .insert(time, self.falloff.cast(), interpolation);
+    }
+    // Set the light to its keyframed values at time
+    pub fn pose(&mut self, time: f64) {
+        if let Some(position) = self.animation.position.sample(time) {
+            self.position = position.into();
+        }
+        if let Some(colour) = self.animation.colour.sample(time) {
+            self.colour = colour.cast();
+        }
+        if let Some(falloff) = self.animation.falloff.sample(time) {
+            self.falloff = falloff.cast();
+        }
+    }
 }
@@ -1,12 +1,71 @@
+use crate::animation::{Interpolation, MaterialAnimation};
+use crate::bsdf::{
+    fresnel_dielectric, Bsdf, Conductor, Dielectric, Lambertian, OrenNayar, Phong, Plastic,
+};
 #[allow(dead_code)]
 use nalgebra::Vector3;
+
+// BSDF MODEL -----------------------------------------------------------------
+/// How the path tracer scatters light off a material. Models with an albedo take it from `kd`
+/// and those with a boundary their index of refraction from `ior`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BsdfModel {
+    /// Lambertian `kd`, a perfect mirror `kr` and a smooth boundary letting `kt` through, the
+    /// model of `Material(kd, ks, kr, shininess)`
+    Phong,
+    Lambertian,
+    /// Rough matte surface, sigma is the spread of its facet slopes in degrees
+    OrenNayar {
+        sigma: f32,
+    },
+    /// Metal with the complex index of refraction `eta + i k`
+    Conductor {
+        eta: Vector3<f32>,
+        k: Vector3<f32>,
+        roughness: f32,
+    },
+    /// Glass-like boundary of a transparent medium, tinted by `kt`
+    Dielectric {
+        roughness: f32,
+    },
+    /// Diffuse `kd` under a clear coat
+    Plastic {
+        roughness: f32,
+    },
+}
+
+impl BsdfModel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BsdfModel::Phong => "phong",
+            BsdfModel::Lambertian => "lambertian",
+            BsdfModel::OrenNayar { .. } => "oren-nayar",
+            BsdfModel::Conductor { .. } => "conductor",
+            BsdfModel::Dielectric { .. } => "dielectric",
+            BsdfModel::Plastic { .. } => "plastic",
+        }
+    }
+}
+
+// Index of refraction of glass, what transparent materials refract with unless told otherwise
+const DEFAULT_IOR: f32 = 1.5;
+
 // MATERIAL -----------------------------------------------------------------
+// kd, ks, kr, kt and shininess are what the whitted integrator shades with, the path tracer
+// scatters with the BSDF of the model
 #[derive(Clone)]
 pub struct Material {
     pub kd: Vector3<f32>,
     pub ks: Vector3<f32>,
     pub kr: Vector3<f32>,
+    // Transmission colour, zero for an opaque material
+    pub kt: Vector3<f32>,
     pub shininess: f32,
+    pub ior: f32,
+    // Beer-Lambert absorption per unit of distance travelled inside the material
+    pub absorption: Vector3<f32>,
+    pub model: BsdfModel,
+    pub animation: MaterialAnimation,
 }
 
 impl Material {
@@ -19,9 +78,28 @@ impl Material {
             kd,
             ks,
             kr,
+            kt: Vector3::zeros(),
             shininess,
+            ior: DEFAULT_IOR,
+            absorption: Vector3::zeros(),
+            model: BsdfModel::Phong,
+            animation: MaterialAnimation::default(),
         }
     }
+    // Phong material that also lets kt through a boundary with the index of refraction ior
+    pub fn transparent(
+        kd: Vector3<f64>,
+        ks: Vector3<f64>,
+        kr: Vector3<f64>,
+        kt: Vector3<f64>,
+        shininess: f64,
+        ior: f64,
+    ) -> Material {
+        let mut material = Material::new(kd, ks, kr, shininess);
+        material.kt = kt.cast();
+        material.ior = ior as f32;
+        material
+    }
     pub fn magenta() -> Material {
         let kd = Vector3::new(1.0, 0.0, 1.0);
         let ks = Vector3::new(1.0, 0.0, 1.0);
@@ -31,7 +109,12 @@ impl Material {
             kd,
             ks,
             kr,
+            kt: Vector3::zeros(),
             shininess,
+            ior: DEFAULT_IOR,
+            absorption: Vector3::zeros(),
+            model: BsdfModel::Phong,
+            animation: MaterialAnimation::default(),
         }
     }
     pub fn turquoise() -> Material {
@@ -43,7 +126,12 @@ impl Material {
             kd,
             ks,
             kr,
+            kt: Vector3::zeros(),
             shininess,
+            ior: DEFAULT_IOR,
+            absorption: Vector3::zeros(),
+            model: BsdfModel::Phong,
+            animation: MaterialAnimation::default(),
         }
     }
     pub fn red() -> Material {
@@ -55,7 +143,12 @@ impl Material {
             kd,
             ks,
             kr,
+            kt: Vector3::zeros(),
             shininess,
+            ior: DEFAULT_IOR,
+            absorption: Vector3::zeros(),
+            model: BsdfModel::Phong,
+            animation: MaterialAnimation::default(),
         }
     }
     pub fn blue() -> Material {
@@ -67,7 +160,12 @@ impl Material {
             kd,
             ks,
             kr,
+            kt: Vector3::zeros(),
             shininess,
+            ior: DEFAULT_IOR,
+            absorption: Vector3::zeros(),
+            model: BsdfModel::Phong,
+            animation: MaterialAnimation::default(),
         }
     }
     pub fn green() -> Material {
@@ -79,7 +177,169 @@ impl Material {
             kd,
             ks,
             kr,
+            kt: Vector3::zeros(),
             shininess,
+            ior: DEFAULT_IOR,
+            absorption: Vector3::zeros(),
+            model: BsdfModel::Phong,
+            animation: MaterialAnimation::default(),
+        }
+    }
+    // The constructors below fill kd, ks, kr and shininess with a Phong look-alike of the
+    // model, so the whitted preview shows roughly what the path tracer will
+    pub fn lambertian(albedo: Vector3<f64>) -> Material {
+        let mut material = Material::new(albedo, Vector3::zeros(), Vector3::zeros(), 1.0);
+        material.model = BsdfModel::Lambertian;
+        material
+    }
+    pub fn oren_nayar(albedo: Vector3<f64>, sigma: f64) -> Material {
+        let mut material = Material::lambertian(albedo);
+        material.model = BsdfModel::OrenNayar {
+            sigma: sigma as f32,
+        };
+        material
+    }
+    pub fn conductor(eta: Vector3<f64>, k: Vector3<f64>, roughness: f64) -> Material {
+        let (eta, k, roughness) = (eta.cast(), k.cast(), roughness as f32);
+        let reflectance = Conductor::new(eta, k, roughness).fresnel(1.0);
+        Material {
+            kd: Vector3::zeros(),
+            ks: reflectance,
+            kr: reflectance * (1.0 - roughness).powi(2),
+            kt: Vector3::zeros(),
+            shininess: shininess(roughness),
+            ior: DEFAULT_IOR,
+            absorption: Vector3::zeros(),
+            model: BsdfModel::Conductor { eta, k, roughness },
+            animation: MaterialAnimation::default(),
+        }
+    }
+    pub fn dielectric(ior: f64, roughness: f64) -> Material {
+        let roughness = roughness as f32;
+        // The whitted integrator reflects and refracts kt by the Fresnel equations too
+        Material {
+            kd: Vector3::zeros(),
+            ks: Vector3::repeat(1.0 - roughness),
+            kr: Vector3::zeros(),
+            kt: Vector3::repeat(1.0),
+            shininess: shininess(roughness),
+            ior: ior as f32,
+            absorption: Vector3::zeros(),
+            model: BsdfModel::Dielectric { roughness },
+            animation: MaterialAnimation::default(),
+        }
+    }
+    pub fn plastic(kd: Vector3<f64>, ior: f64, roughness: f64) -> Material {
+        let (ior, roughness) = (ior as f32, roughness as f32);
+        let reflectance = fresnel_dielectric(1.0, ior as f64) as f32;
+        Material {
+            kd: kd.cast(),
+            ks: Vector3::repeat(1.0 - roughness),
+            kr: Vector3::repeat(reflectance * (1.0 - roughness).powi(2)),
+            kt: Vector3::zeros(),
+            shininess: shininess(roughness),
+            ior,
+            absorption: Vector3::zeros(),
+            model: BsdfModel::Plastic { roughness },
+            animation: MaterialAnimation::default(),
+        }
+    }
+    // The BSDF the path tracer scatters light with
+    pub fn bsdf(&self) -> Box<dyn Bsdf> {
+        match self.model {
+            BsdfModel::Phong => Box::new(Phong {
+                diffuse: self.kd,
+                mirror: self.kr,
+                transmission: self.kt,
+                ior: self.ior as f64,
+            }),
+            BsdfModel::Lambertian => Box::new(Lambertian { albedo: self.kd }),
+            BsdfModel::OrenNayar { sigma } => Box::new(OrenNayar::new(self.kd, sigma)),
+            BsdfModel::Conductor { eta, k, roughness } => {
+                Box::new(Conductor::new(eta, k, roughness))
+            }
+            BsdfModel::Dielectric { roughness } => {
+                Box::new(Dielectric::new(self.kt, self.ior, roughness))
+            }
+            BsdfModel::Plastic { roughness } => {
+                Box::new(Plastic::new(self.kd, self.ior, roughness))
+            }
+        }
+    }
+    // If light passes through the material, so the whitted integrator traces refraction rays
+    pub fn transmits(&self) -> bool {
+        self.kt != Vector3::zeros()
+    }
+    // Share of the light left after travelling a distance inside the material
+    pub fn transmittance(&self, distance: f64) -> Vector3<f32> {
+        self.absorption.map(|a| (-a * distance as f32).exp())
+    }
+    // Record the current coefficients as a keyframe at time
+    pub fn keyframe(&mut self, time: f64, interpolation: Interpolation) {
+        let animation = &mut self.animation;
+        animation.kd.insert(time, self.kd.cast(), interpolation);
+        animation.ks.insert(time, self.ks.cast(), interpolation);
+        animation.kr.insert(time, self.kr.cast(), interpolation);
+        animation.kt.insert(time, self.kt.cast(), interpolation);
+        let shininess = self.shininess as f64;
+        animation.shininess.insert(time, shininess, interpolation);
+        animation.ior.insert(time, self.ior as f64, interpolation);
+        let absorption = self.absorption.cast();
+        animation.absorption.insert(time, absorption, interpolation);
+    }
+    // Set the coefficients to their keyframed values at time
+    pub fn pose(&mut self, time: f64) {
+        if let Some(kd) = self.animation.kd.sample(time) {
+            self.kd = kd.cast();
+        }
+        if let Some(ks) = self.animation.ks.sample(time) {
+            self.ks = ks.cast();
+        }
+        if let Some(kr) = self.animation.kr.sample(time) {
+            self.kr = kr.cast();
+        }
+        if let Some(kt) = self.animation.kt.sample(time) {
+            self.kt = kt.cast();
+        }
+        if let Some(shininess) = self.animation.shininess.sample(time) {
+            self.shininess = shininess as f32;
+        }
+        if let Some(ior) = self.animation.ior.sample(time) {
+            self.ior = ior as f32;
+        }
+        if let Some(absorption) = self.animation.absorption.sample(time) {
+            self.absorption = absorption.cast();
+        }
+    }
+}
+
+// Blinn-Phong exponent with a highlight about as wide as GGX of a roughness
+fn shininess(roughness: f32) -> f32 {
+    let alpha = (roughness * roughness).max(1e-3);
+    (2.0 / (alpha * alpha) - 2.0).clamp(1.0, 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transmittance_is_beer_lambert() {
+        let mut glass = Material::dielectric(1.5, 0.0);
+        assert_eq!(glass.transmittance(3.0), Vector3::repeat(1.0));
+
+        glass.absorption = Vector3::new(0.0, 0.5, 2.0);
+        for distance in [0.0, 0.1, 1.0, 2.5] {
+            let transmittance = glass.transmittance(distance);
+            for c in 0..3 {
+                let expected = (-glass.absorption[c] as f64 * distance).exp();
+                assert!((transmittance[c] as f64 - expected).abs() < 1e-6);
+            }
         }
+        // Two stretches absorb as much as one as long as both
+        let apart = glass
+            .transmittance(0.4)
+            .component_mul(&glass.transmittance(0.7));
+        assert!((apart - glass.transmittance(1.1)).amax() < 1e-6);
     }
 }
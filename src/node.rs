@@ -1,18 +1,38 @@
 use crate::{
+    animation::{Curve, Interpolation, NodeAnimation},
     bvh::AABB,
     material::Material,
     primitive::*,
     ray::{Intersection, Ray},
     EPSILON,
 };
-use nalgebra::{distance, Matrix3, Matrix4, Vector3};
+use nalgebra::{distance, Matrix3, Matrix4, Point3, Vector3};
 use std::sync::Arc;
 
+// Times across the shutter a moving node's bounds are sampled at
+const MOTION_STEPS: u32 = 32;
+// Fraction of the size of the swept bounds added around them, covering the arcs
+// a rotating node traces between the sampled times
+const MOTION_PADDING: f64 = 0.02;
+
+/// A point picked on the surface of a node, in world space
+pub struct SurfaceSample {
+    pub point: Point3<f64>,
+    pub normal: Vector3<f64>,
+    /// Density over the node's world surface area
+    pub pdf: f64,
+}
+
 #[derive(Clone)]
 pub struct Node {
+    //Label the node was added to the scene with
+    pub label: String,
     //Primitive
     pub primitive: Arc<dyn Primitive>,
     pub material: Material,
+    //Light given off by the surface on the side its normal faces, black for none
+    pub emission: Vector3<f32>,
+    pub strength: f32,
     pub aabb: AABB,
     //Transformations
     pub rotation: [f64; 3],
@@ -24,6 +44,14 @@ pub struct Node {
     pub inv_transpose_model: Matrix3<f64>,
     //If the node is active
     pub active: bool,
+    //Keyframed transformations
+    pub animation: NodeAnimation,
+    //Scene time the node was posed at, rays carry their time as an offset from it
+    pub time: f64,
+    //Seconds after the scene time the shutter opens and closes, None until a camera sets it
+    pub shutter: Option<(f64, f64)>,
+    //If the keyframes move the node away from its pose while the shutter is open
+    moving: bool,
 }
 
 impl Node {
@@ -31,8 +59,11 @@ impl Node {
     pub fn new(primitive: Arc<dyn Primitive>, material: Material) -> Node {
         let aabb = primitive.get_aabb();
         Node {
+            label: String::new(),
             primitive,
             material,
+            emission: Vector3::zeros(),
+            strength: 1.0,
             aabb,
             rotation: [0.0, 0.0, 0.0],
             scale: [1.0, 1.0, 1.0],
@@ -41,6 +72,10 @@ impl Node {
             inv_model: Matrix4::identity(),
             inv_transpose_model: Matrix3::identity(),
             active: true,
+            animation: NodeAnimation::default(),
+            time: 0.0,
+            shutter: None,
+            moving: false,
         }
     }
     //New node with parent transformations
@@ -49,6 +84,11 @@ impl Node {
         child.primitive = primitive;
         child
     }
+    //Make the node an area light of a colour scaled by strength
+    pub fn emit(&mut self, colour: Vector3<f64>, strength: f64) {
+        self.emission = colour.cast();
+        self.strength = strength as f32;
+    }
     //Toggle is a mesh is visible or not
     pub fn set_active(&mut self, active: bool) {
         self.active = active;
@@ -82,42 +122,252 @@ impl Node {
         // Recompute the model and inverse model matrices
         self.compute();
     }
+    // Record the current transformation as a keyframe at time
+    pub fn keyframe(&mut self, time: f64, interpolation: Interpolation) {
+        let animation = &mut self.animation;
+        let translation = Vector3::from_row_slice(&self.translation);
+        let rotation = Vector3::from_row_slice(&self.rotation);
+        let scale = Vector3::from_row_slice(&self.scale);
+        animation
+            .translation
+            .insert(time, translation, interpolation);
+        animation.rotation.insert(time, rotation, interpolation);
+        animation.scale.insert(time, scale, interpolation);
+        self.update_motion();
+    }
+    // Open the shutter from open to close seconds after the scene time
+    pub fn set_shutter(&mut self, open: f64, close: f64) {
+        self.shutter = Some((open, close));
+        self.update_motion();
+    }
+    // Without a shutter any keyframed node may move, with one only a node whose keyframes
+    // change somewhere between its pose and the ends of the shutter does
+    fn update_motion(&mut self) {
+        self.moving = match self.shutter {
+            Some((open, close)) => {
+                let start = self.time + open.min(0.0);
+                let end = self.time + close.max(0.0);
+                !self.animation.holds(start, end)
+            }
+            None => !self.animation.is_empty(),
+        };
+    }
+    // Move the node and its material to where their keyframes place them at time
+    pub fn pose(&mut self, time: f64) {
+        self.material.pose(time);
+        self.time = time;
+        self.update_motion();
+        if self.animation.is_empty() {
+            return;
+        }
+        if let Some(translation) = self.animation.translation.sample(time) {
+            self.translation = translation.into();
+        }
+        if let Some(rotation) = self.animation.rotation.sample(time) {
+            self.rotation = rotation.into();
+        }
+        if let Some(scale) = self.animation.scale.sample(time) {
+            self.scale = scale.into();
+        }
+        self.compute();
+    }
     // This function computes the model and inverse model matrices
     pub fn compute(&mut self) {
-        //Translation matrix
         let translation = Vector3::from_row_slice(&self.translation);
-        let translation_matrix = Matrix4::new_translation(&translation);
-        // Scale matrix
-        let scale = &Vector3::from_row_slice(&self.scale);
-        let scale_matrix = Matrix4::new_nonuniform_scaling(&scale);
-        // Rotation matrix
-        let (roll, pitch, yaw) = (self.rotation[0], self.rotation[1], self.rotation[2]);
-        let rotation_matrix =
-            Matrix4::from_euler_angles(roll.to_radians(), pitch.to_radians(), yaw.to_radians());
-        // Compute the model matrix by combining the translation, rotation, and scale matrices
-        self.model = (translation_matrix * rotation_matrix * scale_matrix).cast();
-        // Compute the inverse model matrix by inverting the model matrix
-        self.inv_model = self.model.try_inverse().unwrap();
-        self.inv_transpose_model = self.inv_model.transpose().remove_row(3).remove_column(3);
+        let rotation = Vector3::from_row_slice(&self.rotation);
+        let scale = Vector3::from_row_slice(&self.scale);
+        (self.model, self.inv_model, self.inv_transpose_model) =
+            model_matrices(&translation, &rotation, &scale);
         // Reset AABB from primitive local space before transforming to world space
         self.aabb = self.primitive.get_aabb();
         self.aabb.transform_mut(&self.model);
     }
+    // Model, inverse model and inverse transpose matrices where the keyframes place the node at time
+    pub fn transform_at(&self, time: f64) -> (Matrix4<f64>, Matrix4<f64>, Matrix3<f64>) {
+        let animation = &self.animation;
+        let sample = |curve: &Curve<Vector3<f64>>, current: &[f64; 3]| {
+            curve
+                .sample(time)
+                .unwrap_or_else(|| Vector3::from_row_slice(current))
+        };
+        let translation = sample(&animation.translation, &self.translation);
+        let rotation = sample(&animation.rotation, &self.rotation);
+        let scale = sample(&animation.scale, &self.scale);
+        model_matrices(&translation, &rotation, &scale)
+    }
+    // If the node moves while a ray is in flight, so has to be transformed to the ray's time
+    pub fn moves(&self, ray: &Ray) -> bool {
+        ray.time != 0.0 && self.moving
+    }
+    // If the ray can hit the node's box, the box of a moving node only covers where it
+    // was posed so the node itself is always tested
+    pub fn bounds_hit(&self, ray: &Ray) -> bool {
+        self.moves(ray) || self.aabb.intersect_ray(ray)
+    }
+    // World box covering every place the node is at while the shutter is open,
+    // from open to close seconds after the time it was posed at
+    pub fn swept_aabb(&self, open: f64, close: f64) -> AABB {
+        if !self.moving || close <= open {
+            return self.aabb.clone();
+        }
+        let mut swept = AABB::empty();
+        for step in 0..=MOTION_STEPS {
+            let time = open + (close - open) * step as f64 / MOTION_STEPS as f64;
+            let (model, _, _) = self.transform_at(self.time + time);
+            let mut aabb = self.primitive.get_aabb();
+            aabb.transform_mut(&model);
+            swept.join_mut(&aabb);
+        }
+        let padding = swept.size() * MOTION_PADDING;
+        swept.bln -= padding;
+        swept.trf += padding;
+        swept
+    }
     // Intersection of a ray, will convert to model coords and check
     pub fn intersect_ray(&self, ray: &Ray) -> Option<Intersection> {
-        let local_ray = ray.transform(&self.inv_model); //Transform from world coordinates
+        // Moving nodes are intersected where they are at the ray's time
+        let posed;
+        let (model, inv_model, inv_transpose_model) = match self.moves(ray) {
+            true => {
+                posed = self.transform_at(self.time + ray.time);
+                (&posed.0, &posed.1, &posed.2)
+            }
+            false => (&self.model, &self.inv_model, &self.inv_transpose_model),
+        };
+        let local_ray = ray.transform(inv_model); //Transform from world coordinates
         if let Some(mut intersect) = self.primitive.intersect_ray(&local_ray) {
             if intersect.distance < EPSILON {
                 return None;
             }
-            intersect.transform_mut(&self.model, &self.inv_transpose_model); //Transform to world coords
+            intersect.transform_mut(model, inv_transpose_model); //Transform to world coords
             intersect.distance = distance(&intersect.point, &ray.a); // use world-space ray origin
             return Some(intersect);
         }
-        return None;
+        None
+    }
+    // If the surface gives off light
+    pub fn emits(&self) -> bool {
+        self.emission != Vector3::zeros() && self.strength > 0.0
+    }
+    // Radiance the surface gives off towards a direction, nothing behind it
+    pub fn emitted(&self, normal: &Vector3<f64>, direction: &Vector3<f64>) -> Vector3<f32> {
+        match normal.dot(direction) > 0.0 {
+            true => self.emission * self.strength,
+            false => Vector3::zeros(),
+        }
+    }
+    // Point spread uniformly over the primitive where the node is at a ray's time,
+    // None if the primitive cannot be sampled
+    pub fn sample_surface(&self, time: f64, sample: (f64, f64)) -> Option<SurfaceSample> {
+        let (point, normal) = self.primitive.sample_point(sample)?;
+        let (model, inv_transpose_model) = self.model_at(time);
+        let normal = (inv_transpose_model * normal).normalize();
+        Some(SurfaceSample {
+            point: model.transform_point(&point),
+            pdf: self.area_pdf(&normal, time),
+            normal,
+        })
+    }
+    // Density over world area of `sample_surface` picking a point with a unit normal. The
+    // model matrix stretches the surface around the point by |det A| / |A^T n|.
+    pub fn area_pdf(&self, normal: &Vector3<f64>, time: f64) -> f64 {
+        let Some(area) = self.primitive.area() else {
+            return 0.0;
+        };
+        let (model, _) = self.model_at(time);
+        let linear = model.fixed_view::<3, 3>(0, 0);
+        (linear.transpose() * normal).norm() / (area * linear.determinant().abs())
+    }
+    // Density over solid angle of sampling the surface from a point and finding a point on it
+    pub fn light_pdf(&self, from: &Point3<f64>, intersect: &Intersection, time: f64) -> f64 {
+        let normal = intersect.normal.normalize();
+        let to_point = intersect.point - from;
+        let distance2 = to_point.norm_squared();
+        let cos = normal.dot(&to_point).abs() / distance2.sqrt();
+        match cos > 0.0 {
+            true => self.area_pdf(&normal, time) * distance2 / cos,
+            false => 0.0,
+        }
+    }
+    // Model and inverse transpose matrices where the node is at a ray's time
+    fn model_at(&self, time: f64) -> (Matrix4<f64>, Matrix3<f64>) {
+        match time != 0.0 && self.moving {
+            true => {
+                let (model, _, inv_transpose_model) = self.transform_at(self.time + time);
+                (model, inv_transpose_model)
+            }
+            false => (self.model, self.inv_transpose_model),
+        }
     }
     //Gets the bounding box in world coords
+    #[allow(dead_code)]
     pub fn get_world_aabb(&self) -> AABB {
-        return self.aabb.clone();
+        self.aabb.clone()
+    }
+}
+
+// Model, inverse model and inverse transpose matrices of a translation, a rotation in
+// degrees and a scale
+fn model_matrices(
+    translation: &Vector3<f64>,
+    rotation: &Vector3<f64>,
+    scale: &Vector3<f64>,
+) -> (Matrix4<f64>, Matrix4<f64>, Matrix3<f64>) {
+    //Translation matrix
+    let translation_matrix = Matrix4::new_translation(translation);
+    // Scale matrix
+    let scale_matrix = Matrix4::new_nonuniform_scaling(scale);
+    // Rotation matrix
+    let (roll, pitch, yaw) = (rotation[0], rotation[1], rotation[2]);
+    let rotation_matrix =
+        Matrix4::from_euler_angles(roll.to_radians(), pitch.to_radians(), yaw.to_radians());
+    // Compute the model matrix by combining the translation, rotation, and scale matrices
+    let model: Matrix4<f64> = (translation_matrix * rotation_matrix * scale_matrix).cast();
+    // Compute the inverse model matrix by inverting the model matrix
+    let inv_model = model.try_inverse().unwrap();
+    let inv_transpose_model = inv_model.transpose().remove_row(3).remove_column(3);
+    (model, inv_model, inv_transpose_model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unit sphere keyframed to slide from the origin to x = 2 over the first second, then
+    // held there until 3
+    fn sliding_sphere() -> Node {
+        let mut node = Node::new(Sphere::new(Point3::origin(), 1.0), Material::magenta());
+        node.keyframe(0.0, Interpolation::Linear);
+        node.translate(2.0, 0.0, 0.0);
+        for time in [1.0, 2.0, 3.0] {
+            node.keyframe(time, Interpolation::Linear);
+        }
+        node
+    }
+
+    #[test]
+    fn keyframed_nodes_move_until_a_shutter_is_set() {
+        let mut node = sliding_sphere();
+        node.pose(2.5);
+        let ray = Ray::new(Point3::new(2.0, 0.0, 5.0), -Vector3::z()).with_time(0.1);
+        assert!(node.moves(&ray));
+        node.set_shutter(0.0, 0.25);
+        assert!(!node.moves(&ray));
+        assert!(node.intersect_ray(&ray).is_some());
+    }
+
+    #[test]
+    fn nodes_move_when_the_shutter_spans_a_change() {
+        let mut node = sliding_sphere();
+        node.set_shutter(0.0, 0.25);
+        node.pose(0.5);
+        let ray = Ray::new(Point3::new(2.0, 0.0, 5.0), -Vector3::z()).with_time(0.1);
+        assert!(node.moves(&ray));
+        // Posed at 2.5 the shutter opens after the node has stopped
+        node.pose(2.5);
+        assert!(!node.moves(&ray));
+        // A shutter ahead of the pose also covers the time up to it
+        node.set_shutter(-2.0, -1.75);
+        assert!(node.moves(&ray));
     }
 }
@@ -0,0 +1,107 @@
+use exr::prelude::{
+    AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes, SmallVec, Vec2,
+    WritableImage,
+};
+use image::codecs::hdr::HdrEncoder;
+use image::{ImageError, ImageResult, Rgb, Rgba32FImage};
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// IMAGE OUTPUT -----------------------------------------------------------------
+/// Formats a framebuffer can be written as
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputFormat {
+    /// 8 bit image in any format the image crate supports (PNG, JPEG, ...)
+    Ldr,
+    /// OpenEXR, 32 bit float RGBA
+    Exr,
+    /// Radiance RGBE
+    Hdr,
+    /// Portable float map, 32 bit float RGB
+    Pfm,
+}
+
+impl OutputFormat {
+    /// Pick the format from the extension of a path, anything unknown is left to the image crate
+    pub fn from_path<P: AsRef<Path>>(path: P) -> OutputFormat {
+        let extension = path
+            .as_ref()
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("exr") => OutputFormat::Exr,
+            Some("hdr") => OutputFormat::Hdr,
+            Some("pfm") => OutputFormat::Pfm,
+            _ => OutputFormat::Ldr,
+        }
+    }
+
+    /// If the format keeps values above 1.0
+    pub fn is_hdr(&self) -> bool {
+        *self != OutputFormat::Ldr
+    }
+}
+
+/// Save an 8 bit RGBA image, the format is taken from the extension
+pub fn save_ldr<P: AsRef<Path>>(path: P, width: u32, height: u32, rgba: &[u8]) -> ImageResult<()> {
+    image::save_buffer(path, rgba, width, height, image::ColorType::Rgba8)
+}
+
+/// Save linear f32 RGBA pixels as OpenEXR
+pub fn save_exr<P: AsRef<Path>>(path: P, width: u32, height: u32, rgba: &[f32]) -> ImageResult<()> {
+    let image = Rgba32FImage::from_raw(width, height, rgba.to_vec())
+        .expect("framebuffer does not match its dimensions");
+    image.save(path)
+}
+
+/// Save linear f32 RGBA pixels as Radiance HDR, alpha is dropped
+pub fn save_hdr<P: AsRef<Path>>(path: P, width: u32, height: u32, rgba: &[f32]) -> ImageResult<()> {
+    let pixels: Vec<Rgb<f32>> = rgba
+        .chunks_exact(4)
+        .map(|pixel| Rgb([pixel[0].max(0.0), pixel[1].max(0.0), pixel[2].max(0.0)]))
+        .collect();
+    let writer = BufWriter::new(File::create(path)?);
+    HdrEncoder::new(writer).encode(&pixels, width as usize, height as usize)
+}
+
+/// Save linear f32 RGBA pixels as a little endian portable float map, alpha is dropped
+pub fn save_pfm<P: AsRef<Path>>(path: P, width: u32, height: u32, rgba: &[f32]) -> ImageResult<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    // A negative scale marks the data as little endian
+    write!(writer, "PF\n{width} {height}\n-1.0\n")?;
+    // Rows are stored from the bottom up
+    for row in rgba.chunks_exact(width as usize * 4).rev() {
+        for pixel in row.chunks_exact(4) {
+            for channel in &pixel[..3] {
+                writer.write_all(&channel.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush().map_err(ImageError::IoError)
+}
+
+/// Save named f32 channels into one OpenEXR image, e.g. "R", "G", "B" and "normal.X".
+/// Each channel holds one value per pixel, row by row from the top left.
+pub fn save_exr_channels<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    channels: Vec<(String, Vec<f32>)>,
+) -> ImageResult<()> {
+    let channels: SmallVec<[AnyChannel<FlatSamples>; 4]> = channels
+        .into_iter()
+        .map(|(name, samples)| AnyChannel::new(name.as_str(), FlatSamples::F32(samples)))
+        .collect();
+    let layer = Layer::new(
+        Vec2(width as usize, height as usize),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels),
+    );
+    Image::from_layer(layer)
+        .write()
+        .to_file(path)
+        .map_err(|e| ImageError::IoError(std::io::Error::other(e)))
+}
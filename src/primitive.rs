@@ -1,12 +1,14 @@
 use crate::{
     bvh::AABB,
     ray::{Intersection, Ray},
+    sampler::{concentric_disk, tangents, uniform_sphere},
     {EPSILON, INFINITY},
 };
 
 #[allow(dead_code)]
 use nalgebra::{distance, Point3, Vector3};
 use roots::{find_roots_quadratic, find_roots_quartic, Roots};
+use std::f64::consts::PI;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::sync::Arc;
@@ -14,6 +16,14 @@ use std::sync::Arc;
 pub trait Primitive: Send + Sync {
     fn intersect_ray(&self, ray: &Ray) -> Option<Intersection>;
     fn get_aabb(&self) -> AABB;
+    /// Surface area in model space, None for surfaces that cannot be sampled as area lights
+    fn area(&self) -> Option<f64> {
+        None
+    }
+    /// Point spread uniformly over the surface by two numbers in [0, 1), with its unit normal
+    fn sample_point(&self, _sample: (f64, f64)) -> Option<(Point3<f64>, Vector3<f64>)> {
+        None
+    }
 }
 
 // SPHERE -----------------------------------------------------------------
@@ -75,6 +85,15 @@ impl Primitive for Sphere {
         let trf = self.position + radius_vec;
         AABB::new(bln, trf)
     }
+
+    fn area(&self) -> Option<f64> {
+        Some(4.0 * PI * self.radius * self.radius)
+    }
+
+    fn sample_point(&self, sample: (f64, f64)) -> Option<(Point3<f64>, Vector3<f64>)> {
+        let normal = uniform_sphere(sample);
+        Some((self.position + normal * self.radius, normal))
+    }
 }
 
 // CIRCLE -----------------------------------------------------------------
@@ -131,14 +150,12 @@ impl Primitive for Circle {
         //Distance to center of circle
         let distance = distance(&intersect, &self.position).abs();
         match distance <= self.radius {
-            true => {
-                return Some(Intersection {
-                    point: intersect,
-                    normal: self.normal,
-                    distance: t,
-                })
-            }
-            false => return None,
+            true => Some(Intersection {
+                point: intersect,
+                normal: self.normal,
+                distance: t,
+            }),
+            false => None,
         }
     }
 
@@ -150,6 +167,17 @@ impl Primitive for Circle {
         let trf = position + radius_vec;
         AABB::new(bln, trf)
     }
+
+    fn area(&self) -> Option<f64> {
+        Some(PI * self.radius * self.radius)
+    }
+
+    fn sample_point(&self, sample: (f64, f64)) -> Option<(Point3<f64>, Vector3<f64>)> {
+        let (tangent, bitangent) = tangents(&self.normal);
+        let (x, y) = concentric_disk(sample);
+        let offset = (tangent * x + bitangent * y) * self.radius;
+        Some((self.position + offset, self.normal))
+    }
 }
 
 // CYLINDER -----------------------------------------------------------------
@@ -216,7 +244,7 @@ impl Primitive for Cylinder {
                     let normal = Vector3::new(2.0 * intersect.x, 0.0, 2.0 * intersect.z);
                     Some(Intersection {
                         point: intersect,
-                        normal: normal,
+                        normal,
                         distance: t,
                     })
                 } else {
@@ -422,6 +450,16 @@ impl Primitive for RectangleXY {
         let tr = self.tr + Vector3::new(0.0, 0.0, 0.1);
         AABB::new(bl, tr)
     }
+
+    fn area(&self) -> Option<f64> {
+        Some((self.tr.x - self.bl.x) * (self.tr.y - self.bl.y))
+    }
+
+    fn sample_point(&self, (u, v): (f64, f64)) -> Option<(Point3<f64>, Vector3<f64>)> {
+        let x = self.bl.x + u * (self.tr.x - self.bl.x);
+        let y = self.bl.y + v * (self.tr.y - self.bl.y);
+        Some((Point3::new(x, y, self.bl.z), Vector3::z()))
+    }
 }
 
 // Cube -----------------------------------------------------------------
@@ -492,10 +530,12 @@ impl Primitive for Cube {
                 Vector3::new(0.0, 1.0, 0.0),
                 Vector3::new(0.0, 0.0, 1.0),
             ];
-            let min_idx = diffs.iter()
+            let min_idx = diffs
+                .iter()
                 .enumerate()
                 .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
-                .unwrap().0;
+                .unwrap()
+                .0;
             let normal = normals[min_idx];
 
             Some(Intersection {
@@ -511,6 +551,43 @@ impl Primitive for Cube {
     fn get_aabb(&self) -> AABB {
         AABB::new(self.bln, self.trf)
     }
+
+    fn area(&self) -> Option<f64> {
+        let size = self.trf - self.bln;
+        Some(2.0 * (size.x * size.y + size.y * size.z + size.z * size.x))
+    }
+
+    fn sample_point(&self, (u, v): (f64, f64)) -> Option<(Point3<f64>, Vector3<f64>)> {
+        // Pick a pair of opposite faces by their area, then one of the two with what is left of u
+        let size = self.trf - self.bln;
+        let faces = [size.y * size.z, size.z * size.x, size.x * size.y];
+        let mut u = u * faces.iter().sum::<f64>();
+        let mut axis = 0;
+        while axis < 2 && u >= faces[axis] {
+            u -= faces[axis];
+            axis += 1;
+        }
+        let u = (u / faces[axis]).min(1.0);
+        let far = u >= 0.5;
+        let u = match far {
+            true => 2.0 * u - 1.0,
+            false => 2.0 * u,
+        };
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut point = self.bln;
+        point[axis] = match far {
+            true => self.trf[axis],
+            false => self.bln[axis],
+        };
+        point[a] += u * size[a];
+        point[b] += v * size[b];
+        let mut normal = Vector3::zeros();
+        normal[axis] = match far {
+            true => 1.0,
+            false => -1.0,
+        };
+        Some((point, normal))
+    }
 }
 
 // TRIANGLE -----------------------------------------------------------------
@@ -557,7 +634,7 @@ impl Primitive for Triangle {
         let s = ray.a - u;
         let p = inv_det * s.dot(&b_cross_e2);
 
-        if p < 0.0 || p > 1.0 {
+        if !(0.0..=1.0).contains(&p) {
             return None;
         }
 
@@ -590,6 +667,18 @@ impl Primitive for Triangle {
         let trf = u.sup(&v).sup(&w);
         AABB::new(bln, trf)
     }
+
+    fn area(&self) -> Option<f64> {
+        Some((self.v - self.u).cross(&(self.w - self.u)).norm() / 2.0)
+    }
+
+    fn sample_point(&self, (a, b): (f64, f64)) -> Option<(Point3<f64>, Vector3<f64>)> {
+        // Uniform barycentric coordinates, folded in by the square root
+        let root = a.sqrt();
+        let (p, q) = (1.0 - root, b * root);
+        let point = self.u + (self.v - self.u) * p + (self.w - self.u) * q;
+        Some((point, self.normal))
+    }
 }
 
 // MESH -----------------------------------------------------------------
@@ -626,45 +715,40 @@ impl Mesh {
         let file = File::open(filename).expect("Failed to open file");
         let reader = BufReader::new(file);
 
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                let mut parts = line.split_whitespace();
-                if let Some(keyword) = parts.next() {
-                    match keyword {
-                        "v" => {
-                            // Parse vertex coordinates
-                            if let (Some(x_str), Some(y_str), Some(z_str)) =
-                                (parts.next(), parts.next(), parts.next())
-                            {
-                                let x: f64 = x_str.parse().expect("Failed to parse vertex X");
-                                let y: f64 = y_str.parse().expect("Failed to parse vertex Y");
-                                let z: f64 = z_str.parse().expect("Failed to parse vertex Z");
-                                vertices.push(Point3::new(x, y, z));
-                            }
+        for line in reader.lines().map_while(Result::ok) {
+            let mut parts = line.split_whitespace();
+            if let Some(keyword) = parts.next() {
+                match keyword {
+                    "v" => {
+                        // Parse vertex coordinates
+                        if let (Some(x_str), Some(y_str), Some(z_str)) =
+                            (parts.next(), parts.next(), parts.next())
+                        {
+                            let x: f64 = x_str.parse().expect("Failed to parse vertex X");
+                            let y: f64 = y_str.parse().expect("Failed to parse vertex Y");
+                            let z: f64 = z_str.parse().expect("Failed to parse vertex Z");
+                            vertices.push(Point3::new(x, y, z));
                         }
-                        "f" => {
-                            // Parse face indices
-                            if let (Some(v1_str), Some(v2_str), Some(v3_str)) =
-                                (parts.next(), parts.next(), parts.next())
-                            {
-                                let v1: usize =
-                                    v1_str.parse().expect("Failed to parse vertex index");
-                                let v2: usize =
-                                    v2_str.parse().expect("Failed to parse vertex index");
-                                let v3: usize =
-                                    v3_str.parse().expect("Failed to parse vertex index");
-                                // Indices in OBJ files are 1-based, so subtract 1 to convert to 0-based.
-                                let u = vertices[v1 - 1];
-                                let v = vertices[v2 - 1];
-                                let w = vertices[v3 - 1];
-                                let uv = v - u;
-                                let uw = w - u;
-                                let normal = uw.cross(&uv).normalize();
-                                triangles.push(Triangle { u, v, w, normal });
-                            }
+                    }
+                    "f" => {
+                        // Parse face indices
+                        if let (Some(v1_str), Some(v2_str), Some(v3_str)) =
+                            (parts.next(), parts.next(), parts.next())
+                        {
+                            let v1: usize = v1_str.parse().expect("Failed to parse vertex index");
+                            let v2: usize = v2_str.parse().expect("Failed to parse vertex index");
+                            let v3: usize = v3_str.parse().expect("Failed to parse vertex index");
+                            // Indices in OBJ files are 1-based, so subtract 1 to convert to 0-based.
+                            let u = vertices[v1 - 1];
+                            let v = vertices[v2 - 1];
+                            let w = vertices[v3 - 1];
+                            let uv = v - u;
+                            let uw = w - u;
+                            let normal = uw.cross(&uv).normalize();
+                            triangles.push(Triangle { u, v, w, normal });
                         }
-                        _ => {}
                     }
+                    _ => {}
                 }
             }
         }
@@ -794,10 +878,7 @@ impl Primitive for Torus {
             Roots::Four(arr) => smallest_non_zero(&arr),
         };
 
-        let t = match t {
-            Some(t) => t,
-            None => return None,
-        };
+        let t = t?;
 
         //Now we have the smallest non-zero t
         let point = ray.at_t(t);
@@ -945,16 +1026,13 @@ impl Primitive for CrossCap {
             - b * f.powf(2.0);
         let t4 = b.powf(2.0) * d.powf(2.0) + b.powf(2.0) * f.powf(2.0) - f.powf(4.0);
 
-        let t = match match find_roots_quartic(t4, t3, t2, t1, t0) {
+        let t = match find_roots_quartic(t4, t3, t2, t1, t0) {
             Roots::No(arr) => smallest_non_zero(&arr),
             Roots::One(arr) => smallest_non_zero(&arr),
             Roots::Two(arr) => smallest_non_zero(&arr),
             Roots::Three(arr) => smallest_non_zero(&arr),
             Roots::Four(arr) => smallest_non_zero(&arr),
-        } {
-            Some(t) => t,
-            None => return None,
-        };
+        }?;
 
         let point = ray.at_t(t);
         let (x, y, z) = (point.x, point.y, point.z);
@@ -1059,16 +1137,13 @@ impl Primitive for CrossCap2 {
             + b * b * d * d / q
             + d * d * d * d / q
             + d * d * f * f / q;
-        let t = match match find_roots_quartic(t4, t3, t2, t1, t0) {
+        let t = match find_roots_quartic(t4, t3, t2, t1, t0) {
             Roots::No(arr) => smallest_non_zero(&arr),
             Roots::One(arr) => smallest_non_zero(&arr),
             Roots::Two(arr) => smallest_non_zero(&arr),
             Roots::Three(arr) => smallest_non_zero(&arr),
             Roots::Four(arr) => smallest_non_zero(&arr),
-        } {
-            Some(t) => t,
-            None => return None,
-        };
+        }?;
 
         let point = ray.at_t(t);
         let (x, y, z) = (point.x, point.y, point.z);
@@ -1138,16 +1213,13 @@ impl Primitive for Steiner {
             - b * d * f;
         let t4 = b.powf(2.0) * d.powf(2.0) - b.powf(2.0) * f.powf(2.0) + d.powf(2.0) * f.powf(2.0);
 
-        let t = match match find_roots_quartic(t4, t3, t2, t1, t0) {
+        let t = match find_roots_quartic(t4, t3, t2, t1, t0) {
             Roots::No(arr) => smallest_non_zero(&arr),
             Roots::One(arr) => smallest_non_zero(&arr),
             Roots::Two(arr) => smallest_non_zero(&arr),
             Roots::Three(arr) => smallest_non_zero(&arr),
             Roots::Four(arr) => smallest_non_zero(&arr),
-        } {
-            Some(t) => t,
-            None => return None,
-        };
+        }?;
 
         let point = ray.at_t(t);
         let (x, y, z) = (point.x, point.y, point.z);
@@ -1226,16 +1298,13 @@ impl Primitive for Steiner2 {
             - b * f.powi(2);
         let t4 = b.powi(2) * d.powi(2) + b.powi(2) * f.powi(2) - f.powi(4);
 
-        let t = match match find_roots_quartic(t4, t3, t2, t1, t0) {
+        let t = match find_roots_quartic(t4, t3, t2, t1, t0) {
             Roots::No(arr) => smallest_non_zero(&arr),
             Roots::One(arr) => smallest_non_zero(&arr),
             Roots::Two(arr) => smallest_non_zero(&arr),
             Roots::Three(arr) => smallest_non_zero(&arr),
             Roots::Four(arr) => smallest_non_zero(&arr),
-        } {
-            Some(t) => t,
-            None => return None,
-        };
+        }?;
 
         let point = ray.at_t(t);
         let (x, y, z) = (point.x, point.y, point.z);
@@ -1333,16 +1402,13 @@ impl Primitive for Roman {
             + 2.0 * b.powf(2.0) * f.powf(2.0)
             + 2.0 * d.powf(2.0) * f.powf(2.0)
             + f.powf(4.0);
-        let t = match match find_roots_quartic(t4, t3, t2, t1, t0) {
+        let t = match find_roots_quartic(t4, t3, t2, t1, t0) {
             Roots::No(arr) => smallest_non_zero(&arr),
             Roots::One(arr) => smallest_non_zero(&arr),
             Roots::Two(arr) => smallest_non_zero(&arr),
             Roots::Three(arr) => smallest_non_zero(&arr),
             Roots::Four(arr) => smallest_non_zero(&arr),
-        } {
-            Some(t) => t,
-            None => return None,
-        };
+        }?;
 
         let point = ray.at_t(t);
         let (x, y, z) = (point.x, point.y, point.z);
@@ -1366,10 +1432,120 @@ impl Primitive for Roman {
 }
 
 fn smallest_non_zero(arr: &[f64]) -> Option<f64> {
-    for &num in arr {
-        if num >= 0.0 {
-            return Some(num);
+    arr.iter().find(|&&num| num >= 0.0).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    const SAMPLES: usize = 20_000;
+
+    // A primitive by name, with its analytic area and the centre of its surface
+    type Sampled = (&'static str, Arc<dyn Primitive>, f64, Point3<f64>);
+
+    // Primitives that can be sampled
+    fn sampled_primitives() -> Vec<Sampled> {
+        let centre = Point3::new(0.5, -1.0, 2.0);
+        let (u, v, w) = (
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(2.0, 0.0, 1.0),
+            Point3::new(0.5, 1.5, 0.0),
+        );
+        let triangle_area = (v - u).cross(&(w - u)).norm() / 2.0;
+        vec![
+            ("sphere", Sphere::new(centre, 0.7), 4.0 * PI * 0.49, centre),
+            (
+                "circle",
+                Circle::new(centre, 0.7, Vector3::new(1.0, 2.0, -0.5)),
+                PI * 0.49,
+                centre,
+            ),
+            (
+                "rectangle",
+                RectangleXY::new(Point3::new(-1.0, 0.0, 0.5), Point3::new(2.0, 0.5, 0.5)),
+                1.5,
+                Point3::new(0.5, 0.25, 0.5),
+            ),
+            (
+                "cube",
+                Cube::new(Point3::new(-1.0, 0.0, 1.0), Point3::new(1.0, 0.5, 4.0)),
+                2.0 * (2.0 * 0.5 + 0.5 * 3.0 + 3.0 * 2.0),
+                Point3::new(0.0, 0.25, 2.5),
+            ),
+            (
+                "triangle",
+                Triangle::new(u, v, w),
+                triangle_area,
+                Point3::from((u.coords + v.coords + w.coords) / 3.0),
+            ),
+        ]
+    }
+
+    #[test]
+    fn area_is_analytic() {
+        for (name, primitive, area, _) in sampled_primitives() {
+            let actual = primitive.area().unwrap();
+            assert!(
+                (actual - area).abs() < 1e-9,
+                "{name}: {actual} is not {area}"
+            );
+        }
+        assert!(Torus::new(1.0, 0.25).area().is_none());
+    }
+
+    // A ray aimed back along the normal of a sampled point hits the primitive there, with the
+    // same normal
+    #[test]
+    fn sampled_points_lie_on_the_surface() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        for (name, primitive, _, _) in sampled_primitives() {
+            for _ in 0..1000 {
+                let (point, normal) = primitive.sample_point((rng.gen(), rng.gen())).unwrap();
+                assert!(
+                    (normal.norm() - 1.0).abs() < 1e-9,
+                    "{name}: normal is not unit"
+                );
+                let ray = Ray::new(point + normal * 0.01, -normal);
+                let intersect = primitive
+                    .intersect_ray(&ray)
+                    .unwrap_or_else(|| panic!("{name}: missed {point:?}"));
+                assert!(
+                    (intersect.point - point).norm() < 1e-6,
+                    "{name}: {point:?} is off the surface"
+                );
+                let cos = intersect.normal.normalize().dot(&normal).abs();
+                assert!(cos > 1.0 - 1e-6, "{name}: normal of {point:?} is off");
+            }
+        }
+    }
+
+    // Points spread evenly over a surface average out at its centre
+    #[test]
+    fn sampled_points_are_uniform() {
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        for (name, primitive, _, centre) in sampled_primitives() {
+            let mean = (0..SAMPLES)
+                .map(|_| primitive.sample_point((rng.gen(), rng.gen())).unwrap().0)
+                .fold(Vector3::zeros(), |sum, point| sum + point.coords)
+                / SAMPLES as f64;
+            assert!(
+                (mean - centre.coords).norm() < 0.02,
+                "{name}: points centre on {mean:?}, not {centre:?}"
+            );
+        }
+        // Each pair of cube faces is picked in proportion to its area
+        let cube = Cube::new(Point3::new(-1.0, 0.0, 1.0), Point3::new(1.0, 0.5, 4.0));
+        let mut faces = [0.0; 3];
+        for _ in 0..SAMPLES {
+            let (_, normal) = cube.sample_point((rng.gen(), rng.gen())).unwrap();
+            faces[normal.iamax()] += 1.0 / SAMPLES as f64;
+        }
+        let expected = [0.5 * 3.0, 3.0 * 2.0, 2.0 * 0.5].map(|face| face / 8.5);
+        for axis in 0..3 {
+            assert!((faces[axis] - expected[axis]).abs() < 0.01);
         }
     }
-    None
 }
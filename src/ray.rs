@@ -1,17 +1,18 @@
-use crate::{bvh::BVH, light::Light, node::Node, scene::Scene, state::RaytracingOption, EPSILON};
+use crate::{
+    bsdf::refract,
+    bvh::BVH,
+    emitter::Emitter,
+    inspect::{HitRecord, LightRecord, RayKind, RayRecord, ShadowRecord},
+    light::Light,
+    material::Material,
+    node::Node,
+    renderer::RaytracingOption,
+    sampler::{uniform_sphere, Sampler},
+    scene::Scene,
+    SURFACE_OFFSET,
+};
 use nalgebra::{distance, Matrix3, Matrix4, Point3, Vector3};
-use rand;
-
-fn random_vec() -> Vector3<f64> {
-    Vector3::new(
-        rand::random::<f64>() * 2.0 - 1.0,
-        rand::random::<f64>() * 2.0 - 1.0,
-        rand::random::<f64>() * 2.0 - 1.0,
-    )
-}
-fn random_unit_vec() -> Vector3<f64> {
-    random_vec().normalize()
-}
+use std::f64::consts::PI;
 
 // INTERSECTION -----------------------------------------------------------------
 pub struct Intersection {
@@ -21,6 +22,7 @@ pub struct Intersection {
     pub distance: f64,
 }
 //Intersection point including point and normal
+#[allow(dead_code)]
 impl Intersection {
     pub fn transform(&mut self, trans: &Matrix4<f64>, inv_trans: &Matrix4<f64>) -> Intersection {
         Intersection {
@@ -40,6 +42,8 @@ impl Intersection {
 pub struct Ray {
     pub a: Point3<f64>,
     pub b: Vector3<f64>,
+    // Seconds after the frame time the ray is traced at, somewhere within the camera shutter
+    pub time: f64,
 }
 
 #[allow(dead_code)]
@@ -49,13 +53,19 @@ impl Ray {
         Ray {
             a,
             b: b.normalize(),
+            time: 0.0,
         }
     }
+    // The same ray traced at a time within the shutter
+    pub fn with_time(mut self, time: f64) -> Ray {
+        self.time = time;
+        self
+    }
     // The starting point is the origin and the direction is negative z-axis
     pub fn unit() -> Ray {
         let a = Point3::origin();
         let b = -Vector3::z();
-        Ray { a, b }
+        Ray { a, b, time: 0.0 }
     }
     //Return the point at distance t along the ray
     pub fn at_t(&self, t: f64) -> Point3<f64> {
@@ -66,6 +76,7 @@ impl Ray {
         Ray {
             a: trans.transform_point(&self.a),
             b: trans.transform_vector(&self.b),
+            time: self.time,
         }
     }
     //Transform mutably
@@ -76,12 +87,12 @@ impl Ray {
     //This function will determine if the ray hits an object in the scene
     //This is not optimised as it does not include bounding boxes
     pub fn hit_scene(ray: &Ray, scene: &Scene) -> bool {
-        for (_, node) in &scene.nodes {
+        for node in scene.nodes.values() {
             if !node.active {
                 continue;
             }
             // Transform ray into local model cordinates
-            if node.intersect_ray(&ray).is_some() {
+            if node.intersect_ray(ray).is_some() {
                 return true;
             }
         }
@@ -89,22 +100,19 @@ impl Ray {
     }
     //This function find the closest intersection point of a ray with an object in the scene
     //Also not optimised, as it does not include bounding boxes
-    pub fn closest_intersect<'a>(
-        ray: &'a Ray,
-        scene: &'a Scene,
-    ) -> Option<(&'a Node, Intersection)> {
+    pub fn closest_intersect<'a>(ray: &Ray, scene: &'a Scene) -> Option<(&'a Node, Intersection)> {
         let mut closest_distance = f64::MAX;
         let mut closest_intersect: Option<(&Node, Intersection)> = None;
         let ray_a = ray.a;
-        for (_, node) in &scene.nodes {
+        for node in scene.nodes.values() {
             //position of ray in world coords
             if !node.active {
                 continue;
             }
 
-            if node.aabb.intersect_ray(&ray) {
+            if node.bounds_hit(ray) {
                 //Check node intersection
-                if let Some(intersect) = node.intersect_ray(&ray) {
+                if let Some(intersect) = node.intersect_ray(ray) {
                     // Check for closest distance by converting to world coords
                     let distance = distance(&ray_a, &intersect.point);
                     if distance < closest_distance {
@@ -116,89 +124,186 @@ impl Ray {
         }
         closest_intersect
     }
-    // This function takes a scene and returns the color of the point where the ray intersects the scene
+    // This function takes a scene and returns the color of the point where the ray intersects the scene.
+    // With a record the hit and every ray traced from it are written into it.
+    #[allow(clippy::too_many_arguments)]
     pub fn shade_ray(
         &self,
         scene: &Scene,
+        areas: &[Emitter],
         depth: u8,
         options: &RaytracingOption,
         sbvh: &Option<BVH>,
+        sampler: &mut Sampler,
+        mut record: Option<&mut RayRecord>,
     ) -> Option<Vector3<f32>> {
         //If we have exceeded depth then return
         if depth == options.ray_depth {
             return None;
         }
+        let colour = self.first_hit(scene, sbvh).map(|(node, intersect)| {
+            let hit = record
+                .as_deref_mut()
+                .map(|record| record.hit.insert(HitRecord::new(node, &intersect)));
+            Ray::phong_shade_point(
+                scene, areas, self, node, &intersect, depth, options, sbvh, sampler, hit,
+            )
+        });
+        if let Some(record) = record {
+            record.colour = colour;
+        }
+        colour
+    }
+
+    // Closest node the ray hits, through the bvh if there is one
+    pub fn first_hit<'a>(
+        &self,
+        scene: &'a Scene,
+        sbvh: &'a Option<BVH>,
+    ) -> Option<(&'a Node, Intersection)> {
         match sbvh {
             //We have a bvh so use bvh traversal
-            Some(bvh) => {
-                //Intersect the scene with the bvh
-                if let Some((node, intersect)) = bvh.traverse(self, 0) {
-                    return Some(Ray::phong_shade_point(
-                        &scene, &self, &node, &intersect, depth, options, sbvh,
-                    ));
-                }
-                return None;
-            }
-            //We dont have a bvh so use generic algorithm
-            None => {
-                //No BVH given so intersect normally
-                match Ray::closest_intersect(self, scene) {
-                    Some((node, intersect)) => {
-                        Some(Ray::phong_shade_point(
-                            &scene, &self, &node, &intersect, depth, options, sbvh,
-                        )) // If there is an intersection, shade it
-                    }
-                    None => None, // If there is no intersection, return None
-                }
-            }
+            Some(bvh) => bvh.traverse(self, 0),
+            //No BVH given so intersect normally
+            None => Ray::closest_intersect(self, scene),
         }
     }
 
     // Function to shade a point in the scene using Phong shading model
+    #[allow(clippy::too_many_arguments)]
     pub fn phong_shade_point(
         scene: &Scene,
+        areas: &[Emitter],
         ray: &Ray,
         node: &Node,
         intersect: &Intersection,
         depth: u8,
         options: &RaytracingOption,
         bvh: &Option<BVH>,
+        sampler: &mut Sampler,
+        mut record: Option<&mut HitRecord>,
     ) -> Vector3<f32> {
         let normal = &intersect.normal;
         let point = &intersect.point;
         let incidence = &ray.b;
         let material = &node.material;
 
+        // A transparent material is hit from inside when the ray leaves along the normal. The
+        // lights and the diffuse bounces are outside, only what it reflects and refracts is seen.
+        let inside = material.transmits() && incidence.dot(normal) > 0.0;
+
         let mut colour = Vector3::zeros();
 
+        // Rays past the maximum depth are never traced, so are left out of the record
+        let record_rays = record.is_some() && depth + 1 < options.ray_depth;
+
+        // Share of the light reaching a transparent material that it reflects rather than
+        // refracts, 1 past the critical angle
+        let outward = normal.normalize();
+        let fresnel = match options.refract && material.transmits() {
+            true => options
+                .fresnel
+                .reflectance(-incidence.dot(&outward), material.ior as f64)
+                as f32,
+            false => 0.0,
+        };
+
         // Reflection is view-dependent, not light-dependent — compute once
         let mut reflect = Vector3::zeros();
         if options.reflect {
-            let reflect_dir = incidence - 2.0 * incidence.dot(&normal) * normal;
-            let reflect_ray = Ray::new(*point, reflect_dir);
-            if let Some(col) = reflect_ray.shade_ray(scene, depth + 1, options, bvh) {
-                reflect += col.component_mul(&material.kr)
+            let reflect_dir = incidence - 2.0 * incidence.dot(normal) * normal;
+            let reflect_ray = Ray::new(*point, reflect_dir).with_time(ray.time);
+            let mut child =
+                record_rays.then(|| RayRecord::new(RayKind::Reflection, &reflect_ray, depth + 1));
+            if let Some(col) = reflect_ray.shade_ray(
+                scene,
+                areas,
+                depth + 1,
+                options,
+                bvh,
+                sampler,
+                child.as_mut(),
+            ) {
+                reflect += col.component_mul(&(material.kr + material.kt * fresnel))
+            }
+            if let Some(record) = record.as_deref_mut() {
+                record.reflection = reflect;
+                record.rays.extend(child);
+            }
+        }
+
+        // Refraction through a transparent material by Snell's law, none on total internal
+        // reflection
+        let mut transmit = Vector3::zeros();
+        let refraction = match options.refract && material.transmits() && fresnel < 1.0 {
+            true => refract(&-incidence, &outward, material.ior as f64),
+            false => None,
+        };
+        if let Some((refract_dir, _)) = refraction {
+            // Start just past the surface, on the side the ray carries on into
+            let offset = SURFACE_OFFSET.copysign(outward.dot(&refract_dir));
+            let refract_ray = Ray::new(point + outward * offset, refract_dir).with_time(ray.time);
+            let mut child =
+                record_rays.then(|| RayRecord::new(RayKind::Refraction, &refract_ray, depth + 1));
+            if let Some(col) = refract_ray.shade_ray(
+                scene,
+                areas,
+                depth + 1,
+                options,
+                bvh,
+                sampler,
+                child.as_mut(),
+            ) {
+                transmit += col.component_mul(&material.kt) * (1.0 - fresnel)
+            }
+            if let Some(record) = record.as_deref_mut() {
+                record.refraction = transmit;
+                record.rays.extend(child);
             }
         }
 
         // Indirect diffuse (global illumination samples) — compute once
         let mut indirect = Vector3::zeros();
-        if options.diffuse {
+        if options.diffuse && !inside {
             for _ in 0..options.diffuse_rays {
-                let diffuse_dir = random_unit_vec();
-                let diffuse_ray = Ray::new(point.clone(), diffuse_dir + normal);
-                if let Some(col) = diffuse_ray.shade_ray(scene, depth + 1, options, bvh) {
+                let diffuse_dir = uniform_sphere(sampler.next_2d());
+                let diffuse_ray = Ray::new(*point, diffuse_dir + normal).with_time(ray.time);
+                let mut child =
+                    record_rays.then(|| RayRecord::new(RayKind::Diffuse, &diffuse_ray, depth + 1));
+                if let Some(col) = diffuse_ray.shade_ray(
+                    scene,
+                    areas,
+                    depth + 1,
+                    options,
+                    bvh,
+                    sampler,
+                    child.as_mut(),
+                ) {
                     indirect += col * options.diffuse_coefficient;
                 }
+                if let Some(record) = record.as_deref_mut() {
+                    record.rays.extend(child);
+                }
+            }
+            if let Some(record) = record.as_deref_mut() {
+                record.indirect = indirect;
             }
         }
 
-        for (_, light) in &scene.lights {
-            if !light.active {
+        for (label, light) in &scene.lights {
+            if !light.active || inside {
                 continue;
             }
             if light.ambient {
                 colour += light.colour;
+                if let Some(record) = record.as_deref_mut() {
+                    record.lights.push(LightRecord {
+                        label: label.clone(),
+                        ambient: true,
+                        shadow: None,
+                        contribution: light.colour,
+                    });
+                }
                 continue;
             }
 
@@ -208,124 +313,186 @@ impl Ray {
             let to_light = to_light.normalize();
 
             //Niave Shadows
+            let mut shadow = None;
             if options.shadows {
-                let to_light_ray = Ray::new(*point, to_light);
-                if to_light_ray.light_blocked(scene, light, bvh) {
+                let to_light_ray = Ray::new(*point, to_light).with_time(ray.time);
+                shadow = record
+                    .is_some()
+                    .then(|| ShadowRecord::new(&to_light_ray, light_distance as f64));
+                if to_light_ray.light_blocked(scene, light, bvh, shadow.as_mut()) {
+                    if let Some(record) = record.as_deref_mut() {
+                        record.lights.push(LightRecord {
+                            label: label.clone(),
+                            ambient: false,
+                            shadow,
+                            contribution: Vector3::zeros(),
+                        });
+                    }
                     continue;
                 }
             }
 
-            let n_dot_l = normal.dot(&to_light).max(0.0) as f32;
-
-            //Direct diffuse component (Lambertian)
-            let mut diffuse = Vector3::zeros();
-            if options.diffuse {
-                diffuse += material.kd * n_dot_l;
-            }
-
-            //Specular component
-            let mut specular = Vector3::zeros();
-            if options.specular {
-                if n_dot_l > 0.0 {
-                    let h = (to_light - incidence).normalize();
-                    let n_dot_h = normal.dot(&h).max(0.0) as f32;
-                    specular = material.ks * n_dot_h.powf(material.shininess);
-                }
-            }
+            let reflected = Ray::phong(material, normal, &to_light, incidence, options);
 
             //Falloff
             let mut falloff = 1.0;
             if options.falloff {
-                falloff = 1.0
-                    / ((1.0 + light.falloff[0])
-                        + light.falloff[1] * light_distance
-                        + light.falloff[2] * light_distance * light_distance);
+                falloff = light.attenuation(light_distance);
+            }
+
+            let intensity = light.colour.component_mul(&reflected) * falloff;
+            colour += &intensity;
+            if let Some(record) = record.as_deref_mut() {
+                record.lights.push(LightRecord {
+                    label: label.clone(),
+                    ambient: false,
+                    shadow,
+                    contribution: intensity,
+                });
             }
+        }
 
-            let intensity = light.colour.component_mul(&(diffuse + specular)) * falloff;
+        // Emissive nodes light the point from one point picked on each. It stands in for a
+        // point light as bright as the whole surface seen from here.
+        let areas: &[Emitter] = match inside {
+            true => &[],
+            false => areas,
+        };
+        for emitter in areas {
+            let sample = sampler.next_2d();
+            let Some(light) = emitter.sample(point, ray.time, options.falloff, sample) else {
+                continue;
+            };
+            let mut shadow = None;
+            if options.shadows {
+                let to_light_ray = Ray::new(*point, light.direction).with_time(ray.time);
+                shadow = record
+                    .is_some()
+                    .then(|| ShadowRecord::new(&to_light_ray, light.distance));
+                if to_light_ray.blocked(scene, light.distance, bvh, shadow.as_mut()) {
+                    if let Some(record) = record.as_deref_mut() {
+                        record.lights.push(LightRecord {
+                            label: emitter.label().to_string(),
+                            ambient: false,
+                            shadow,
+                            contribution: Vector3::zeros(),
+                        });
+                    }
+                    continue;
+                }
+            }
+            let reflected = Ray::phong(material, normal, &light.direction, incidence, options);
+            let intensity = light.radiance.component_mul(&reflected) / (PI * light.pdf) as f32;
             colour += &intensity;
+            if let Some(record) = record.as_deref_mut() {
+                record.lights.push(LightRecord {
+                    label: emitter.label().to_string(),
+                    ambient: false,
+                    shadow,
+                    contribution: intensity,
+                });
+            }
         }
 
         // Add light-independent terms
-        colour += reflect + indirect;
+        colour += reflect + transmit + indirect;
+
+        // Emissive nodes are seen glowing
+        if node.emits() {
+            colour += node.emitted(normal, &-incidence);
+        }
+
+        // Light from inside crossed the material and was absorbed on the way
+        if inside {
+            colour = colour.component_mul(&material.transmittance(intersect.distance));
+        }
 
         colour
     }
 
-    pub fn light_blocked(&self, scene: &Scene, light: &Light, bvh: &Option<BVH>) -> bool {
-        let light_distance = distance(&self.a, &light.position);
-        match bvh {
-            Some(bvh) => {
-                //We have a bvh so use bvh traversal
-                if let Some((_, intersect)) = bvh.traverse(self, 0) {
-                    return intersect.distance < light_distance;
-                }
-            }
-            None => {
-                for (_, node) in &scene.nodes {
-                    if !node.active {
-                        continue;
-                    }
-                    if node.aabb.intersect_ray(self) {
-                        match node.intersect_ray(self) {
-                            Some(intersect) => {
-                                if intersect.distance < light_distance {
-                                    return true;
-                                }
-                            }
-                            None => continue,
-                        }
-                    }
-                }
+    // Phong diffuse and specular light reflected back along the incidence from a unit light
+    fn phong(
+        material: &Material,
+        normal: &Vector3<f64>,
+        to_light: &Vector3<f64>,
+        incidence: &Vector3<f64>,
+        options: &RaytracingOption,
+    ) -> Vector3<f32> {
+        let n_dot_l = normal.dot(to_light).max(0.0) as f32;
+
+        //Direct diffuse component (Lambertian)
+        let mut diffuse = Vector3::zeros();
+        if options.diffuse {
+            diffuse += material.kd * n_dot_l;
+        }
+
+        //Specular component
+        let mut specular = Vector3::zeros();
+        if options.specular && n_dot_l > 0.0 {
+            let h = (to_light - incidence).normalize();
+            let n_dot_h = normal.dot(&h).max(0.0) as f32;
+            specular = material.ks * n_dot_h.powf(material.shininess);
+        }
+
+        diffuse + specular
+    }
+
+    // If a node is between the ray origin and the light, a record is told which one
+    pub fn light_blocked(
+        &self,
+        scene: &Scene,
+        light: &Light,
+        bvh: &Option<BVH>,
+        record: Option<&mut ShadowRecord>,
+    ) -> bool {
+        self.blocked(scene, distance(&self.a, &light.position), bvh, record)
+    }
+
+    // If a node is closer along the ray than a distance, a record is told which one
+    pub fn blocked(
+        &self,
+        scene: &Scene,
+        light_distance: f64,
+        bvh: &Option<BVH>,
+        record: Option<&mut ShadowRecord>,
+    ) -> bool {
+        let occluder = match bvh {
+            //We have a bvh so use bvh traversal
+            Some(bvh) => bvh
+                .traverse(self, 0)
+                .filter(|(_, intersect)| intersect.distance < light_distance),
+            None => self.first_occluder(scene, light_distance),
+        };
+        match (occluder, record) {
+            (Some((node, intersect)), Some(record)) => {
+                record.occluder = Some((node.label.clone(), intersect.distance));
+                true
             }
+            (occluder, _) => occluder.is_some(),
         }
-        return false;
     }
-    //Cast a set of rays
-    pub fn cast_rays(
-        eye: &Point3<f64>,
-        target: &Point3<f64>,
-        up: &Vector3<f64>,
-        fovy: f64,
-        width: u32,
-        height: u32,
-    ) -> Vec<Ray> {
-        //Aspect ratio calculation
-        let (width, height) = (width as f64, height as f64);
-        let aspect = width / height;
-        //X and Y fov calculations
-        let fovy_radians = fovy.to_radians();
-        let fovh_radians = 2.0 * ((fovy_radians / 2.0).tan() * aspect).atan();
-        // Vectors pointing forward, right and up
-        let zv = (target - eye).normalize();
-        let xv = zv.cross(&up).normalize();
-        let yv = xv.cross(&zv).normalize();
-        // ☐ height and width of projection
-        let vheight = 2.0 * (fovy_radians / 2.0).tan();
-        let vwidth = 2.0 * (fovh_radians / 2.0).tan();
-        // Increment of right and up per pixel
-        let dy = vheight / height;
-        let dx = vwidth / width;
-        let dxv = dx * xv;
-        let dyv = dy * yv;
-        // Half the width for later calculation
-        let half_width = width / 2.0;
-        let half_height = height / 2.0;
-        // Array of rays
-        let mut rays = Vec::with_capacity(width as usize * height as usize);
-        // Iterate column by row
-        for y in 0..height as u32 {
-            for x in 0..width as u32 {
-                let x = (x as f64) - half_width;
-                let y = half_height - (y as f64);
 
-                let horizontal = x * &dxv;
-                let vertical = y * &dyv;
-                let direction = (zv + horizontal + vertical).normalize();
-                let ray = Ray::new(eye.clone(), direction);
-                rays.push(ray);
+    // Any node the ray hits closer than a distance, without a bvh
+    fn first_occluder<'a>(
+        &self,
+        scene: &'a Scene,
+        light_distance: f64,
+    ) -> Option<(&'a Node, Intersection)> {
+        for node in scene.nodes.values() {
+            if !node.active {
+                continue;
+            }
+            if node.bounds_hit(self) {
+                match node.intersect_ray(self) {
+                    Some(intersect) => {
+                        if intersect.distance < light_distance {
+                            return Some((node, intersect));
+                        }
+                    }
+                    None => continue,
+                }
             }
         }
-        rays
+        None
     }
 }
@@ -0,0 +1,1060 @@
+use crate::{
+    adaptive::{AdaptiveSampling, PixelStats},
+    aov::{heatmap, Aov, AovSample},
+    bsdf::FresnelKind,
+    bvh::BVH,
+    camera::{Camera, Film},
+    filter::Filter,
+    inspect::{RayKind, RayRecord},
+    integrator::{Integrator, IntegratorKind, MisHeuristic},
+    output::{self, OutputFormat},
+    ray::Ray,
+    sampler::{Sampler, SamplerKind},
+    scene::Scene,
+    tile::{schedule, TileOrder, TileRect},
+    tonemap::DisplayTransform,
+};
+use nalgebra::{Point3, Vector3, Vector4};
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+
+// Side of the blocks filled with a single ray during the coarse pass
+const COARSE_BLOCK: u32 = 4;
+// Steps per unit of the fixed point accumulation buffer, 2^32 leaves room for sums up to 2^31
+const ACCUMULATION_SCALE: f64 = 4294967296.0;
+
+// OPTIONS -----------------------------------------------------------------
+#[derive(Clone)]
+pub struct RaytracingOption {
+    pub threads: u32,
+    pub ray_samples: u32,
+    /// How the samples of a pixel are spread over its area, the lens and the bounces
+    pub sampler: SamplerKind,
+    /// Weights samples into the pixels around them
+    pub filter: Filter,
+    /// Stop sampling pixels whose noise is already low enough
+    pub adaptive: AdaptiveSampling,
+    /// Seed for every random number drawn while tracing, the same seed gives the same image
+    pub seed: u64,
+    pub clear_color: [u8; 4],
+    pub pixel_clear: [u8; 4],
+    /// Side of the square tiles handed out to the workers
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    /// Trace a low resolution preview of every tile before the full frame
+    pub coarse_pass: bool,
+    /// Passes of `ray_samples` per pixel accumulated before the renderer stops
+    pub passes: u32,
+    /// Exposure, tone mapping and encoding from the linear framebuffer to the display
+    pub display: DisplayTransform,
+    pub buffer_proportion: f32,
+    /// Whitted for a fast preview, path tracing for ground truth
+    pub integrator: IntegratorKind,
+    pub ray_depth: u8,
+    /// Longest path the path tracer follows, Russian roulette usually ends them sooner
+    pub max_bounces: u8,
+    /// Bounces a path makes before Russian roulette may end it
+    pub roulette_bounces: u8,
+    /// How the path tracer weights light samples against bounces that reach the same light
+    pub mis: MisHeuristic,
+    pub diffuse_rays: u8,
+    pub diffuse_coefficient: f32,
+    pub bvh_active: bool,
+    pub shadows: bool,
+    pub diffuse: bool,
+    pub reflect: bool,
+    /// Trace refraction rays through transparent materials in the whitted integrator
+    pub refract: bool,
+    /// How the whitted integrator splits light between reflection and refraction
+    pub fresnel: FresnelKind,
+    pub specular: bool,
+    pub falloff: bool,
+}
+
+impl RaytracingOption {
+    pub fn default() -> RaytracingOption {
+        RaytracingOption {
+            threads: 12,
+            ray_samples: 1,
+            sampler: SamplerKind::Sobol,
+            filter: Filter::default(),
+            adaptive: AdaptiveSampling::default(),
+            seed: 0,
+            clear_color: [0x22, 0x00, 0x11, 0x55],
+            pixel_clear: [0x11, 0x00, 0x22, 0x55],
+            tile_size: 16,
+            tile_order: TileOrder::Spiral,
+            coarse_pass: true,
+            passes: 1,
+            display: DisplayTransform::default(),
+            buffer_proportion: 1.0,
+            integrator: IntegratorKind::Whitted,
+            ray_depth: 1,
+            max_bounces: 32,
+            roulette_bounces: 3,
+            mis: MisHeuristic::Power,
+            diffuse_rays: 3,
+            diffuse_coefficient: 0.1,
+            bvh_active: false,
+            shadows: true,
+            diffuse: true,
+            reflect: true,
+            refract: true,
+            fresnel: FresnelKind::Exact,
+            specular: true,
+            falloff: true,
+        }
+    }
+}
+
+// TILE -----------------------------------------------------------------
+/// A rectangle of pixels traced together by one worker
+pub struct Tile {
+    /// Position of the tile in the schedule of the frame
+    pub index: usize,
+    pub rect: TileRect,
+    /// If the tile is a low resolution preview, later replaced by the full tile
+    pub coarse: bool,
+    /// Passes accumulated into the pixels of the tile so far
+    pub passes: u32,
+    /// Framebuffer index and colour of every pixel the tile touched, the
+    /// reconstruction filter spreads samples a few pixels past its edges
+    pub pixels: Vec<(usize, [u8; 4])>,
+}
+
+// Filtered samples of one pass over a tile, sent back by a worker
+struct TileSamples {
+    index: usize,
+    coarse: bool,
+    // Pixels the samples were splatted into, the tile and the reach of the filter around it
+    reach: TileRect,
+    // Weighted colour and summed weight of every pixel in reach, row by row
+    splats: Vec<Vector4<f32>>,
+    // AOVs of every pixel, only traced in the first pass
+    aovs: Vec<AovSample>,
+    // Samples traced through every pixel of the tile in this pass
+    stats: Vec<PixelStats>,
+}
+
+/// How far through the current frame the renderer is
+#[derive(Clone, Copy)]
+pub struct RenderProgress {
+    pub pixels_done: usize,
+    pub pixels_total: usize,
+    /// Passes finished over the whole frame
+    pub passes_done: u32,
+    pub passes_total: u32,
+}
+
+/// The node under a pixel of the viewport and where its surface was hit
+#[derive(Clone, Debug)]
+pub struct Pick {
+    pub label: String,
+    pub distance: f64,
+    pub position: Point3<f64>,
+    pub normal: Vector3<f64>,
+}
+
+impl RenderProgress {
+    /// Fraction of the frame completed, between 0 and 1
+    pub fn fraction(&self) -> f32 {
+        match self.pixels_total {
+            0 => 1.0,
+            total => self.pixels_done as f32 / total as f32,
+        }
+    }
+}
+
+// Tiles of a frame still to be traced, claimed in order through an atomic counter
+struct Schedule {
+    tiles: Arc<Vec<TileRect>>,
+    // Passes each tile already had when the job started
+    tile_passes: Vec<u32>,
+    first_pass: u32,
+    passes: u32,
+    coarse: bool,
+    next: AtomicUsize,
+    // With adaptive sampling a pass waits for the one before it to be received,
+    // so every tile knows which of its pixels converged no matter the timing
+    adaptive: bool,
+    passes_done: Mutex<u32>,
+    progress: Condvar,
+}
+
+impl Schedule {
+    // The next tile and pass to trace, previews first then pass by pass
+    fn claim(&self, active: &AtomicBool) -> Option<(usize, u32, bool)> {
+        let tile_count = self.tiles.len();
+        let coarse_count = if self.coarse { tile_count } else { 0 };
+        loop {
+            let n = self.next.fetch_add(1, Ordering::Relaxed);
+            if n < coarse_count {
+                return Some((n, 0, true));
+            }
+            let n = n - coarse_count;
+            let pass = self.first_pass + (n / tile_count.max(1)) as u32;
+            if pass >= self.passes {
+                return None;
+            }
+            let index = n % tile_count;
+            // Skip tiles that were further ahead when a previous job was cancelled
+            if self.tile_passes[index] > pass {
+                continue;
+            }
+            if self.adaptive {
+                let mut passes_done = self.passes_done.lock().unwrap();
+                while *passes_done < pass && active.load(Ordering::Relaxed) {
+                    passes_done = self.progress.wait(passes_done).unwrap();
+                }
+                if !active.load(Ordering::Relaxed) {
+                    return None;
+                }
+            }
+            return Some((index, pass, false));
+        }
+    }
+}
+
+// Handle to the worker threads of a frame in flight
+struct RenderJob {
+    rx: mpsc::Receiver<TileSamples>,
+    active: Arc<AtomicBool>,
+    schedule: Arc<Schedule>,
+}
+
+impl RenderJob {
+    // Let workers waiting on the previous pass carry on
+    fn passes_done(&self, passes_done: u32) {
+        *self.schedule.passes_done.lock().unwrap() = passes_done;
+        self.schedule.progress.notify_all();
+    }
+}
+
+impl Drop for RenderJob {
+    fn drop(&mut self) {
+        // Signal the workers to stop picking up new tiles
+        self.active.store(false, Ordering::Relaxed);
+        let _passes_done = self.schedule.passes_done.lock().unwrap();
+        self.schedule.progress.notify_all();
+    }
+}
+
+// RENDERER -----------------------------------------------------------------
+/// Traces a scene from a camera, accumulating passes into an f32 buffer
+/// and showing their running average in linear f32 and RGBA8 framebuffers
+pub struct Renderer {
+    scene: Arc<Scene>,
+    camera: Camera,
+    options: Arc<RaytracingOption>,
+    bvh: Arc<Option<BVH>>,
+    film: Film,
+
+    width: u32,
+    height: u32,
+    framebuffer: Vec<u8>,
+    hdr_framebuffer: Vec<f32>,
+    aovs: Vec<AovSample>,
+    // Samples traced through each pixel, and the pixels adaptive sampling is done with
+    stats: Vec<PixelStats>,
+    converged: Arc<Vec<AtomicBool>>,
+    // Which image is shown in the framebuffer
+    view: Aov,
+
+    // Filter weighted sum of every sample splatted into each pixel, and the sum of the weights,
+    // in fixed point. Integer sums come out the same whatever order neighbouring tiles
+    // arrive in, so the image does not depend on the thread count.
+    accumulation: Vec<[i64; 4]>,
+    tiles: Arc<Vec<TileRect>>,
+    // Passes accumulated into each tile
+    tile_passes: Vec<u32>,
+    // Lowest entry of tile_passes, and how many tiles are still on it
+    passes_done: u32,
+    tiles_behind: usize,
+    pixels_done: usize,
+
+    job: Option<RenderJob>,
+}
+
+impl Renderer {
+    /// Create a renderer with a framebuffer of `width` by `height` pixels
+    pub fn new(
+        mut scene: Scene,
+        camera: Camera,
+        options: RaytracingOption,
+        width: u32,
+        height: u32,
+    ) -> Renderer {
+        scene.set_shutter(camera.shutter_open, camera.shutter_close);
+        let mut renderer = Renderer {
+            film: Film::new(&camera, width, height),
+            scene: Arc::new(scene),
+            camera,
+            options: Arc::new(options),
+            bvh: Arc::new(None),
+            width,
+            height,
+            framebuffer: Vec::new(),
+            hdr_framebuffer: Vec::new(),
+            aovs: Vec::new(),
+            stats: Vec::new(),
+            converged: Arc::new(Vec::new()),
+            view: Aov::Beauty,
+            accumulation: Vec::new(),
+            tiles: Arc::new(Vec::new()),
+            tile_passes: Vec::new(),
+            passes_done: 0,
+            tiles_behind: 0,
+            pixels_done: 0,
+            job: None,
+        };
+        renderer.build_bvh();
+        renderer.resize(width, height);
+        renderer
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn options(&self) -> &RaytracingOption {
+        &self.options
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// RGBA8 pixels, row by row from the top left
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// Linear RGBA pixels without any clamping, row by row from the top left
+    pub fn hdr_framebuffer(&self) -> &[f32] {
+        &self.hdr_framebuffer
+    }
+
+    /// AOVs of the first sample of every pixel, row by row from the top left
+    pub fn aovs(&self) -> &[AovSample] {
+        &self.aovs
+    }
+
+    /// The image shown in the framebuffer
+    pub fn view(&self) -> Aov {
+        self.view
+    }
+
+    /// Passes accumulated over the whole frame
+    pub fn passes(&self) -> u32 {
+        self.passes_done
+    }
+
+    /// Replace the scene, rebuilding the BVH if it is enabled.
+    /// A display transform set by the scene replaces the one in the options.
+    pub fn set_scene(&mut self, mut scene: Scene) {
+        self.cancel();
+        if let Some(display) = scene.display {
+            Arc::make_mut(&mut self.options).display = display;
+        }
+        scene.set_shutter(self.camera.shutter_open, self.camera.shutter_close);
+        self.scene = Arc::new(scene);
+        self.build_bvh();
+        self.reset_accumulation();
+    }
+
+    /// Move the renderer to a new camera
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.cancel();
+        // Moving nodes are bounded over the shutter
+        let shutter = (camera.shutter_open, camera.shutter_close);
+        let rebuild = shutter != (self.camera.shutter_open, self.camera.shutter_close);
+        self.camera = camera;
+        if rebuild {
+            Arc::make_mut(&mut self.scene).set_shutter(shutter.0, shutter.1);
+            self.build_bvh();
+        }
+        self.build_film();
+        self.reset_accumulation();
+    }
+
+    /// Apply new raytracing options
+    pub fn set_options(&mut self, options: RaytracingOption) {
+        self.cancel();
+        self.options = Arc::new(options);
+        self.build_bvh();
+        self.build_film();
+        self.build_tiles();
+    }
+
+    /// Change how the linear framebuffer is shown, without tracing anything again
+    pub fn set_display(&mut self, display: DisplayTransform) {
+        Arc::make_mut(&mut self.options).display = display;
+        self.refresh_framebuffer();
+    }
+
+    /// Show an AOV in the framebuffer instead of the shaded colour
+    pub fn set_view(&mut self, view: Aov) {
+        self.view = view;
+        self.refresh_framebuffer();
+    }
+
+    /// Resize the framebuffer, clearing its contents
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.cancel();
+        self.width = width;
+        self.height = height;
+        self.framebuffer = vec![0; width as usize * height as usize * 4];
+        self.hdr_framebuffer = vec![0.0; width as usize * height as usize * 4];
+        self.aovs = vec![AovSample::miss(); width as usize * height as usize];
+        self.accumulation = vec![[0; 4]; width as usize * height as usize];
+        self.build_tiles();
+        self.clear();
+        self.build_film();
+    }
+
+    /// Fill the framebuffer with the pixel clear colour and drop every accumulated pass
+    pub fn clear(&mut self) {
+        self.cancel();
+        for pixel in self.framebuffer.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&self.options.pixel_clear);
+        }
+        let clear = self.options.pixel_clear.map(|c| c as f32 / 255.0);
+        for pixel in self.hdr_framebuffer.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&clear);
+        }
+        self.reset_accumulation();
+    }
+
+    /// If worker threads are still tracing the current frame
+    pub fn is_rendering(&self) -> bool {
+        self.job.is_some()
+    }
+
+    /// Node seen through the centre of a framebuffer pixel, through the middle of the
+    /// lens halfway through the shutter, where a moving node is seen in the blur
+    pub fn pick(&self, x: u32, y: u32) -> Option<Pick> {
+        let (x, y) = (x as f64 + 0.5, y as f64 + 0.5);
+        let ray = self.film.centre_ray(x, y, self.film.shutter_middle())?;
+        let (node, intersect) = ray.first_hit(&self.scene, &self.bvh)?;
+        Some(Pick {
+            label: node.label.clone(),
+            distance: intersect.distance,
+            position: intersect.point,
+            normal: intersect.normal.normalize(),
+        })
+    }
+
+    /// Trace a ray through the centre of a framebuffer pixel, recording every ray it spawns.
+    /// The bounces draw the numbers of the pixel's first sample. None where the projection
+    /// leaves the pixel empty.
+    pub fn inspect(&self, x: u32, y: u32) -> Option<RayRecord> {
+        let integrator = self.options.integrator.integrator(&self.scene);
+        let frame = Frame {
+            film: self.film,
+            width: self.width,
+            height: self.height,
+            eye: self.camera.eye,
+            forward: self.camera.forward(),
+            scene: &self.scene,
+            options: &self.options,
+            bvh: &self.bvh,
+            integrator: integrator.as_ref(),
+            converged: &self.converged,
+        };
+        let index = (y * self.width + x) as usize;
+        let (_, _, mut sampler) = sample_ray(&frame, index, 0);
+        let (cx, cy) = (x as f64 + 0.5, y as f64 + 0.5);
+        let ray = self.film.centre_ray(cx, cy, self.film.shutter_middle())?;
+        let mut record = RayRecord::new(RayKind::Camera, &ray, 0);
+        frame.integrator.radiance(
+            &ray,
+            frame.scene,
+            frame.options,
+            frame.bvh,
+            &mut sampler,
+            Some(&mut record),
+        );
+        Some(record)
+    }
+
+    /// Progress through the passes of the current frame
+    pub fn progress(&self) -> RenderProgress {
+        RenderProgress {
+            pixels_done: self.pixels_done,
+            pixels_total: (self.width * self.height) as usize * self.options.passes as usize,
+            passes_done: self.passes_done,
+            passes_total: self.options.passes,
+        }
+    }
+
+    /// Stop tracing the current frame, leaving the framebuffer as it is
+    pub fn cancel(&mut self) {
+        self.job = None;
+    }
+
+    /// Begin tracing passes on background threads until `passes` have been accumulated,
+    /// carrying on from any passes already traced. Returns immediately.
+    pub fn start(&mut self) {
+        self.cancel();
+        if self.passes_done >= self.options.passes {
+            return;
+        }
+
+        let schedule = Arc::new(Schedule {
+            tiles: self.tiles.clone(),
+            tile_passes: self.tile_passes.clone(),
+            first_pass: self.passes_done,
+            passes: self.options.passes,
+            coarse: self.options.coarse_pass && self.passes_done == 0,
+            next: AtomicUsize::new(0),
+            adaptive: self.options.adaptive.enabled,
+            passes_done: Mutex::new(self.passes_done),
+            progress: Condvar::new(),
+        });
+
+        let (tx, rx) = mpsc::channel();
+        let active = Arc::new(AtomicBool::new(true));
+
+        // Spawn persistent worker threads
+        for _ in 0..self.options.threads {
+            let film = self.film;
+            let scene = self.scene.clone();
+            let options = self.options.clone();
+            let bvh = self.bvh.clone();
+            let converged = self.converged.clone();
+            let schedule = schedule.clone();
+            let tx = tx.clone();
+            let active = active.clone();
+            let (width, height) = (self.width, self.height);
+            let eye = self.camera.eye;
+            let forward = (self.camera.target - self.camera.eye).normalize();
+
+            thread::spawn(move || {
+                let integrator = options.integrator.integrator(&scene);
+                let frame = Frame {
+                    film,
+                    width,
+                    height,
+                    eye,
+                    forward,
+                    scene: &scene,
+                    options: &options,
+                    bvh: &bvh,
+                    integrator: integrator.as_ref(),
+                    converged: &converged,
+                };
+                trace_worker(&frame, &schedule, &tx, &active)
+            });
+        }
+
+        self.job = Some(RenderJob {
+            rx,
+            active,
+            schedule,
+        });
+    }
+
+    /// Copy every finished tile into the framebuffer without blocking.
+    /// Returns true while the frame is still being traced.
+    pub fn poll<F: FnMut(&Tile)>(&mut self, mut on_tile: F) -> bool {
+        loop {
+            let Some(job) = &self.job else {
+                return false;
+            };
+            match job.rx.try_recv() {
+                Ok(samples) => {
+                    if let Some(tile) = self.receive(samples) {
+                        on_tile(&tile);
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    // All worker threads have finished
+                    self.job = None;
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Trace a whole frame, blocking until it is finished
+    pub fn render(&mut self) -> &[u8] {
+        self.render_with(|_, _| {})
+    }
+
+    /// Trace a whole frame, calling `on_tile` as each tile is finished
+    pub fn render_with<F: FnMut(&Tile, RenderProgress)>(&mut self, mut on_tile: F) -> &[u8] {
+        self.start();
+        // Blocks until a tile arrives, ends once every worker has hung up
+        while let Some(Ok(samples)) = self.job.as_ref().map(|job| job.rx.recv()) {
+            if let Some(tile) = self.receive(samples) {
+                on_tile(&tile, self.progress());
+            }
+        }
+        self.job = None;
+        &self.framebuffer
+    }
+
+    /// Trace a whole frame, calling `on_pixel` with the index and final colour of every pixel
+    pub fn render_pixels<F: FnMut(usize, [u8; 4])>(&mut self, mut on_pixel: F) -> &[u8] {
+        let passes = self.options.passes;
+        // A pixel is final once every tile whose samples reach it has traced its last pass
+        let margin = self.options.filter.margin();
+        let mut remaining = vec![0u32; self.width as usize * self.height as usize];
+        for rect in self.tiles.iter() {
+            for index in rect
+                .expand(margin, self.width, self.height)
+                .indices(self.width)
+            {
+                remaining[index] += 1;
+            }
+        }
+        self.render_with(|tile, _| {
+            if tile.coarse || tile.passes < passes {
+                return;
+            }
+            for (index, rgba) in &tile.pixels {
+                remaining[*index] -= 1;
+                if remaining[*index] == 0 {
+                    on_pixel(*index, *rgba);
+                }
+            }
+        })
+    }
+
+    /// Save the shaded colour as an image, whichever AOV is on view. The format is taken from
+    /// the extension: 8 bit formats go through the display transform, `.exr`, `.hdr` and
+    /// `.pfm` keep the full range of the linear framebuffer.
+    pub fn save_image<P: AsRef<Path>>(&self, path: P) -> image::ImageResult<()> {
+        let (width, height) = (self.width, self.height);
+        match OutputFormat::from_path(&path) {
+            OutputFormat::Ldr => {
+                let pixels: Vec<u8> = self
+                    .hdr_framebuffer
+                    .chunks_exact(4)
+                    .flat_map(|linear| {
+                        let colour = Vector3::new(linear[0], linear[1], linear[2]);
+                        self.options.display.to_rgba(colour)
+                    })
+                    .collect();
+                output::save_ldr(path, width, height, &pixels)
+            }
+            OutputFormat::Exr => output::save_exr(path, width, height, &self.hdr_framebuffer),
+            OutputFormat::Hdr => output::save_hdr(path, width, height, &self.hdr_framebuffer),
+            OutputFormat::Pfm => output::save_pfm(path, width, height, &self.hdr_framebuffer),
+        }
+    }
+
+    /// Save one AOV as an image. 8 bit formats get the same mapping as the viewer,
+    /// `.exr`, `.hdr` and `.pfm` get the raw values.
+    pub fn save_aov<P: AsRef<Path>>(&self, aov: Aov, path: P) -> image::ImageResult<()> {
+        let (width, height) = (self.width, self.height);
+        if aov == Aov::Beauty {
+            return self.save_image(path);
+        }
+        let format = OutputFormat::from_path(&path);
+        if !format.is_hdr() {
+            let pixels: Vec<u8> = (0..self.aovs.len())
+                .flat_map(|index| {
+                    let colour = self.aov_display(index, aov) * 255.0;
+                    [colour.x as u8, colour.y as u8, colour.z as u8, 0xff]
+                })
+                .collect();
+            return output::save_ldr(path, width, height, &pixels);
+        }
+        let pixels: Vec<f32> = (0..self.aovs.len())
+            .flat_map(|index| {
+                let value = self.aov_value(index, aov);
+                [value.x, value.y, value.z, 1.0]
+            })
+            .collect();
+        match format {
+            OutputFormat::Hdr => output::save_hdr(path, width, height, &pixels),
+            OutputFormat::Pfm => output::save_pfm(path, width, height, &pixels),
+            _ => output::save_exr(path, width, height, &pixels),
+        }
+    }
+
+    /// Save the linear framebuffer and every AOV as layers of one OpenEXR image,
+    /// the shaded colour in R, G, B, A and the AOVs as channels like `normal.X`
+    pub fn save_exr_layers<P: AsRef<Path>>(&self, path: P) -> image::ImageResult<()> {
+        let mut channels = Vec::new();
+        for (c, name) in ["R", "G", "B", "A"].iter().enumerate() {
+            let samples = self.hdr_framebuffer.iter().skip(c).step_by(4).copied();
+            channels.push((name.to_string(), samples.collect()));
+        }
+        for aov in Aov::ALL.iter().filter(|aov| **aov != Aov::Beauty) {
+            for (c, channel) in aov.channels().iter().enumerate() {
+                let samples = (0..self.aovs.len()).map(|index| self.aov_value(index, *aov)[c]);
+                channels.push((format!("{}.{channel}", aov.name()), samples.collect()));
+            }
+        }
+        output::save_exr_channels(path, self.width, self.height, channels)
+    }
+
+    // Add a traced tile to the accumulation buffer and update its pixels in the
+    // framebuffer, None if it is a preview of a tile that has already been traced
+    fn receive(&mut self, samples: TileSamples) -> Option<Tile> {
+        let rect = self.tiles[samples.index];
+        match samples.coarse {
+            true if self.tile_passes[samples.index] > 0 => return None,
+            true => {}
+            false => self.tile_passes[samples.index] += 1,
+        }
+        for (index, stats) in rect.indices(self.width).zip(&samples.stats) {
+            self.stats[index].merge(stats);
+            if self.options.adaptive.converged(&self.stats[index]) {
+                self.converged[index].store(true, Ordering::Relaxed);
+            }
+        }
+
+        let mut pixels = Vec::with_capacity(samples.reach.area());
+        for (index, splat) in samples.reach.indices(self.width).zip(samples.splats) {
+            // A preview is shown as it is, passes are averaged with everything traced before
+            let total = match samples.coarse {
+                true => splat.cast(),
+                false => {
+                    let sum = &mut self.accumulation[index];
+                    for (sum, value) in sum.iter_mut().zip(splat.iter()) {
+                        let value = (*value as f64 * ACCUMULATION_SCALE).round() as i64;
+                        *sum = sum.saturating_add(value);
+                    }
+                    Vector4::from(*sum).map(|sum| sum as f64 / ACCUMULATION_SCALE)
+                }
+            };
+            // Negative lobes can leave a pixel without weight until more samples land
+            if total.w > 0.0 {
+                let colour: Vector3<f32> = (total.xyz() / total.w).cast();
+                self.hdr_framebuffer[index * 4..(index + 1) * 4]
+                    .copy_from_slice(&[colour.x, colour.y, colour.z, 1.0]);
+                let rgba = self.display_pixel(index);
+                self.framebuffer[index * 4..(index + 1) * 4].copy_from_slice(&rgba);
+            }
+            let mut rgba = [0; 4];
+            rgba.copy_from_slice(&self.framebuffer[index * 4..(index + 1) * 4]);
+            pixels.push((index, rgba));
+        }
+        for (index, aov) in rect.indices(self.width).zip(samples.aovs) {
+            self.aovs[index] = aov;
+        }
+
+        if !samples.coarse {
+            self.pixels_done += rect.area();
+            if self.tile_passes[samples.index] == self.passes_done + 1 {
+                self.tiles_behind -= 1;
+            }
+            // Every tile has caught up so the whole frame has another pass
+            let passes_done = self.passes_done;
+            while self.tiles_behind == 0 && !self.tiles.is_empty() {
+                self.passes_done += 1;
+                let passes_done = self.passes_done;
+                self.tiles_behind = self
+                    .tile_passes
+                    .iter()
+                    .filter(|p| **p == passes_done)
+                    .count();
+            }
+            if let Some(job) = self.job.as_ref().filter(|_| self.passes_done > passes_done) {
+                job.passes_done(self.passes_done);
+            }
+        }
+
+        Some(Tile {
+            index: samples.index,
+            rect,
+            coarse: samples.coarse,
+            passes: self.tile_passes[samples.index],
+            pixels,
+        })
+    }
+
+    // Colour shown for a pixel in the current view
+    fn display_pixel(&self, index: usize) -> [u8; 4] {
+        match self.view {
+            Aov::Beauty => {
+                let linear = &self.hdr_framebuffer[index * 4..(index + 1) * 4];
+                let colour = Vector3::new(linear[0], linear[1], linear[2]);
+                self.options.display.to_rgba(colour)
+            }
+            aov => {
+                let colour = self.aov_display(index, aov) * 255.0;
+                [colour.x as u8, colour.y as u8, colour.z as u8, 0xff]
+            }
+        }
+    }
+
+    // Raw value of an AOV of a pixel
+    fn aov_value(&self, index: usize, aov: Aov) -> Vector3<f32> {
+        match aov {
+            Aov::Samples => Vector3::repeat(self.stats[index].samples as f32),
+            aov => self.aovs[index].value(aov),
+        }
+    }
+
+    // An AOV of a pixel squeezed into 0 to 1, sample counts as a heatmap up to the target
+    fn aov_display(&self, index: usize, aov: Aov) -> Vector3<f32> {
+        match aov {
+            Aov::Samples => {
+                let target = self.options.passes * self.options.ray_samples;
+                heatmap(self.stats[index].samples as f32 / target.max(1) as f32)
+            }
+            aov => self.aovs[index].display(aov),
+        }
+    }
+
+    // Redraw every traced tile of the framebuffer after the view or display changed
+    fn refresh_framebuffer(&mut self) {
+        let margin = self.options.filter.margin();
+        for (tile, rect) in self.tiles.clone().iter().enumerate() {
+            if self.tile_passes[tile] == 0 {
+                continue;
+            }
+            for index in rect
+                .expand(margin, self.width, self.height)
+                .indices(self.width)
+            {
+                let rgba = self.display_pixel(index);
+                self.framebuffer[index * 4..(index + 1) * 4].copy_from_slice(&rgba);
+            }
+        }
+    }
+
+    fn reset_accumulation(&mut self) {
+        for colour in self.accumulation.iter_mut() {
+            *colour = [0; 4];
+        }
+        for aov in self.aovs.iter_mut() {
+            *aov = AovSample::miss();
+        }
+        let pixels = self.width as usize * self.height as usize;
+        self.stats = vec![PixelStats::default(); pixels];
+        // Workers of a cancelled job may still hold the old mask
+        self.converged = Arc::new((0..pixels).map(|_| AtomicBool::new(false)).collect());
+        self.tile_passes = vec![0; self.tiles.len()];
+        self.passes_done = 0;
+        self.tiles_behind = self.tiles.len();
+        self.pixels_done = 0;
+    }
+
+    fn build_tiles(&mut self) {
+        self.tiles = Arc::new(schedule(
+            self.width,
+            self.height,
+            self.options.tile_size,
+            self.options.tile_order,
+        ));
+        self.reset_accumulation();
+    }
+
+    fn build_bvh(&mut self) {
+        self.bvh = match self.options.bvh_active {
+            true => {
+                let shutter = (self.camera.shutter_open, self.camera.shutter_close);
+                Arc::new(Some(BVH::build(&self.scene.nodes, shutter)))
+            }
+            false => Arc::new(None),
+        };
+    }
+
+    fn build_film(&mut self) {
+        self.film = Film::new(&self.camera, self.width, self.height);
+    }
+}
+
+// Everything a worker needs to trace pixels of the frame
+struct Frame<'a> {
+    film: Film,
+    width: u32,
+    height: u32,
+    // Position and view direction of the camera, for the depth AOV
+    eye: Point3<f64>,
+    forward: Vector3<f64>,
+    scene: &'a Scene,
+    options: &'a RaytracingOption,
+    bvh: &'a Option<BVH>,
+    integrator: &'a dyn Integrator,
+    // Pixels adaptive sampling has finished with
+    converged: &'a [AtomicBool],
+}
+
+// Claim tiles from the schedule until they run out or the job is cancelled
+fn trace_worker(
+    frame: &Frame,
+    schedule: &Schedule,
+    tx: &mpsc::Sender<TileSamples>,
+    active: &AtomicBool,
+) {
+    while active.load(Ordering::Relaxed) {
+        let Some((index, pass, coarse)) = schedule.claim(active) else {
+            break;
+        };
+        let rect = &schedule.tiles[index];
+        let (reach, splats, stats) = match coarse {
+            true => (*rect, trace_coarse(frame, rect), Vec::new()),
+            false => trace_splats(frame, rect, pass),
+        };
+        // AOVs follow the first sample of each pixel
+        let aovs = match (coarse, pass) {
+            (false, 0) => rect
+                .indices(frame.width)
+                .map(|pixel| match sample_ray(frame, pixel, 0) {
+                    (Some(ray), _, _) => {
+                        AovSample::trace(&ray, &frame.eye, &frame.forward, frame.scene, frame.bvh)
+                    }
+                    (None, _, _) => AovSample::miss(),
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        // Send results back to the renderer
+        let samples = TileSamples {
+            index,
+            coarse,
+            reach,
+            splats,
+            aovs,
+            stats,
+        };
+        if tx.send(samples).is_err() {
+            break;
+        }
+    }
+}
+
+// Trace one ray per block of the tile and fill the block with its colour, at full weight
+fn trace_coarse(frame: &Frame, rect: &TileRect) -> Vec<Vector4<f32>> {
+    let mut colours = vec![Vector4::zeros(); rect.area()];
+    for by in (0..rect.height).step_by(COARSE_BLOCK as usize) {
+        for bx in (0..rect.width).step_by(COARSE_BLOCK as usize) {
+            let pixel = ((rect.y + by) * frame.width + rect.x + bx) as usize;
+            let (colour, _) = trace_sample(frame, pixel, 0);
+            for y in by..(by + COARSE_BLOCK).min(rect.height) {
+                for x in bx..(bx + COARSE_BLOCK).min(rect.width) {
+                    colours[(y * rect.width + x) as usize] = colour.push(1.0);
+                }
+            }
+        }
+    }
+    colours
+}
+
+// Trace one pass of samples through every pixel of the tile that has not settled and
+// splat them into the pixels the filter reaches, returned with the rectangle they cover
+// and the samples taken in each pixel of the tile
+fn trace_splats(
+    frame: &Frame,
+    rect: &TileRect,
+    pass: u32,
+) -> (TileRect, Vec<Vector4<f32>>, Vec<PixelStats>) {
+    let filter = &frame.options.filter;
+    let reach = rect.expand(filter.margin(), frame.width, frame.height);
+    let mut splats = vec![Vector4::zeros(); reach.area()];
+    let mut stats = vec![PixelStats::default(); rect.area()];
+    let samples = frame.options.ray_samples;
+    for (pixel, stats) in rect.indices(frame.width).zip(stats.iter_mut()) {
+        if settled(frame, pixel, filter.margin()) {
+            continue;
+        }
+        for sample in pass * samples..(pass + 1) * samples {
+            let (colour, (x, y)) = trace_sample(frame, pixel, sample);
+            splat(&mut splats, &reach, filter, x, y, colour);
+            stats.add(colour);
+        }
+    }
+    (reach, splats, stats)
+}
+
+// If a pixel and every pixel its samples reach have converged. A converged pixel keeps
+// being sampled while a neighbour is, or the neighbour would average the filter weights
+// of only the samples that happen to come from pixels still active.
+fn settled(frame: &Frame, pixel: usize, margin: u32) -> bool {
+    let width = frame.width as usize;
+    let pixel = TileRect {
+        x: (pixel % width) as u32,
+        y: (pixel / width) as u32,
+        width: 1,
+        height: 1,
+    };
+    pixel
+        .expand(margin, frame.width, frame.height)
+        .indices(frame.width)
+        .all(|index| frame.converged[index].load(Ordering::Relaxed))
+}
+
+// Add a sample at film position x, y to every pixel of reach within the filter radius
+fn splat(
+    splats: &mut [Vector4<f32>],
+    reach: &TileRect,
+    filter: &Filter,
+    x: f64,
+    y: f64,
+    colour: Vector3<f32>,
+) {
+    let margin = filter.margin() as i64;
+    let (px, py) = (x.floor() as i64, y.floor() as i64);
+    for ty in py - margin..=py + margin {
+        for tx in px - margin..=px + margin {
+            if tx < 0 || ty < 0 || !reach.contains(tx as u32, ty as u32) {
+                continue;
+            }
+            let weight = filter.weight((ty as f64 + 0.5 - y) as f32)
+                * filter.weight((tx as f64 + 0.5 - x) as f32);
+            if weight == 0.0 {
+                continue;
+            }
+            let index = (ty as u32 - reach.y) * reach.width + (tx as u32 - reach.x);
+            splats[index as usize] += colour.push(1.0) * weight;
+        }
+    }
+}
+
+// Colour of one sample of a pixel and where on the film it was taken
+fn trace_sample(frame: &Frame, index: usize, sample: u32) -> (Vector3<f32>, (f64, f64)) {
+    let (ray, position, mut sampler) = sample_ray(frame, index, sample);
+    let colour = ray
+        .and_then(|ray| {
+            frame.integrator.radiance(
+                &ray,
+                frame.scene,
+                frame.options,
+                frame.bvh,
+                &mut sampler,
+                None,
+            )
+        })
+        .unwrap_or(Vector3::zeros());
+    (colour, position)
+}
+
+// Camera ray of one sample through its position in the pixel, on the lens and in the shutter,
+// with the film position and the sampler to carry on tracing it with. No ray where
+// the projection leaves the film empty.
+fn sample_ray(frame: &Frame, index: usize, sample: u32) -> (Option<Ray>, (f64, f64), Sampler) {
+    let options = frame.options;
+    // Seeded from the pixel and sample alone so the order pixels are traced in does not matter
+    let mut sampler = Sampler::new(
+        options.sampler,
+        options.seed,
+        index,
+        sample,
+        options.ray_samples,
+    );
+    let (u, v) = sampler.film();
+    let x = (index % frame.width as usize) as f64 + u;
+    let y = (index / frame.width as usize) as f64 + v;
+    let lens = sampler.lens();
+    let time = frame.film.time(&mut sampler);
+    (frame.film.ray(x, y, lens, time), (x, y), sampler)
+}
@@ -0,0 +1,462 @@
+use nalgebra::Vector3;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::f64::consts::PI;
+
+// Dimensions of the camera sample, bounces draw from FIRST_BOUNCE_DIMENSION on
+const FILM_DIMENSION: u32 = 0;
+const LENS_DIMENSION: u32 = 2;
+const FIRST_BOUNCE_DIMENSION: u32 = 4;
+
+// Bases of the Halton dimensions, past the last one samples fall back to random numbers
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+// Largest f64 below 1.0
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+// SAMPLER KIND -----------------------------------------------------------------
+/// How the numbers of each sample are spread over the pixel, lens and bounces
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SamplerKind {
+    /// Every number is uniformly random
+    Independent,
+    /// One jittered sample per cell of a grid over the samples of a pass
+    Stratified,
+    /// Halton sequence with a random rotation per pixel
+    Halton,
+    /// Sobol (0,2) sequence with a random digital shift per pixel and dimension
+    Sobol,
+}
+
+impl SamplerKind {
+    pub const ALL: [SamplerKind; 4] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+    ];
+
+    /// Parse the names used from the command line
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name.to_lowercase().as_str() {
+            "independent" | "random" => Some(SamplerKind::Independent),
+            "stratified" | "jittered" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SamplerKind::Independent => "independent",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+        }
+    }
+}
+
+// SAMPLER -----------------------------------------------------------------
+/// Numbers in [0, 1) for one sample of one pixel. The film position, the lens position
+/// and every bounce draw from their own dimensions, so a sequence stays well spread in each.
+pub struct Sampler {
+    kind: SamplerKind,
+    // Seed mixed with the pixel, scrambles the sequence differently in every pixel
+    scramble: u64,
+    index: u32,
+    // Samples traced through the pixel in every pass
+    count: u32,
+    dimension: u32,
+    rng: ChaCha8Rng,
+}
+
+impl Sampler {
+    /// Sampler for sample `index` of `pixel`, where every pass traces `count` samples
+    pub fn new(kind: SamplerKind, seed: u64, pixel: usize, index: u32, count: u32) -> Sampler {
+        Sampler {
+            kind,
+            scramble: splitmix64(splitmix64(seed) ^ pixel as u64),
+            index,
+            count: count.max(1),
+            dimension: FIRST_BOUNCE_DIMENSION,
+            rng: sample_rng(seed, pixel, index),
+        }
+    }
+
+    /// Position inside the pixel
+    pub fn film(&mut self) -> (f64, f64) {
+        self.sample_2d(FILM_DIMENSION)
+    }
+
+    /// Position on the lens aperture
+    pub fn lens(&mut self) -> (f64, f64) {
+        self.sample_2d(LENS_DIMENSION)
+    }
+
+    /// Next number along the path
+    pub fn next_1d(&mut self) -> f64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        self.sample_1d(dimension)
+    }
+
+    /// Next pair of numbers along the path, e.g. for a bounce direction
+    pub fn next_2d(&mut self) -> (f64, f64) {
+        let dimension = self.dimension;
+        self.dimension += 2;
+        self.sample_2d(dimension)
+    }
+
+    fn sample_1d(&mut self, dimension: u32) -> f64 {
+        match self.kind {
+            SamplerKind::Independent => self.rng.gen(),
+            SamplerKind::Stratified => {
+                let cell = permute(self.index % self.count, self.count, self.key(dimension));
+                ((cell as f64 + self.rng.gen::<f64>()) / self.count as f64).min(ONE_MINUS_EPSILON)
+            }
+            SamplerKind::Halton => self.halton(dimension),
+            SamplerKind::Sobol => self.sobol(dimension).0,
+        }
+    }
+
+    fn sample_2d(&mut self, dimension: u32) -> (f64, f64) {
+        match self.kind {
+            SamplerKind::Independent => (self.rng.gen(), self.rng.gen()),
+            SamplerKind::Stratified => {
+                // The smallest grid with a cell for every sample, cells left over stay empty
+                let columns = (self.count as f64).sqrt().ceil() as u32;
+                let rows = self.count.div_ceil(columns);
+                let cell = permute(self.index % self.count, columns * rows, self.key(dimension));
+                let u = ((cell % columns) as f64 + self.rng.gen::<f64>()) / columns as f64;
+                let v = ((cell / columns) as f64 + self.rng.gen::<f64>()) / rows as f64;
+                (u.min(ONE_MINUS_EPSILON), v.min(ONE_MINUS_EPSILON))
+            }
+            SamplerKind::Halton => (self.halton(dimension), self.halton(dimension + 1)),
+            SamplerKind::Sobol => self.sobol(dimension),
+        }
+    }
+
+    // Random bits for one dimension of this pixel, different for every pass
+    fn key(&self, dimension: u32) -> u32 {
+        let pass = (self.index / self.count) as u64;
+        splitmix64(self.scramble ^ ((dimension as u64) << 32) ^ pass) as u32
+    }
+
+    fn halton(&mut self, dimension: u32) -> f64 {
+        let Some(&base) = PRIMES.get(dimension as usize) else {
+            return self.rng.gen();
+        };
+        // Cranley-Patterson rotation so neighbouring pixels do not see the same points
+        let rotation = splitmix64(self.scramble ^ dimension as u64) as f64 / 2f64.powi(64);
+        (radical_inverse(base, self.index) + rotation)
+            .fract()
+            .min(ONE_MINUS_EPSILON)
+    }
+
+    fn sobol(&self, dimension: u32) -> (f64, f64) {
+        // Each pair of dimensions shuffles the samples of the pass on its own, otherwise
+        // the film position would decide every bounce
+        let index = (self.index / self.count) * self.count
+            + permute(self.index % self.count, self.count, self.key(dimension));
+        let shift = splitmix64(self.scramble ^ dimension as u64);
+        let x = index.reverse_bits() ^ shift as u32;
+        let y = sobol_second(index) ^ (shift >> 32) as u32;
+        (x as f64 / 2f64.powi(32), y as f64 / 2f64.powi(32))
+    }
+}
+
+/// Uniformly distributed direction on the unit sphere from two numbers in [0, 1)
+pub fn uniform_sphere((u, v): (f64, f64)) -> Vector3<f64> {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Direction on the hemisphere around +z from two numbers in [0, 1), more likely the
+/// closer it is to +z, with a density of cos(theta) / pi
+pub fn cosine_hemisphere(sample: (f64, f64)) -> Vector3<f64> {
+    let (x, y) = concentric_disk(sample);
+    Vector3::new(x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+}
+
+/// Uniformly distributed direction at most acos(cos_max) away from +z from two numbers in
+/// [0, 1), with a density of 1 / (2 pi (1 - cos_max))
+pub fn uniform_cone((u, v): (f64, f64), cos_max: f64) -> Vector3<f64> {
+    let z = 1.0 - u * (1.0 - cos_max);
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Turn a direction around +z into the same direction around normal, which must be unit length
+pub fn orient(local: &Vector3<f64>, normal: &Vector3<f64>) -> Vector3<f64> {
+    let (tangent, bitangent) = tangents(normal);
+    tangent * local.x + bitangent * local.y + normal * local.z
+}
+
+/// Two unit vectors at right angles to each other and to a unit normal, following Duff et al.,
+/// "Building an Orthonormal Basis, Revisited"
+pub fn tangents(normal: &Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let sign = 1.0f64.copysign(normal.z);
+    let a = -1.0 / (sign + normal.z);
+    let b = normal.x * normal.y * a;
+    let tangent = Vector3::new(
+        1.0 + sign * normal.x * normal.x * a,
+        sign * b,
+        -sign * normal.x,
+    );
+    let bitangent = Vector3::new(b, sign + normal.y * normal.y * a, -normal.y);
+    (tangent, bitangent)
+}
+
+/// Uniformly distributed point on the unit disk from two numbers in [0, 1),
+/// Shirley and Chiu's mapping keeps neighbouring samples close together
+pub fn concentric_disk((u, v): (f64, f64)) -> (f64, f64) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = match a.abs() > b.abs() {
+        true => (a, PI / 4.0 * (b / a)),
+        false => (b, PI / 2.0 - PI / 4.0 * (a / b)),
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+/// Uniformly distributed point inside a regular polygon with its corners on the unit circle
+pub fn regular_polygon((u, v): (f64, f64), sides: u32, rotation: f64) -> (f64, f64) {
+    // Pick one of the triangles between the centre and a side, then a point inside it
+    let side = ((u * sides as f64) as u32).min(sides - 1);
+    let u = u * sides as f64 - side as f64;
+    let corner = |i: u32| {
+        let angle = rotation + 2.0 * PI * i as f64 / sides as f64;
+        (angle.cos(), angle.sin())
+    };
+    let (a, b) = (corner(side), corner(side + 1));
+    let s = u.sqrt();
+    (
+        s * ((1.0 - v) * a.0 + v * b.0),
+        s * ((1.0 - v) * a.1 + v * b.1),
+    )
+}
+
+/// Random number generator for one sample of one pixel, derived from the global seed.
+/// ChaCha8 and the seed expansion below are fixed algorithms, unlike `StdRng`, so a
+/// dependency update cannot change the images a seed renders.
+pub fn sample_rng(seed: u64, pixel: usize, sample: u32) -> ChaCha8Rng {
+    let mut hash = splitmix64(seed);
+    hash = splitmix64(hash ^ pixel as u64);
+    hash = splitmix64(hash ^ sample as u64);
+    let mut key = [0u8; 32];
+    for chunk in key.chunks_exact_mut(8) {
+        hash = splitmix64(hash);
+        chunk.copy_from_slice(&hash.to_le_bytes());
+    }
+    ChaCha8Rng::from_seed(key)
+}
+
+// Mixes all the bits of x, used to combine seeds
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Digits of index in base, mirrored around the decimal point
+fn radical_inverse(base: u32, mut index: u32) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv = inv_base;
+    let mut result = 0.0;
+    while index > 0 {
+        result += (index % base) as f64 * inv;
+        index /= base;
+        inv *= inv_base;
+    }
+    result
+}
+
+// Second dimension of the Sobol sequence, the first is the bit reversed index
+fn sobol_second(mut index: u32) -> u32 {
+    let mut v = 1 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 == 1 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Kensler's hashed permutation of 0..len, from "Correlated Multi-Jittered Sampling"
+fn permute(mut i: u32, len: u32, key: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= key;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= key >> 16;
+        i ^= (i & w) >> 4;
+        i ^= key >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= key >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | key >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < len {
+            // Offset modulo len first, a sum wrapping past u32::MAX would repeat indices
+            return (i + key % len) % len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Film, lens and bounce numbers drawn by one sample, far past the Halton primes
+    fn draw(kind: SamplerKind, seed: u64, pixel: usize, index: u32, count: u32) -> Vec<f64> {
+        let mut sampler = Sampler::new(kind, seed, pixel, index, count);
+        let (film, lens) = (sampler.film(), sampler.lens());
+        let mut numbers = vec![film.0, film.1, lens.0, lens.1];
+        for _ in 0..24 {
+            numbers.push(sampler.next_1d());
+            let (u, v) = sampler.next_2d();
+            numbers.extend([u, v]);
+        }
+        numbers
+    }
+
+    #[test]
+    fn numbers_lie_in_the_unit_interval() {
+        for kind in SamplerKind::ALL {
+            for count in [1, 6, 16, 33] {
+                for pixel in [0, 17, 4096] {
+                    for index in 0..2 * count {
+                        for number in draw(kind, 9, pixel, index, count) {
+                            assert!(
+                                (0.0..1.0).contains(&number),
+                                "{} drew {number}",
+                                kind.name()
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn same_sample_draws_the_same_numbers() {
+        for kind in SamplerKind::ALL {
+            let numbers = draw(kind, 5, 300, 11, 16);
+            assert_eq!(numbers, draw(kind, 5, 300, 11, 16), "{}", kind.name());
+            assert_ne!(numbers, draw(kind, 5, 301, 11, 16), "{}", kind.name());
+            assert_ne!(numbers, draw(kind, 6, 300, 11, 16), "{}", kind.name());
+            assert_ne!(numbers, draw(kind, 5, 300, 12, 16), "{}", kind.name());
+        }
+    }
+
+    // Cells of a columns by rows grid over [0, 1)^2 hit by the samples of one pass
+    fn cells(kind: SamplerKind, pass: u32, count: u32, columns: u32, rows: u32) -> Vec<u32> {
+        let mut hits = vec![0; (columns * rows) as usize];
+        for index in pass * count..(pass + 1) * count {
+            let mut sampler = Sampler::new(kind, 3, 42, index, count);
+            for (u, v) in [sampler.film(), sampler.lens(), sampler.next_2d()] {
+                let cell = (v * rows as f64) as u32 * columns + (u * columns as f64) as u32;
+                hits[cell as usize] += 1;
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn stratified_puts_one_sample_in_each_cell() {
+        for pass in 0..3 {
+            // Film, lens and a bounce each fill the grid once
+            assert!(cells(SamplerKind::Stratified, pass, 16, 4, 4)
+                .iter()
+                .all(|&hits| hits == 3));
+            assert!(cells(SamplerKind::Stratified, pass, 6, 3, 2)
+                .iter()
+                .all(|&hits| hits == 3));
+        }
+        let mut hits = [0; 10];
+        for index in 0..10 {
+            let number = Sampler::new(SamplerKind::Stratified, 3, 42, index, 10).next_1d();
+            hits[(number * 10.0) as usize] += 1;
+        }
+        assert_eq!(hits, [1; 10]);
+    }
+
+    #[test]
+    fn sobol_puts_one_sample_in_each_elementary_interval() {
+        for pass in 0..3 {
+            for (columns, rows) in [(16, 1), (8, 2), (4, 4), (2, 8), (1, 16)] {
+                assert!(
+                    cells(SamplerKind::Sobol, pass, 16, columns, rows)
+                        .iter()
+                        .all(|&hits| hits == 3),
+                    "pass {pass} of {columns}x{rows}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn radical_inverse_mirrors_the_digits() {
+        let base_2: Vec<f64> = (0..5).map(|i| radical_inverse(2, i)).collect();
+        assert_eq!(base_2, [0.0, 0.5, 0.25, 0.75, 0.125]);
+        assert!((radical_inverse(3, 5) - (2.0 / 3.0 + 1.0 / 9.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn permute_shuffles_every_index_once() {
+        for len in [1, 2, 3, 7, 16, 100, 1000] {
+            for key in [0, 1, 0xdead_beef, u32::MAX] {
+                let mut seen = vec![false; len as usize];
+                for i in 0..len {
+                    let j = permute(i, len, key) as usize;
+                    assert!(!seen[j], "{j} twice in {len} with key {key}");
+                    seen[j] = true;
+                }
+            }
+        }
+        let order = |key| (0..16).map(|i| permute(i, 16, key)).collect::<Vec<u32>>();
+        assert_ne!(order(1), order(2));
+    }
+
+    // Pinned so a change to the generator, which would change every image, fails here first
+    #[test]
+    fn sample_rng_is_stable() {
+        let mut rng = sample_rng(0, 7, 3);
+        let numbers: Vec<u64> = (0..3).map(|_| rng.gen()).collect();
+        assert_eq!(
+            numbers,
+            [
+                1009878252717609169,
+                14816188824490974640,
+                6923126023691577825
+            ]
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{camera::Camera, light::Light, material::*, node::*};
+use crate::{camera::Camera, light::Light, material::*, node::*, tonemap::DisplayTransform};
 use std::collections::HashMap;
 
 #[derive(Clone)]
@@ -7,6 +7,8 @@ pub struct Scene {
     pub materials: HashMap<String, Material>,
     pub lights: HashMap<String, Light>,
     pub cameras: HashMap<String, Camera>,
+    // Display transform the scene was lit for, replaces the one in the options when loaded
+    pub display: Option<DisplayTransform>,
 }
 
 impl Scene {
@@ -17,10 +19,12 @@ impl Scene {
             materials: HashMap::new(),
             lights: HashMap::new(),
             cameras: HashMap::new(),
+            display: None,
         }
     }
     // Adds a node to the scene
-    pub fn add_node(&mut self, label: String, node: Node) {
+    pub fn add_node(&mut self, label: String, mut node: Node) {
+        node.label = label.clone();
         self.nodes.insert(label, node);
     }
     // Adds a material to the scene
@@ -35,9 +39,36 @@ impl Scene {
     pub fn add_camera(&mut self, label: String, camera: Camera) {
         self.cameras.insert(label, camera);
     }
+    // Sets the display transform to view the scene with
+    pub fn set_display(&mut self, display: DisplayTransform) {
+        self.display = Some(display);
+    }
+    // Copy of the scene with every keyframed value set to its value at time
+    pub fn at_time(&self, time: f64) -> Scene {
+        let mut scene = self.clone();
+        for node in scene.nodes.values_mut() {
+            node.pose(time);
+        }
+        for material in scene.materials.values_mut() {
+            material.pose(time);
+        }
+        for light in scene.lights.values_mut() {
+            light.pose(time);
+        }
+        for camera in scene.cameras.values_mut() {
+            camera.pose(time);
+        }
+        scene
+    }
+    // Open the shutter of every node from open to close seconds after the scene time
+    pub fn set_shutter(&mut self, open: f64, close: f64) {
+        for node in self.nodes.values_mut() {
+            node.set_shutter(open, close);
+        }
+    }
     // Compute all matricies for nodes
     pub fn compute(&mut self) {
-        for (_, node) in &mut self.nodes {
+        for node in self.nodes.values_mut() {
             node.compute();
         }
     }
@@ -0,0 +1,434 @@
+use crate::{
+    animation::Interpolation,
+    bsdf::Conductor,
+    camera::{Camera, Projection},
+    light::Light,
+    material::*,
+    node::*,
+    primitive::*,
+    scene::*,
+    tonemap::{DisplayTransform, ToneMapper},
+};
+use nalgebra::{Point3, Vector3};
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope};
+
+/// Evaluate a scene script with `time` defined as a constant in its scope
+pub fn eval_scene(engine: &Engine, script: &str, time: f64) -> Result<Scene, Box<EvalAltResult>> {
+    let mut scope = Scope::new();
+    scope.push_constant("time", time);
+    engine.eval_with_scope(&mut scope, script)
+}
+
+// Interpolation named in a script, e.g. node.keyframe(1.0, "bezier")
+fn interpolation(name: &str) -> Result<Interpolation, Box<EvalAltResult>> {
+    Interpolation::from_name(name).ok_or_else(|| {
+        format!("unknown interpolation '{name}', expected step, linear or bezier").into()
+    })
+}
+
+// Tone mapper named in a script, e.g. Display(1.0, "aces")
+fn tone_mapper(name: &str) -> Result<ToneMapper, Box<EvalAltResult>> {
+    ToneMapper::from_name(name).ok_or_else(|| {
+        format!("unknown tone mapper '{name}', expected clamp, reinhard, aces or hable").into()
+    })
+}
+
+// Projection named in a script, e.g. camera.projection = "fisheye"
+fn projection(name: &str) -> Result<Projection, Box<EvalAltResult>> {
+    Projection::from_name(name).ok_or_else(|| {
+        format!(
+            "unknown projection '{name}', expected perspective, orthographic, fisheye or equirectangular"
+        )
+        .into()
+    })
+}
+
+// Metal named in a script, e.g. MaterialGGX("gold", 0.2)
+fn metal(name: &str, roughness: f64) -> Result<Material, Box<EvalAltResult>> {
+    let (eta, k) = Conductor::preset(name)
+        .ok_or_else(|| format!("unknown metal '{name}', expected gold, copper or aluminium"))?;
+    Ok(Material::conductor(eta, k, roughness))
+}
+
+// Number in a map of options, integers are accepted too
+fn number(key: &str, value: &Dynamic) -> Result<f64, Box<EvalAltResult>> {
+    value
+        .as_float()
+        .or_else(|_| value.as_int().map(|i| i as f64))
+        .map_err(|_| format!("camera option '{key}' must be a number").into())
+}
+
+// Camera with the optional parameters of a map applied, e.g.
+// Camera(eye, target, up, #{ fov: 50.0, shift_y: 0.1, near: 0.5 })
+fn camera_with_options(
+    eye: Point3<f64>,
+    target: Point3<f64>,
+    up: Vector3<f64>,
+    options: Map,
+) -> Result<Camera, Box<EvalAltResult>> {
+    let mut camera = Camera::new(eye, target, up);
+    for (key, value) in options.iter() {
+        match key.as_str() {
+            "fov" => camera.fov = number(key, value)?,
+            "aspect" => camera.aspect = number(key, value)?,
+            "shift_x" => camera.shift.x = number(key, value)?,
+            "shift_y" => camera.shift.y = number(key, value)?,
+            "near" => camera.near = number(key, value)?,
+            "aperture" => camera.aperture = number(key, value)?,
+            "focus" => camera.focus_distance = number(key, value)?,
+            "blades" => camera.blades = number(key, value)?.max(0.0) as u32,
+            "blade_rotation" => camera.blade_rotation = number(key, value)?,
+            "view_width" => camera.view_width = number(key, value)?,
+            "fisheye_fov" => camera.fisheye_fov = number(key, value)?,
+            "shutter_open" => camera.shutter_open = number(key, value)?,
+            "shutter_close" => camera.shutter_close = number(key, value)?,
+            "projection" => {
+                let name = value
+                    .clone()
+                    .into_string()
+                    .map_err(|_| "camera option 'projection' must be a string".to_string())?;
+                camera.projection = projection(&name)?;
+            }
+            key => return Err(format!("unknown camera option '{key}'").into()),
+        }
+    }
+    Ok(camera)
+}
+
+/// Create a Rhai engine with every scene building type and function registered
+pub fn init_engine() -> Engine {
+    let mut engine = Engine::new();
+
+    engine
+        .register_type::<Vector3<f64>>()
+        .register_fn("V", Vector3::<f64>::new);
+    engine
+        .register_type::<Point3<f64>>()
+        .register_fn("P", Point3::<f64>::new);
+    engine
+        .register_type::<Camera>()
+        .register_fn("Camera", Camera::new)
+        .register_get_set("eye", |c: &mut Camera| c.eye, Camera::set_eye)
+        .register_get_set("target", |c: &mut Camera| c.target, Camera::set_target)
+        .register_get_set("up", |c: &mut Camera| c.up, Camera::set_up)
+        .register_fn("Camera", Camera::with_lens)
+        .register_fn("Camera", camera_with_options)
+        .register_fn(
+            "Camera",
+            |eye: Point3<f64>,
+             target: Point3<f64>,
+             up: Vector3<f64>,
+             aperture: f64,
+             focus_distance: f64,
+             blades: i64,
+             blade_rotation: f64| Camera {
+                blades: blades.max(0) as u32,
+                blade_rotation,
+                ..Camera::with_lens(eye, target, up, aperture, focus_distance)
+            },
+        )
+        .register_get_set(
+            "aperture",
+            |c: &mut Camera| c.aperture,
+            |c: &mut Camera, aperture: f64| c.aperture = aperture,
+        )
+        .register_get_set(
+            "focus",
+            |c: &mut Camera| c.focus_distance,
+            |c: &mut Camera, focus_distance: f64| c.focus_distance = focus_distance,
+        )
+        .register_get_set(
+            "blades",
+            |c: &mut Camera| c.blades as i64,
+            |c: &mut Camera, blades: i64| c.blades = blades.max(0) as u32,
+        )
+        .register_get_set(
+            "blade_rotation",
+            |c: &mut Camera| c.blade_rotation,
+            |c: &mut Camera, rotation: f64| c.blade_rotation = rotation,
+        )
+        .register_get_set(
+            "fov",
+            |c: &mut Camera| c.fov,
+            |c: &mut Camera, fov: f64| c.fov = fov,
+        )
+        .register_get_set(
+            "aspect",
+            |c: &mut Camera| c.aspect,
+            |c: &mut Camera, aspect: f64| c.aspect = aspect,
+        )
+        .register_get_set(
+            "shift_x",
+            |c: &mut Camera| c.shift.x,
+            |c: &mut Camera, shift: f64| c.shift.x = shift,
+        )
+        .register_get_set(
+            "shift_y",
+            |c: &mut Camera| c.shift.y,
+            |c: &mut Camera, shift: f64| c.shift.y = shift,
+        )
+        .register_get_set(
+            "near",
+            |c: &mut Camera| c.near,
+            |c: &mut Camera, near: f64| c.near = near,
+        )
+        .register_get_set(
+            "shutter_open",
+            |c: &mut Camera| c.shutter_open,
+            |c: &mut Camera, open: f64| c.shutter_open = open,
+        )
+        .register_get_set(
+            "shutter_close",
+            |c: &mut Camera| c.shutter_close,
+            |c: &mut Camera, close: f64| c.shutter_close = close,
+        )
+        .register_get_set(
+            "projection",
+            |c: &mut Camera| c.projection.name().to_string(),
+            |c: &mut Camera, name: String| -> Result<(), Box<EvalAltResult>> {
+                c.projection = projection(&name)?;
+                Ok(())
+            },
+        )
+        .register_get_set(
+            "view_width",
+            |c: &mut Camera| c.view_width,
+            |c: &mut Camera, width: f64| c.view_width = width,
+        )
+        .register_get_set(
+            "fisheye_fov",
+            |c: &mut Camera| c.fisheye_fov,
+            |c: &mut Camera, fov: f64| c.fisheye_fov = fov,
+        )
+        .register_fn("keyframe", |c: &mut Camera, t: f64| {
+            c.keyframe(t, Interpolation::Linear)
+        })
+        .register_fn(
+            "keyframe",
+            |c: &mut Camera, t: f64, name: &str| -> Result<(), Box<EvalAltResult>> {
+                c.keyframe(t, interpolation(name)?);
+                Ok(())
+            },
+        );
+    engine
+        .register_type::<Scene>()
+        .register_fn("Scene", Scene::empty)
+        .register_fn("addNode", Scene::add_node)
+        .register_fn("addLight", Scene::add_light)
+        .register_fn("addCamera", Scene::add_camera)
+        .register_fn("addMaterial", Scene::add_material)
+        .register_fn("setDisplay", Scene::set_display);
+    engine
+        .register_type::<DisplayTransform>()
+        .register_fn("Display", DisplayTransform::default)
+        .register_fn(
+            "Display",
+            |exposure: f64, name: &str| -> Result<DisplayTransform, Box<EvalAltResult>> {
+                Ok(DisplayTransform {
+                    exposure: exposure as f32,
+                    tone_mapper: tone_mapper(name)?,
+                    ..DisplayTransform::default()
+                })
+            },
+        )
+        .register_get_set(
+            "exposure",
+            |d: &mut DisplayTransform| d.exposure as f64,
+            |d: &mut DisplayTransform, exposure: f64| d.exposure = exposure as f32,
+        )
+        .register_get_set(
+            "tonemap",
+            |d: &mut DisplayTransform| d.tone_mapper.name().to_string(),
+            |d: &mut DisplayTransform, name: String| -> Result<(), Box<EvalAltResult>> {
+                d.tone_mapper = tone_mapper(&name)?;
+                Ok(())
+            },
+        )
+        .register_get_set(
+            "srgb",
+            |d: &mut DisplayTransform| d.srgb,
+            |d: &mut DisplayTransform, srgb: bool| d.srgb = srgb,
+        );
+
+    engine
+        .register_type::<Node>()
+        .register_fn("Node", Node::new)
+        .register_fn("translate", Node::translate)
+        .register_fn("rotate", Node::rotate)
+        .register_fn("scale", Node::scale)
+        .register_fn("child", Node::child)
+        .register_fn("active", Node::set_active)
+        .register_fn("emit", Node::emit)
+        .register_get_set(
+            "emission",
+            |n: &mut Node| n.emission.cast::<f64>(),
+            |n: &mut Node, c: Vector3<f64>| n.emission = c.cast(),
+        )
+        .register_get_set(
+            "strength",
+            |n: &mut Node| n.strength as f64,
+            |n: &mut Node, s: f64| n.strength = s.max(0.0) as f32,
+        )
+        .register_fn("keyframe", |n: &mut Node, t: f64| {
+            n.keyframe(t, Interpolation::Linear)
+        })
+        .register_fn(
+            "keyframe",
+            |n: &mut Node, t: f64, name: &str| -> Result<(), Box<EvalAltResult>> {
+                n.keyframe(t, interpolation(name)?);
+                Ok(())
+            },
+        );
+    engine
+        .register_type::<Light>()
+        .register_fn("Light", Light::new)
+        .register_fn("Ambient", Light::ambient)
+        .register_fn("active", Light::set_active)
+        .register_get_set(
+            "position",
+            |l: &mut Light| l.position,
+            |l: &mut Light, p: Point3<f64>| l.position = p,
+        )
+        .register_get_set(
+            "colour",
+            |l: &mut Light| l.colour.cast::<f64>(),
+            |l: &mut Light, c: Vector3<f64>| l.colour = c.cast(),
+        )
+        .register_get_set(
+            "falloff",
+            |l: &mut Light| l.falloff.cast::<f64>(),
+            |l: &mut Light, f: Vector3<f64>| l.falloff = f.cast(),
+        )
+        .register_get_set(
+            "radius",
+            |l: &mut Light| l.radius,
+            |l: &mut Light, r: f64| l.radius = r.max(0.0),
+        )
+        .register_fn("keyframe", |l: &mut Light, t: f64| {
+            l.keyframe(t, Interpolation::Linear)
+        })
+        .register_fn(
+            "keyframe",
+            |l: &mut Light, t: f64, name: &str| -> Result<(), Box<EvalAltResult>> {
+                l.keyframe(t, interpolation(name)?);
+                Ok(())
+            },
+        );
+    engine
+        .register_type::<Material>()
+        .register_fn("Material", Material::new)
+        .register_fn("Material", Material::transparent)
+        .register_fn("MaterialRed", Material::red)
+        .register_fn("MaterialBlue", Material::blue)
+        .register_fn("MaterialGreen", Material::green)
+        .register_fn("MaterialMagenta", Material::magenta)
+        .register_fn("MaterialTurquoise", Material::turquoise)
+        .register_fn("MaterialLambertian", Material::lambertian)
+        .register_fn("MaterialOrenNayar", Material::oren_nayar)
+        .register_fn("MaterialGGX", metal)
+        .register_fn("MaterialGGX", Material::conductor)
+        .register_fn("MaterialDielectric", Material::dielectric)
+        .register_fn("MaterialPlastic", Material::plastic)
+        .register_get("model", |m: &mut Material| m.model.name().to_string())
+        .register_get_set(
+            "kd",
+            |m: &mut Material| m.kd.cast::<f64>(),
+            |m: &mut Material, kd: Vector3<f64>| m.kd = kd.cast(),
+        )
+        .register_get_set(
+            "ks",
+            |m: &mut Material| m.ks.cast::<f64>(),
+            |m: &mut Material, ks: Vector3<f64>| m.ks = ks.cast(),
+        )
+        .register_get_set(
+            "kr",
+            |m: &mut Material| m.kr.cast::<f64>(),
+            |m: &mut Material, kr: Vector3<f64>| m.kr = kr.cast(),
+        )
+        .register_get_set(
+            "kt",
+            |m: &mut Material| m.kt.cast::<f64>(),
+            |m: &mut Material, kt: Vector3<f64>| m.kt = kt.cast(),
+        )
+        .register_get_set(
+            "shininess",
+            |m: &mut Material| m.shininess as f64,
+            |m: &mut Material, s: f64| m.shininess = s as f32,
+        )
+        .register_get_set(
+            "ior",
+            |m: &mut Material| m.ior as f64,
+            |m: &mut Material, ior: f64| m.ior = ior as f32,
+        )
+        .register_get_set(
+            "absorption",
+            |m: &mut Material| m.absorption.cast::<f64>(),
+            |m: &mut Material, a: Vector3<f64>| m.absorption = a.cast(),
+        )
+        .register_fn("keyframe", |m: &mut Material, t: f64| {
+            m.keyframe(t, Interpolation::Linear)
+        })
+        .register_fn(
+            "keyframe",
+            |m: &mut Material, t: f64, name: &str| -> Result<(), Box<EvalAltResult>> {
+                m.keyframe(t, interpolation(name)?);
+                Ok(())
+            },
+        );
+    engine
+        .register_type::<Sphere>()
+        .register_fn("Sphere", Sphere::new)
+        .register_fn("SphereUnit", Sphere::unit);
+    engine
+        .register_type::<Cube>()
+        .register_fn("Cube", Cube::new)
+        .register_fn("CubeUnit", Cube::unit);
+    engine
+        .register_type::<Triangle>()
+        .register_fn("Triangle", Triangle::new)
+        .register_fn("TriangleUnit", Triangle::unit);
+    engine
+        .register_type::<Cone>()
+        .register_fn("Cone", Cone::new)
+        .register_fn("ConeUnit", Cone::unit);
+    engine
+        .register_type::<Cylinder>()
+        .register_fn("Cylinder", Cylinder::new);
+    engine
+        .register_type::<Circle>()
+        .register_fn("Circle", Circle::new)
+        .register_fn("CircleUnit", Circle::unit);
+    engine
+        .register_type::<Cube>()
+        .register_fn("Cube", Cube::new)
+        .register_fn("CubeUnit", Cube::unit);
+    engine
+        .register_type::<Steiner>()
+        .register_fn("Steiner", Steiner::new);
+    engine
+        .register_type::<Steiner2>()
+        .register_fn("Steiner2", Steiner2::new);
+    engine
+        .register_type::<Roman>()
+        .register_fn("Roman", Roman::new);
+    engine
+        .register_type::<CrossCap>()
+        .register_fn("CrossCap", CrossCap::new);
+    engine
+        .register_type::<CrossCap2>()
+        .register_fn("CrossCap2", CrossCap2::new);
+    engine
+        .register_type::<Torus>()
+        .register_fn("Torus", Torus::new);
+    engine
+        .register_type::<Gnonom>()
+        .register_fn("Gnonom", Gnonom::new);
+    engine
+        .register_type::<Mesh>()
+        .register_fn("Mesh", Mesh::from_file);
+    engine
+        .register_type::<RectangleXY>()
+        .register_fn("Rectange", RectangleXY::new)
+        .register_fn("RectangleUnit", RectangleXY::unit);
+    engine
+}
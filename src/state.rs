@@ -1,27 +1,16 @@
 //Use linear algebra module
 
-use crate::bvh::BVH;
-use crate::camera::Camera;
-use crate::ray::Ray;
-use crate::{gui::Gui, scene::Scene};
-use crate::{gui::GuiEvent, log_error};
+use crate::{gui::Gui, gui::GuiEvent, log_error};
+use rust_opengl::{aov::Aov, Camera, Renderer, Scene};
 use std::collections::HashSet;
-use std::path::Path;
-use std::thread;
-
-use nalgebra::Vector3;
-use rand::seq::SliceRandom;
-use rand::{random, thread_rng};
 
 use std::error::Error;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{mpsc, Arc, Mutex};
 
 use anyhow::Result;
 use pixels::{Pixels, SurfaceTexture};
 use winit::dpi::{LogicalSize, PhysicalSize};
 use winit::event::{
-    ElementState, Event, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent,
+    ElementState, Event, KeyboardInput, ModifiersState, MouseButton, VirtualKeyCode, WindowEvent,
 };
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::{Window, WindowBuilder};
@@ -31,107 +20,50 @@ const START_HEIGHT: i32 = 700;
 
 pub const INIT_FILE: &str = "rhai/scene.rhai";
 pub const SAVE_FILE: &str = "img.png";
-
-#[derive(Clone)]
-pub struct RaytracingOption {
-    pub threads: u32,
-    pub ray_samples: u32,
-    pub ray_randomness: f64,
-    pub clear_color: [u8; 4],
-    pub pixel_clear: [u8; 4],
-    pub pixels_per_thread: u32,
-    pub buffer_proportion: f32,
-    pub buffer_fov: f64,
-    pub ray_depth: u8,
-    pub diffuse_rays: u8,
-    pub diffuse_coefficient: f32,
-    pub bvh_active: bool,
-    pub shadows: bool,
-    pub diffuse: bool,
-    pub reflect: bool,
-    pub specular: bool,
-    pub falloff: bool,
-}
-impl RaytracingOption {
-    pub fn default() -> RaytracingOption {
-        RaytracingOption {
-            threads: 12,
-            ray_samples: 1,
-            ray_randomness: 700.0,
-            clear_color: [0x22, 0x00, 0x11, 0x55],
-            pixel_clear: [0x11, 0x00, 0x22, 0x55],
-            pixels_per_thread: 100,
-            buffer_proportion: 1.0,
-            buffer_fov: 70.0,
-            ray_depth: 1,
-            diffuse_rays: 3,
-            diffuse_coefficient: 0.1,
-            bvh_active: false,
-            shadows: true,
-            diffuse: true,
-            reflect: true,
-            specular: true,
-            falloff: true,
-        }
-    }
-}
+pub const INSPECT_FILE: &str = "ray.json";
 
 const CAMERA_MOVE_SPEED: f64 = 0.15;
 const CAMERA_ORBIT_SPEED: f64 = 0.005;
 
 pub struct State {
-    scene: Arc<Scene>,
-    bvh: Arc<Option<BVH>>,
+    renderer: Renderer,
     camera: Camera,
     window: Window,
 
-    buffer_width: u32,
-    buffer_height: u32,
-
     pixels: Pixels,
     gui: Gui,
 
-    rays: Arc<Vec<Ray>>,
-    ray_queue: Arc<Mutex<Vec<usize>>>,
-    raytracing_options: Arc<RaytracingOption>,
-
-    result_rx: mpsc::Receiver<Vec<(usize, [u8; 4])>>,
-    render_active: Arc<AtomicBool>,
-    rendering: bool,
-
     keys_pressed: HashSet<VirtualKeyCode>,
     right_mouse_down: bool,
     last_mouse_pos: Option<(f64, f64)>,
+    cursor_pos: Option<(f64, f64)>,
+    modifiers: ModifiersState,
     camera_dirty: bool,
 }
 
 impl State {
     pub fn new(window: Window, pixels: Pixels, gui: Gui) -> Self {
-        let scene = Arc::new(Scene::empty());
         let window_size = window.inner_size();
-        let pixels = pixels;
         let camera = Camera::unit();
-        let rays = Arc::new(Vec::new());
-        let (_tx, rx) = mpsc::channel();
+        let renderer = Renderer::new(
+            Scene::empty(),
+            camera.clone(),
+            gui.raytracing_option().clone(),
+            window_size.width,
+            window_size.height,
+        );
 
         Self {
-            scene,
-            bvh: Arc::new(None),
+            renderer,
             camera,
             window,
-            buffer_width: window_size.width as u32,
-            buffer_height: window_size.height as u32,
             pixels,
             gui,
-            rays,
-            ray_queue: Arc::new(Mutex::new(Vec::new())),
-            raytracing_options: Arc::new(RaytracingOption::default()),
-            result_rx: rx,
-            render_active: Arc::new(AtomicBool::new(false)),
-            rendering: false,
             keys_pressed: HashSet::new(),
             right_mouse_down: false,
             last_mouse_pos: None,
+            cursor_pos: None,
+            modifiers: ModifiersState::empty(),
             camera_dirty: false,
         }
     }
@@ -140,41 +72,45 @@ impl State {
         if let Some(event) = self.gui.event.take() {
             match event {
                 GuiEvent::RaytracerOption(options) => {
-                    self.raytracing_options = Arc::new(options);
-                    match self.raytracing_options.bvh_active {
-                        true => self.bvh = Arc::new(Some(BVH::build(&self.scene.nodes))),
-                        false => self.bvh = Arc::new(None),
-                    }
+                    self.renderer.set_options(options);
                     self.resize_buffer()?
                 }
                 GuiEvent::CameraUpdate(camera) => {
-                    self.rays = Arc::new(Ray::cast_rays(
-                        &camera.eye,
-                        &camera.target,
-                        &camera.up,
-                        self.raytracing_options.buffer_fov,
-                        self.buffer_width,
-                        self.buffer_height,
-                    ));
+                    self.renderer.set_camera(camera.clone());
                     self.camera = camera;
-                    self.clear_buffer()?;
-                    self.reset_queue();
+                    self.restart_render();
                 }
                 GuiEvent::SceneLoad(scene) => {
-                    self.scene = Arc::new(scene);
-                    self.clear_buffer()?;
-                    self.reset_queue();
+                    self.renderer.set_scene(scene);
+                    // The scene may bring its own display transform
+                    self.gui.update_display(self.renderer.options().display);
+                    self.restart_render();
                 }
-                GuiEvent::SaveImage(filename) => {
-                    let frame = self.pixels.frame();
-                    image::save_buffer(
-                        Path::new(&filename),
-                        frame,
-                        self.buffer_width,
-                        self.buffer_height,
-                        image::ColorType::Rgba8,
-                    )?
+                GuiEvent::SaveImage(filename) => self.renderer.save_image(&filename)?,
+                GuiEvent::DisplayUpdate(display) => {
+                    self.renderer.set_display(display);
+                    self.pixels
+                        .frame_mut()
+                        .copy_from_slice(self.renderer.framebuffer());
+                }
+                GuiEvent::AovView(aov) => {
+                    self.renderer.set_view(aov);
+                    self.pixels
+                        .frame_mut()
+                        .copy_from_slice(self.renderer.framebuffer());
+                }
+                GuiEvent::SaveAovs(filename) => {
+                    let path = std::path::Path::new(&filename);
+                    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                    for aov in Aov::ALL.into_iter().filter(|aov| *aov != Aov::Beauty) {
+                        let aov_path = path.with_file_name(match path.extension() {
+                            Some(ext) => format!("{stem}_{}.{}", aov.name(), ext.to_string_lossy()),
+                            None => format!("{stem}_{}", aov.name()),
+                        });
+                        self.renderer.save_aov(aov, aov_path)?;
+                    }
                 }
+                GuiEvent::SaveLayers(filename) => self.renderer.save_exr_layers(&filename)?,
             }
         };
         Ok(())
@@ -183,29 +119,17 @@ impl State {
     fn resize_buffer(&mut self) -> Result<(), Box<dyn Error>> {
         // Calculate new buffer dimensions based on proportion
         let size = self.window.inner_size();
-        let proportion = &self.raytracing_options.buffer_proportion;
-        let fovy = self.raytracing_options.buffer_fov;
-        self.buffer_width = (size.width as f32 * proportion) as u32;
-        self.buffer_height = (size.height as f32 * proportion) as u32;
-
-        // Clear the buffer and reset the ray queue
-        self.clear_buffer()?;
-        self.reset_queue();
-
-        // Recalculate rays with new buffer dimensions
-        self.rays = Arc::new(Ray::cast_rays(
-            &self.camera.eye,
-            &self.camera.target,
-            &self.camera.up,
-            fovy,
-            self.buffer_width,
-            self.buffer_height,
-        ));
+        let proportion = self.renderer.options().buffer_proportion;
+        let buffer_width = (size.width as f32 * proportion) as u32;
+        let buffer_height = (size.height as f32 * proportion) as u32;
 
         // Resize buffer and surface
+        self.renderer.resize(buffer_width, buffer_height);
         self.pixels.resize_surface(size.width, size.height)?;
-        self.pixels
-            .resize_buffer(self.buffer_width, self.buffer_height)?;
+        self.pixels.resize_buffer(buffer_width, buffer_height)?;
+
+        // Clear the buffer and start tracing the new frame
+        self.restart_render();
 
         Ok(())
     }
@@ -229,6 +153,17 @@ impl State {
     }
 
     fn mouse_input(&mut self, button: &MouseButton, state: &ElementState) {
+        // Left click selects the node under the cursor and shift-click records the rays
+        // traced through the pixel, unless the cursor is over the GUI
+        if *button == MouseButton::Left
+            && *state == ElementState::Pressed
+            && !self.gui.wants_mouse()
+        {
+            match self.modifiers.shift() {
+                true => self.inspect(),
+                false => self.pick(),
+            }
+        }
         if *button == MouseButton::Right {
             self.right_mouse_down = *state == ElementState::Pressed;
             if !self.right_mouse_down {
@@ -238,20 +173,42 @@ impl State {
     }
 
     fn cursor_moved(&mut self, x: f64, y: f64) {
+        self.cursor_pos = Some((x, y));
         if self.right_mouse_down {
             if let Some((last_x, last_y)) = self.last_mouse_pos {
                 let dx = x - last_x;
                 let dy = y - last_y;
-                self.camera.orbit(
-                    -dx * CAMERA_ORBIT_SPEED,
-                    -dy * CAMERA_ORBIT_SPEED,
-                );
+                self.camera
+                    .orbit(-dx * CAMERA_ORBIT_SPEED, -dy * CAMERA_ORBIT_SPEED);
                 self.camera_dirty = true;
             }
             self.last_mouse_pos = Some((x, y));
         }
     }
 
+    // Framebuffer pixel under the cursor, None outside the image
+    fn cursor_pixel(&self) -> Option<(u32, u32)> {
+        let (x, y) = self.cursor_pos?;
+        let (x, y) = self.pixels.window_pos_to_pixel((x as f32, y as f32)).ok()?;
+        Some((x as u32, y as u32))
+    }
+
+    // Select the node seen through the framebuffer pixel under the cursor
+    fn pick(&mut self) {
+        if let Some((x, y)) = self.cursor_pixel() {
+            let pick = self.renderer.pick(x, y);
+            self.gui.select(pick);
+        }
+    }
+
+    // Trace the pixel under the cursor again, recording the tree of rays behind its colour
+    fn inspect(&mut self) {
+        if let Some((x, y)) = self.cursor_pixel() {
+            let record = self.renderer.inspect(x, y);
+            self.gui.inspect(x, y, record);
+        }
+    }
+
     fn process_camera_movement(&mut self) {
         let speed = CAMERA_MOVE_SPEED;
 
@@ -282,153 +239,38 @@ impl State {
 
         if self.camera_dirty {
             self.camera_dirty = false;
-            self.rays = Arc::new(Ray::cast_rays(
-                &self.camera.eye,
-                &self.camera.target,
-                &self.camera.up,
-                self.raytracing_options.buffer_fov,
-                self.buffer_width,
-                self.buffer_height,
-            ));
+            self.renderer.set_camera(self.camera.clone());
             self.gui.update_camera(&self.camera);
-            let _ = self.clear_buffer();
-            self.reset_queue();
+            self.restart_render();
         }
     }
 
     fn draw(&mut self) {
-        if !self.rendering {
+        if !self.renderer.is_rendering() {
             return;
         }
 
-        // Drain completed results from background workers
-        loop {
-            match self.result_rx.try_recv() {
-                Ok(results) => {
-                    let frame = self.pixels.frame_mut();
-                    for (index, rgba) in results {
-                        frame[index * 4..(index + 1) * 4].copy_from_slice(&rgba);
-                    }
-                }
-                Err(mpsc::TryRecvError::Empty) => break,
-                Err(mpsc::TryRecvError::Disconnected) => {
-                    // All worker threads have finished
-                    self.rendering = false;
-                    self.gui.stop_render_timer();
-                    break;
-                }
-            }
-        }
-    }
-
-    fn clear_buffer(&mut self) -> Result<(), Box<dyn Error>> {
+        // Drain completed tiles from background workers
         let frame = self.pixels.frame_mut();
-        for pixel in frame.chunks_exact_mut(4) {
-            pixel.copy_from_slice(&self.raytracing_options.pixel_clear);
+        let rendering = self.renderer.poll(|tile| {
+            for (index, rgba) in &tile.pixels {
+                frame[index * 4..(index + 1) * 4].copy_from_slice(rgba);
+            }
+        });
+        self.gui.update_render_progress(self.renderer.progress());
+        if !rendering {
+            // All worker threads have finished
+            self.gui.stop_render_timer();
         }
-        Ok(())
     }
 
-    fn reset_queue(&mut self) {
-        // Signal any existing workers to stop
-        self.render_active.store(false, Ordering::Relaxed);
-
-        match self.raytracing_options.bvh_active {
-            true => self.bvh = Arc::new(Some(BVH::build(&self.scene.nodes))),
-            false => self.bvh = Arc::new(None),
-        }
-
-        // Create new shuffled queue
-        let size = self.buffer_height as usize * self.buffer_width as usize;
-        let mut ray_queue: Vec<usize> = (0..size).collect();
-        ray_queue.shuffle(&mut thread_rng());
-        self.ray_queue = Arc::new(Mutex::new(ray_queue));
-
-        // Create new channel and active flag
-        let (tx, rx) = mpsc::channel();
-        self.result_rx = rx;
-        let render_active = Arc::new(AtomicBool::new(true));
-        self.render_active = render_active.clone();
-        self.rendering = true;
-
-        // Spawn persistent worker threads
-        let num_threads = self.raytracing_options.threads;
-        let pixels_per_thread = self.raytracing_options.pixels_per_thread;
-
-        for _ in 0..num_threads {
-            let rays = self.rays.clone();
-            let scene = self.scene.clone();
-            let options = self.raytracing_options.clone();
-            let bvh = self.bvh.clone();
-            let queue = self.ray_queue.clone();
-            let tx = tx.clone();
-            let active = render_active.clone();
-
-            thread::spawn(move || {
-                let randomness = options.ray_randomness;
-                let samples = options.ray_samples;
-                let samples_f32 = samples as f32;
-
-                loop {
-                    if !active.load(Ordering::Relaxed) {
-                        break;
-                    }
-
-                    // Pop a batch from the shared queue
-                    let load: Vec<usize> = {
-                        let mut q = queue.lock().unwrap();
-                        let mut batch = Vec::with_capacity(pixels_per_thread as usize);
-                        for _ in 0..pixels_per_thread {
-                            match q.pop() {
-                                Some(index) => batch.push(index),
-                                None => break,
-                            }
-                        }
-                        batch
-                    };
-
-                    if load.is_empty() {
-                        break;
-                    }
-
-                    // Process the batch
-                    let mut results = Vec::with_capacity(load.len());
-                    for index in &load {
-                        let mut colour: Vector3<f32> = Vector3::zeros();
-                        let ray = &rays[*index];
-                        for _ in 0..samples {
-                            let point = ray.a;
-                            let dir = ray.b;
-                            let rx = (random::<f64>() - 0.5) / randomness;
-                            let ry = (random::<f64>() - 0.5) / randomness;
-                            let rz = (random::<f64>() - 0.5) / randomness;
-                            let nx = dir.x + rx;
-                            let ny = dir.y + ry;
-                            let nz = dir.z + rz;
-
-                            let rand_ray = Ray::new(point, Vector3::new(nx, ny, nz));
-
-                            if let Some(ray_colour) =
-                                rand_ray.shade_ray(&scene, 0, &options, &bvh)
-                            {
-                                colour += ray_colour;
-                            }
-                        }
-                        colour = (colour / samples_f32) * 255.0;
-                        let rgba = [colour.x as u8, colour.y as u8, colour.z as u8, 0xff];
-                        results.push((*index, rgba));
-                    }
-
-                    // Send results back to main thread
-                    if tx.send(results).is_err() {
-                        break;
-                    }
-                }
-            });
-        }
-        // Drop our copy of tx so the channel disconnects when all workers finish
-        drop(tx);
-
+    // Clear the frame and begin tracing it again from scratch
+    fn restart_render(&mut self) {
+        self.renderer.clear();
+        self.pixels
+            .frame_mut()
+            .copy_from_slice(self.renderer.framebuffer());
+        self.renderer.start();
         self.gui.start_render_timer();
     }
 
@@ -477,12 +319,15 @@ pub fn run() -> Result<(), Box<dyn Error>> {
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
                 WindowEvent::Resized(size) => state.resize(&size).expect("Window Resize Error"),
                 WindowEvent::KeyboardInput { input, .. } => state.keyboard_input(&input),
-                WindowEvent::MouseInput { button, state: elem_state, .. } => {
-                    state.mouse_input(&button, &elem_state)
-                }
+                WindowEvent::MouseInput {
+                    button,
+                    state: elem_state,
+                    ..
+                } => state.mouse_input(&button, &elem_state),
                 WindowEvent::CursorMoved { position, .. } => {
                     state.cursor_moved(position.x, position.y)
                 }
+                WindowEvent::ModifiersChanged(modifiers) => state.modifiers = modifiers,
                 _ => {}
             },
 
@@ -0,0 +1,232 @@
+// TILE ORDER -----------------------------------------------------------------
+/// Order the tiles of a frame are handed out to the workers in
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TileOrder {
+    /// Row by row from the top left
+    Scanline,
+    /// Outwards from the centre of the frame, ring by ring
+    Spiral,
+    /// Along a Hilbert curve, neighbouring tiles are traced close together in time
+    Hilbert,
+}
+
+impl TileOrder {
+    pub const ALL: [TileOrder; 3] = [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert];
+
+    /// Parse the names used on the command line, "scanline", "spiral" or "hilbert"
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name.to_lowercase().as_str() {
+            "scanline" => Some(TileOrder::Scanline),
+            "spiral" => Some(TileOrder::Spiral),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TileOrder::Scanline => "scanline",
+            TileOrder::Spiral => "spiral",
+            TileOrder::Hilbert => "hilbert",
+        }
+    }
+}
+
+// TILE RECT -----------------------------------------------------------------
+/// A rectangle of pixels in the framebuffer
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TileRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl TileRect {
+    /// Framebuffer indices of every pixel in the rectangle, row by row
+    pub fn indices(&self, frame_width: u32) -> impl Iterator<Item = usize> + '_ {
+        (self.y..self.y + self.height).flat_map(move |y| {
+            (self.x..self.x + self.width).map(move |x| (y * frame_width + x) as usize)
+        })
+    }
+
+    pub fn area(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    /// The rectangle grown by `margin` pixels on every side, kept inside the frame
+    pub fn expand(&self, margin: u32, frame_width: u32, frame_height: u32) -> TileRect {
+        let x = self.x.saturating_sub(margin);
+        let y = self.y.saturating_sub(margin);
+        TileRect {
+            x,
+            y,
+            width: (self.x + self.width + margin).min(frame_width) - x,
+            height: (self.y + self.height + margin).min(frame_height) - y,
+        }
+    }
+
+    /// If the pixel at x, y lies inside the rectangle
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+/// Split a frame into tiles of at most `size` pixels square, sorted into `order`
+pub fn schedule(width: u32, height: u32, size: u32, order: TileOrder) -> Vec<TileRect> {
+    let size = size.max(1);
+    let columns = width.div_ceil(size);
+    let rows = height.div_ceil(size);
+
+    let mut grid: Vec<(u32, u32)> = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .collect();
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => {
+            // Sort by the square ring around the centre, then by angle around it
+            let cx = (columns as f64 - 1.0) / 2.0;
+            let cy = (rows as f64 - 1.0) / 2.0;
+            grid.sort_by(|a, b| {
+                let key = |(column, row): &(u32, u32)| {
+                    let dx = *column as f64 - cx;
+                    let dy = *row as f64 - cy;
+                    (dx.abs().max(dy.abs()).round(), dy.atan2(dx))
+                };
+                let (ring_a, angle_a) = key(a);
+                let (ring_b, angle_b) = key(b);
+                ring_a.total_cmp(&ring_b).then(angle_a.total_cmp(&angle_b))
+            });
+        }
+        TileOrder::Hilbert => {
+            let n = columns.max(rows).next_power_of_two();
+            grid.sort_by_key(|(column, row)| hilbert_index(n, *column, *row));
+        }
+    }
+
+    grid.into_iter()
+        .map(|(column, row)| {
+            let x = column * size;
+            let y = row * size;
+            TileRect {
+                x,
+                y,
+                width: size.min(width - x),
+                height: size.min(height - y),
+            }
+        })
+        .collect()
+}
+
+// Distance along the Hilbert curve filling an n by n grid, n a power of two
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        d += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+        // Rotate the quadrant so the curve stays continuous
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frame sizes with square, wide, tall, non-power-of-two and partial edge tiles
+    const FRAMES: [(u32, u32, u32); 6] = [
+        (64, 64, 16),
+        (64, 48, 16),
+        (100, 37, 16),
+        (7, 300, 32),
+        (1, 1, 8),
+        (33, 17, 0),
+    ];
+
+    #[test]
+    fn every_order_covers_each_pixel_once() {
+        for order in TileOrder::ALL {
+            for (width, height, size) in FRAMES {
+                let tiles = schedule(width, height, size, order);
+                let size = size.max(1);
+                assert_eq!(
+                    tiles.len() as u32,
+                    width.div_ceil(size) * height.div_ceil(size),
+                    "{} {width}x{height}",
+                    order.name()
+                );
+                let mut visits = vec![0; (width * height) as usize];
+                for tile in &tiles {
+                    assert!(tile.width > 0 && tile.height > 0);
+                    assert!(tile.x + tile.width <= width && tile.y + tile.height <= height);
+                    for index in tile.indices(width) {
+                        visits[index] += 1;
+                    }
+                }
+                assert!(
+                    visits.iter().all(|&count| count == 1),
+                    "{} {width}x{height} does not visit every pixel once",
+                    order.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn scanline_goes_row_by_row() {
+        let tiles = schedule(100, 37, 16, TileOrder::Scanline);
+        let corners: Vec<(u32, u32)> = tiles.iter().map(|t| (t.y, t.x)).collect();
+        let mut sorted = corners.clone();
+        sorted.sort();
+        assert_eq!(corners, sorted);
+    }
+
+    #[test]
+    fn spiral_starts_in_the_centre() {
+        let tiles = schedule(80, 80, 16, TileOrder::Spiral);
+        assert_eq!((tiles[0].x, tiles[0].y), (32, 32));
+        // Every ring is finished before the next one starts
+        let ring = |t: &TileRect| (t.x / 16).abs_diff(2).max((t.y / 16).abs_diff(2));
+        assert!(tiles.windows(2).all(|w| ring(&w[0]) <= ring(&w[1])));
+    }
+
+    #[test]
+    fn hilbert_steps_between_neighbouring_tiles() {
+        let tiles = schedule(128, 128, 16, TileOrder::Hilbert);
+        for pair in tiles.windows(2) {
+            let step = pair[0].x.abs_diff(pair[1].x) + pair[0].y.abs_diff(pair[1].y);
+            assert_eq!(step, 16, "{:?} to {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn expand_stays_inside_the_frame() {
+        let tile = TileRect {
+            x: 0,
+            y: 32,
+            width: 16,
+            height: 16,
+        };
+        let grown = tile.expand(2, 40, 40);
+        assert_eq!(
+            grown,
+            TileRect {
+                x: 0,
+                y: 30,
+                width: 18,
+                height: 10
+            }
+        );
+        assert!(grown.contains(17, 39) && !grown.contains(18, 39));
+    }
+}
@@ -0,0 +1,201 @@
+use nalgebra::Vector3;
+
+// TONE MAPPER -----------------------------------------------------------------
+/// Curve compressing linear scene colour into the displayable 0 to 1 range
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ToneMapper {
+    /// Cut everything above 1.0
+    Clamp,
+    /// x / (1 + x), never reaches white
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve
+    Aces,
+    /// John Hable's filmic curve from Uncharted 2
+    Hable,
+}
+
+impl ToneMapper {
+    pub const ALL: [ToneMapper; 4] = [
+        ToneMapper::Clamp,
+        ToneMapper::Reinhard,
+        ToneMapper::Aces,
+        ToneMapper::Hable,
+    ];
+
+    /// Parse the names used from Rhai and the command line
+    pub fn from_name(name: &str) -> Option<ToneMapper> {
+        match name.to_lowercase().as_str() {
+            "clamp" | "none" => Some(ToneMapper::Clamp),
+            "reinhard" => Some(ToneMapper::Reinhard),
+            "aces" => Some(ToneMapper::Aces),
+            "hable" | "filmic" => Some(ToneMapper::Hable),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneMapper::Clamp => "clamp",
+            ToneMapper::Reinhard => "reinhard",
+            ToneMapper::Aces => "aces",
+            ToneMapper::Hable => "hable",
+        }
+    }
+
+    /// Map one linear channel into 0 to 1
+    pub fn map(&self, x: f32) -> f32 {
+        let x = x.max(0.0);
+        let mapped = match self {
+            ToneMapper::Clamp => x,
+            ToneMapper::Reinhard => x / (1.0 + x),
+            ToneMapper::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+            ToneMapper::Hable => {
+                // The curve is tuned for a white point of 11.2 and an exposure bias of 2
+                const WHITE: f32 = 11.2;
+                hable(x * 2.0) / hable(WHITE)
+            }
+        };
+        mapped.clamp(0.0, 1.0)
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    const A: f32 = 0.15; // Shoulder strength
+    const B: f32 = 0.50; // Linear strength
+    const C: f32 = 0.10; // Linear angle
+    const D: f32 = 0.20; // Toe strength
+    const E: f32 = 0.02; // Toe numerator
+    const F: f32 = 0.30; // Toe denominator
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+/// Encode a linear channel in 0 to 1 with the sRGB transfer function
+pub fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// DISPLAY TRANSFORM -----------------------------------------------------------------
+/// Turns the linear framebuffer into the RGBA8 pixels that are shown and saved
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DisplayTransform {
+    /// Exposure in stops, every +1 doubles the brightness
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+    /// Encode the output as sRGB rather than leaving it linear
+    pub srgb: bool,
+}
+
+impl DisplayTransform {
+    pub fn default() -> DisplayTransform {
+        DisplayTransform {
+            exposure: 0.0,
+            tone_mapper: ToneMapper::Clamp,
+            srgb: true,
+        }
+    }
+
+    /// Display colour in 0 to 1 for a linear scene colour
+    pub fn map(&self, colour: Vector3<f32>) -> Vector3<f32> {
+        let scale = self.exposure.exp2();
+        colour.map(|c| {
+            let mapped = self.tone_mapper.map(c * scale);
+            match self.srgb {
+                true => linear_to_srgb(mapped),
+                false => mapped,
+            }
+        })
+    }
+
+    /// RGBA8 pixel for a linear scene colour
+    pub fn to_rgba(&self, colour: Vector3<f32>) -> [u8; 4] {
+        let colour = self.map(colour) * 255.0;
+        [
+            colour.x.round() as u8,
+            colour.y.round() as u8,
+            colour.z.round() as u8,
+            0xff,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn black_stays_black() {
+        let mut display = DisplayTransform::default();
+        for mapper in ToneMapper::ALL {
+            // Hable's toe cancels to zero only up to rounding
+            assert_close(mapper.map(0.0), 0.0);
+            assert_close(mapper.map(-1.0), 0.0);
+            display.tone_mapper = mapper;
+            assert_eq!(display.to_rgba(Vector3::zeros()), [0, 0, 0, 0xff]);
+        }
+        assert_eq!(linear_to_srgb(0.0), 0.0);
+    }
+
+    #[test]
+    fn mappers_rise_steadily_into_the_display_range() {
+        for mapper in ToneMapper::ALL {
+            let mut previous = 0.0;
+            for i in 1..2000 {
+                let mapped = mapper.map(i as f32 * 0.01);
+                assert!(mapped >= previous, "{} falls at {i}", mapper.name());
+                assert!(mapped <= 1.0, "{} passes white at {i}", mapper.name());
+                previous = mapped;
+            }
+        }
+    }
+
+    #[test]
+    fn mappers_hit_known_values() {
+        assert_close(ToneMapper::Clamp.map(0.25), 0.25);
+        assert_close(ToneMapper::Clamp.map(4.0), 1.0);
+        assert_close(ToneMapper::Reinhard.map(1.0), 0.5);
+        assert_close(ToneMapper::Reinhard.map(3.0), 0.75);
+        assert_close(ToneMapper::Aces.map(1.0), 2.54 / 3.16);
+        // Hable reaches white at its white point, with the exposure bias of 2
+        assert_close(ToneMapper::Hable.map(5.6), 1.0);
+    }
+
+    #[test]
+    fn srgb_segments_meet_at_the_breakpoint() {
+        let breakpoint = 0.0031308;
+        let linear = breakpoint * 12.92;
+        let curve = 1.055 * f32::powf(breakpoint, 1.0 / 2.4) - 0.055;
+        assert_close(linear_to_srgb(breakpoint), linear);
+        assert_close(linear, curve);
+        assert_close(linear_to_srgb(0.001), 0.01292);
+        assert_close(linear_to_srgb(0.5), 0.735357);
+        assert_close(linear_to_srgb(1.0), 1.0);
+    }
+
+    #[test]
+    fn exposure_scales_by_stops() {
+        let mut display = DisplayTransform::default();
+        display.srgb = false;
+        let colour = Vector3::new(0.1, 0.2, 0.4);
+        for (exposure, scale) in [(0.0, 1.0), (1.0, 2.0), (-2.0, 0.25)] {
+            display.exposure = exposure;
+            let mapped = display.map(colour);
+            for c in 0..3 {
+                assert_close(mapped[c], colour[c] * scale);
+            }
+        }
+        display.exposure = 0.0;
+        assert_eq!(display.to_rgba(Vector3::repeat(1.0)), [255, 255, 255, 255]);
+        assert_eq!(display.to_rgba(Vector3::repeat(0.5)), [128, 128, 128, 255]);
+    }
+}
@@ -0,0 +1,246 @@
+//! Golden image regression tests
+//!
+//! Every script in `rhai/` is rendered at a low resolution and compared with the
+//! reference image of the same name in `tests/golden/`. A script with several cameras
+//! is rendered from each of them, into references named after the script and the camera
+//! label, such as `projections_fisheye.png`. A render fails if too many
+//! pixels are further than `PIXEL_TOLERANCE` from the reference, or if the PSNR of
+//! the whole image drops below `MIN_PSNR`. The render and a diff image of every
+//! failure are written to `target/tmp/golden/`.
+//!
+//! Scripts are rendered with the whitted defaults, apart from the few `options` lists,
+//! such as the path traced ones, which take a handful of passes from the fixed seed.
+//!
+//! After an intended change to the images, regenerate the references with
+//! `UPDATE_GOLDEN=1 cargo test --test golden` and check the new PNGs in.
+
+use rust_opengl::integrator::IntegratorKind;
+use rust_opengl::script::{eval_scene, init_engine};
+use rust_opengl::{Camera, RaytracingOption, Renderer};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 48;
+const SEED: u64 = 0;
+// Passes of the path traced scenes, enough to show their lighting through the noise
+const PATH_PASSES: u32 = 4;
+// Ray depth of the glass scene, deep enough to pass through both sides of each sphere
+const GLASS_DEPTH: u8 = 4;
+
+// Largest difference in any channel before a pixel counts as changed
+const PIXEL_TOLERANCE: u8 = 8;
+// Fraction of pixels allowed to change before the render fails
+const MAX_CHANGED_PIXELS: f64 = 0.005;
+// Peak signal to noise ratio below which the render fails, in dB
+const MIN_PSNR: f64 = 40.0;
+
+struct Comparison {
+    changed_pixels: usize,
+    psnr: f64,
+    diff: Vec<u8>,
+}
+
+#[test]
+fn golden_images() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let reference_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    fs::create_dir_all(&output_dir).unwrap();
+
+    let mut failures = Vec::new();
+    let mut names = Vec::new();
+    for script in scripts() {
+        let script_name = script.file_stem().unwrap().to_string_lossy().to_string();
+        for (name, image) in render(&script, options(&script_name)) {
+            names.push(name.clone());
+            if let Some(failure) = check(&name, &image, update, &reference_dir, &output_dir) {
+                failures.push(failure);
+            }
+        }
+    }
+
+    // A reference no render is compared with would silently stop being tested
+    for entry in fs::read_dir(&reference_dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        if !names.contains(&name) {
+            failures.push(format!(
+                "{}: no script renders it any more, remove it",
+                path.display()
+            ));
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "golden images differ:\n{}",
+        failures.join("\n")
+    );
+}
+
+// Compare one render with its reference, or replace the reference when updating,
+// returning why it failed
+fn check(
+    name: &str,
+    image: &[u8],
+    update: bool,
+    reference_dir: &Path,
+    output_dir: &Path,
+) -> Option<String> {
+    let reference_path = reference_dir.join(format!("{name}.png"));
+
+    // A flat image, such as a scene with no light, would not notice its shapes changing
+    if image.chunks_exact(4).all(|pixel| pixel == &image[..4]) {
+        return Some(format!("{name}: render is a single colour"));
+    }
+
+    if update {
+        fs::create_dir_all(reference_dir).unwrap();
+        save(&reference_path, image);
+        return None;
+    }
+
+    let reference = match image::open(&reference_path) {
+        Ok(reference) => reference.to_rgba8(),
+        Err(e) => {
+            return Some(format!(
+                "{name}: no reference at {} ({e}), run with UPDATE_GOLDEN=1 to create it",
+                reference_path.display()
+            ));
+        }
+    };
+    if reference.dimensions() != (WIDTH, HEIGHT) {
+        return Some(format!(
+            "{name}: reference is {:?}, expected {WIDTH}x{HEIGHT}",
+            reference.dimensions()
+        ));
+    }
+
+    let comparison = compare(image, reference.as_raw());
+    let changed = comparison.changed_pixels as f64 / (WIDTH * HEIGHT) as f64;
+    if changed <= MAX_CHANGED_PIXELS && comparison.psnr >= MIN_PSNR {
+        return None;
+    }
+    let actual_path = output_dir.join(format!("{name}.png"));
+    let diff_path = output_dir.join(format!("{name}_diff.png"));
+    save(&actual_path, image);
+    save(&diff_path, &comparison.diff);
+    Some(format!(
+        "{name}: {} pixels changed ({:.2}%), PSNR {:.1} dB, render at {}, diff at {}",
+        comparison.changed_pixels,
+        changed * 100.0,
+        comparison.psnr,
+        actual_path.display(),
+        diff_path.display()
+    ))
+}
+
+// Every scene script shipped in rhai/, sorted so failures are reported in a stable order
+fn scripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("rhai");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rhai"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+// Options a script is rendered with, the whitted defaults unless its scene needs others
+fn options(name: &str) -> RaytracingOption {
+    let mut options = RaytracingOption::default();
+    options.threads = 4;
+    options.seed = SEED;
+    if matches!(name, "path" | "sphere_light" | "area_light") {
+        options.integrator = IntegratorKind::Path;
+        options.passes = PATH_PASSES;
+    }
+    if name == "glass" {
+        options.ray_depth = GLASS_DEPTH;
+    }
+    options
+}
+
+// Render a script from each of its cameras, named after the script and the camera label
+// when it has several, or from the unit camera if it has none
+fn render(script: &Path, options: RaytracingOption) -> Vec<(String, Vec<u8>)> {
+    let name = script.file_stem().unwrap().to_string_lossy().to_string();
+    let source = fs::read_to_string(script).unwrap();
+    let scene = eval_scene(&init_engine(), &source, 0.0)
+        .unwrap_or_else(|e| panic!("{}: {e}", script.display()))
+        .at_time(0.0);
+
+    let mut cameras: Vec<(String, Camera)> = match scene.cameras.len() {
+        0 => vec![(name, Camera::unit())],
+        1 => vec![(name, scene.cameras.values().next().unwrap().clone())],
+        _ => scene
+            .cameras
+            .iter()
+            .map(|(label, camera)| (format!("{name}_{}", file_name(label)), camera.clone()))
+            .collect(),
+    };
+    cameras.sort_by(|a, b| a.0.cmp(&b.0));
+
+    cameras
+        .into_iter()
+        .map(|(name, camera)| {
+            let mut renderer = Renderer::new(scene.clone(), camera, options.clone(), WIDTH, HEIGHT);
+            (name, renderer.render().to_vec())
+        })
+        .collect()
+}
+
+// Camera label as part of a file name, "+Z Cam" becomes "pos_z_cam"
+fn file_name(label: &str) -> String {
+    label
+        .replace('+', " pos ")
+        .replace('-', " neg ")
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+fn compare(image: &[u8], reference: &[u8]) -> Comparison {
+    let mut changed_pixels = 0;
+    let mut squared_error = 0.0;
+    let mut diff = Vec::with_capacity(image.len());
+
+    for (pixel, expected) in image.chunks_exact(4).zip(reference.chunks_exact(4)) {
+        // Alpha is constant so only the colour channels are compared
+        let mut max_delta = 0;
+        for c in 0..3 {
+            let delta = pixel[c].abs_diff(expected[c]);
+            squared_error += (delta as f64).powi(2);
+            max_delta = max_delta.max(delta);
+        }
+        if max_delta > PIXEL_TOLERANCE {
+            changed_pixels += 1;
+            diff.extend_from_slice(&[0xff, 0x00, 0x00, 0xff]);
+        } else {
+            // Small differences are amplified so they are still visible
+            let grey = max_delta.saturating_mul(16);
+            diff.extend_from_slice(&[grey, grey, grey, 0xff]);
+        }
+    }
+
+    let mse = squared_error / (WIDTH * HEIGHT * 3) as f64;
+    let psnr = match mse {
+        0.0 => f64::INFINITY,
+        mse => 10.0 * (255.0 * 255.0 / mse).log10(),
+    };
+    Comparison {
+        changed_pixels,
+        psnr,
+        diff,
+    }
+}
+
+fn save(path: &Path, pixels: &[u8]) {
+    image::save_buffer(path, pixels, WIDTH, HEIGHT, image::ColorType::Rgba8)
+        .unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+}
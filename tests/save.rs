@@ -0,0 +1,51 @@
+//! Saving images from a renderer
+//!
+//! An 8 bit image saved as the shaded colour has to match the beauty render, whatever the
+//! viewer happens to be showing at the time.
+
+use rust_opengl::aov::Aov;
+use rust_opengl::script::{eval_scene, init_engine};
+use rust_opengl::{Camera, RaytracingOption, Renderer};
+
+use std::fs;
+use std::path::Path;
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 24;
+
+#[test]
+fn saved_png_is_beauty_under_an_aov_view() {
+    let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("rhai/shapes.rhai");
+    let source = fs::read_to_string(&script).unwrap();
+    let scene = eval_scene(&init_engine(), &source, 0.0)
+        .unwrap()
+        .at_time(0.0);
+    let mut options = RaytracingOption::default();
+    options.threads = 2;
+    let mut renderer = Renderer::new(scene, Camera::unit(), options, WIDTH, HEIGHT);
+    let beauty = renderer.render().to_vec();
+
+    renderer.set_view(Aov::Normal);
+    assert_ne!(
+        renderer.framebuffer(),
+        &beauty[..],
+        "the view should show normals"
+    );
+
+    let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("save");
+    fs::create_dir_all(&output_dir).unwrap();
+    let image_path = output_dir.join("image.png");
+    let aov_path = output_dir.join("beauty.png");
+    renderer.save_image(&image_path).unwrap();
+    renderer.save_aov(Aov::Beauty, &aov_path).unwrap();
+
+    for path in [image_path, aov_path] {
+        let saved = image::open(&path).unwrap().to_rgba8();
+        assert_eq!(saved.dimensions(), (WIDTH, HEIGHT));
+        assert!(
+            saved.as_raw() == &beauty,
+            "{} is not the beauty render",
+            path.display()
+        );
+    }
+}
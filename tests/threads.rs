@@ -0,0 +1,57 @@
+//! Rendering with any number of threads
+//!
+//! Tiles come back from the workers in whatever order the threads finish them, and a filter
+//! wider than a pixel splats every tile into its neighbours. The image still has to come out
+//! the same, bit for bit, as a render on one thread.
+
+use rust_opengl::filter::{Filter, FilterKind};
+use rust_opengl::integrator::IntegratorKind;
+use rust_opengl::script::{eval_scene, init_engine};
+use rust_opengl::{RaytracingOption, Renderer};
+
+use std::fs;
+use std::path::Path;
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 24;
+const THREADS: u32 = 8;
+// Repeats of the threaded render, each a new chance for the tiles to arrive in another order
+const REPEATS: usize = 2;
+
+fn render(filter: FilterKind, threads: u32) -> Vec<f32> {
+    let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("rhai/path.rhai");
+    let source = fs::read_to_string(&script).unwrap();
+    let scene = eval_scene(&init_engine(), &source, 0.0)
+        .unwrap()
+        .at_time(0.0);
+    let camera = scene.cameras["front"].clone();
+    let mut options = RaytracingOption::default();
+    options.integrator = IntegratorKind::Path;
+    options.filter = Filter::new(filter);
+    options.tile_size = 4;
+    options.passes = 4;
+    options.threads = threads;
+    let mut renderer = Renderer::new(scene, camera, options, WIDTH, HEIGHT);
+    renderer.render();
+    renderer.hdr_framebuffer().to_vec()
+}
+
+#[test]
+fn filtered_image_is_the_same_on_any_number_of_threads() {
+    for filter in [
+        FilterKind::Tent,
+        FilterKind::Gaussian,
+        FilterKind::Mitchell,
+        FilterKind::Lanczos,
+    ] {
+        let single = render(filter, 1);
+        for _ in 0..REPEATS {
+            let threaded = render(filter, THREADS);
+            assert!(
+                threaded == single,
+                "{} filter differs on {THREADS} threads",
+                filter.name()
+            );
+        }
+    }
+}